@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use crate::error::InitError;
+
+/// Features we use when the adapter has them but can live without.
+pub const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::TEXTURE_COMPRESSION_BC
+    .union(wgpu::Features::POLYGON_MODE_LINE)
+    .union(wgpu::Features::TIMESTAMP_QUERY);
+
+/// Which kind of adapter to try first when several can draw to the window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AdapterPreference {
+    /// Discrete, then integrated, then anything else.
+    #[default]
+    Discrete,
+    Integrated,
+    /// A CPU implementation such as llvmpipe, lavapipe or WARP.
+    Software,
+}
+
+impl AdapterPreference {
+    /// Lower is better.
+    fn rank(self, device_type: wgpu::DeviceType) -> u32 {
+        let preferred = match self {
+            Self::Discrete => wgpu::DeviceType::DiscreteGpu,
+            Self::Integrated => wgpu::DeviceType::IntegratedGpu,
+            Self::Software => wgpu::DeviceType::Cpu,
+        };
+
+        if device_type == preferred {
+            return 0;
+        }
+
+        match device_type {
+            wgpu::DeviceType::DiscreteGpu => 1,
+            wgpu::DeviceType::IntegratedGpu => 2,
+            wgpu::DeviceType::VirtualGpu => 3,
+            wgpu::DeviceType::Other => 4,
+            wgpu::DeviceType::Cpu => 5,
+        }
+    }
+}
+
+impl FromStr for AdapterPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "discrete" => Ok(Self::Discrete),
+            "integrated" => Ok(Self::Integrated),
+            "software" | "cpu" => Ok(Self::Software),
+            _ => anyhow::bail!("unknown adapter type {}, expected discrete, integrated or software", s),
+        }
+    }
+}
+
+/// Picks the adapter on `backends` that can present to `surface` and best
+/// matches `preference`, falling back to whatever wgpu offers as a
+/// fallback adapter if none of them can.
+pub async fn select_adapter(
+    instance: &wgpu::Instance,
+    surface: &wgpu::Surface,
+    backends: wgpu::Backends,
+    preference: AdapterPreference,
+) -> Result<wgpu::Adapter, InitError> {
+    let mut adapters = instance.enumerate_adapters(backends)
+        .filter(|adapter| adapter.is_surface_supported(surface))
+        .collect::<Vec<_>>();
+
+    for adapter in &adapters {
+        let info = adapter.get_info();
+        log::info!("found adapter {} ({:?}, {:?})", info.name, info.device_type, info.backend);
+    }
+
+    adapters.sort_by_key(|adapter| preference.rank(adapter.get_info().device_type));
+
+    let adapter = match adapters.into_iter().next() {
+        Some(adapter) => adapter,
+        None => instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: Some(surface),
+            force_fallback_adapter: true,
+        }).await.ok_or(InitError::NoAdapter)?,
+    };
+
+    let info = adapter.get_info();
+    log::info!("using adapter {} ({:?}, {:?})", info.name, info.device_type, info.backend);
+
+    Ok(adapter)
+}
+
+/// Opens the device with every optional feature the adapter supports.
+///
+/// The default limits are tried first, then the downlevel ones so older
+/// and software adapters still get a device.
+pub async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue), InitError> {
+    let features = adapter.features() & OPTIONAL_FEATURES;
+    log::info!("enabling optional features {:?}", features);
+
+    let candidates = [
+        wgpu::Limits::default(),
+        wgpu::Limits::downlevel_defaults(),
+        wgpu::Limits::downlevel_webgl2_defaults(),
+    ];
+
+    let mut last_error = None;
+
+    for limits in candidates {
+        let result = adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                features,
+                limits: limits.using_resolution(adapter.limits()),
+                label: None,
+            },
+            None,
+        ).await;
+
+        match result {
+            Ok(device) => return Ok(device),
+            Err(e) => {
+                log::warn!("couldn't open the device ({}), trying lower limits", e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap().into())
+}
+
+/// MSAA sample counts that can be used with every one of `formats`.
+///
+/// wgpu only says whether a format can be multisampled at all, not with
+/// how many samples, so we only offer 4x, the count WebGPU guarantees for
+/// multisampled formats.
+pub fn sample_counts(adapter: &wgpu::Adapter, formats: &[wgpu::TextureFormat]) -> Vec<u32> {
+    let multisample = formats.iter().all(|&format| {
+        // color targets also have to be resolvable
+        let needed = if format.describe().sample_type == wgpu::TextureSampleType::Depth {
+            wgpu::TextureFormatFeatureFlags::MULTISAMPLE
+        } else {
+            wgpu::TextureFormatFeatureFlags::MULTISAMPLE | wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE
+        };
+
+        adapter.get_texture_format_features(format).flags.contains(needed)
+    });
+
+    if multisample {
+        vec![1, 4]
+    } else {
+        vec![1]
+    }
+}
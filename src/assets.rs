@@ -0,0 +1,391 @@
+use std::cell::OnceCell;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+use ahash::AHashMap;
+
+use crate::{level, model, registry::Registry, resources, texture};
+use crate::error::AssetError;
+
+/// Where an asset is in its trip from disk to the GPU.
+pub enum LoadState<'a, T> {
+    Loading,
+    Ready(&'a T),
+    Failed(&'a AssetError),
+}
+
+struct Slot<T> {
+    path: String,
+    result: OnceCell<Result<T, AssetError>>,
+}
+
+/// Shared reference to an asset that may still be loading.
+///
+/// Handles only live on the main thread; the asset is filled in by
+/// [`AssetManager::poll`] once a worker has finished decoding it.
+pub struct Handle<T> {
+    slot: Rc<Slot<T>>,
+}
+
+impl<T> Handle<T> {
+    fn new(path: &str) -> Self {
+        Self {
+            slot: Rc::new(Slot {
+                path: path.to_string(),
+                result: OnceCell::new(),
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.slot.path
+    }
+
+    /// Number of handles to this asset outside of the manager's cache.
+    pub fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.slot) - 1
+    }
+
+    pub fn state(&self) -> LoadState<'_, T> {
+        match self.slot.result.get() {
+            None => LoadState::Loading,
+            Some(Ok(asset)) => LoadState::Ready(asset),
+            Some(Err(e)) => LoadState::Failed(e),
+        }
+    }
+
+    /// The asset, if it is ready.
+    pub fn get(&self) -> Option<&T> {
+        match self.state() {
+            LoadState::Ready(asset) => Some(asset),
+            _ => None,
+        }
+    }
+
+    fn finish(&self, result: Result<T, AssetError>) {
+        // a slot is only ever finished once, by the job that created it
+        let _ = self.slot.result.set(result);
+    }
+}
+
+/// Handles are equal when they share an asset.
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.slot, &other.slot)
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+/// Handles keyed by path, so every user of a path shares one asset.
+pub struct Cache<T> {
+    entries: AHashMap<String, Handle<T>>,
+}
+
+impl<T> Cache<T> {
+    fn new() -> Self {
+        Self {
+            entries: AHashMap::new(),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<Handle<T>> {
+        self.entries.get(path).cloned()
+    }
+
+    fn insert(&mut self, handle: Handle<T>) {
+        self.entries.insert(handle.path().to_string(), handle);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Handle<T>> {
+        self.entries.values()
+    }
+
+    /// Drops every asset nobody holds a handle to anymore, freeing its GPU
+    /// memory. Returns how many were dropped.
+    pub fn collect_garbage(&mut self) -> usize {
+        let before = self.entries.len();
+
+        self.entries.retain(|path, handle| {
+            let keep = handle.ref_count() > 0;
+            if !keep {
+                log::info!("unloaded {}", path);
+            }
+            keep
+        });
+
+        before - self.entries.len()
+    }
+}
+
+impl Cache<texture::Texture> {
+    /// Returns the texture for `path`, uploading `img` only if it isn't in
+    /// the cache already.
+    pub fn get_or_upload(
+        &mut self,
+        path: &str,
+        img: &image::DynamicImage,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Handle<texture::Texture>, AssetError> {
+        if let Some(handle) = self.get(path).filter(|handle| handle.get().is_some()) {
+            return Ok(handle);
+        }
+
+        let handle = Handle::new(path);
+
+        let texture = texture::Texture::from_image(device, queue, img, Some(path))?;
+        handle.finish(Ok(texture));
+        self.insert(handle.clone());
+
+        Ok(handle)
+    }
+}
+
+/// What a worker is asked to load.
+enum Job {
+    Model(String),
+    Level(String),
+}
+
+/// What a worker loaded, ready to upload.
+enum Loaded {
+    Model(Result<model::ModelData, AssetError>),
+    Level(anyhow::Result<Box<level::LevelAssets>>),
+}
+
+/// What a job will be uploaded as once it's back.
+enum Waiting {
+    Model(Handle<model::Model>),
+    Level(String),
+}
+
+impl Waiting {
+    fn path(&self) -> &str {
+        match self {
+            Self::Model(handle) => handle.path(),
+            Self::Level(path) => path,
+        }
+    }
+}
+
+/// Owns every texture and model, loading each path at most once, and
+/// loads the level.
+///
+/// Models are parsed, and their images decoded, on a pool of worker
+/// threads, and so are levels compiled and lit. The results are uploaded
+/// to the GPU from the main thread in [`poll`]. Textures come in with the
+/// models and levels using them. Assets stay cached while something holds
+/// a [`Handle`] to them and are freed by [`collect_garbage`] once the last
+/// one is dropped.
+///
+/// [`poll`]: AssetManager::poll
+/// [`collect_garbage`]: AssetManager::collect_garbage
+pub struct AssetManager {
+    jobs: Option<mpsc::Sender<(u64, Job)>>,
+    results: mpsc::Receiver<(u64, Loaded)>,
+    workers: Vec<JoinHandle<()>>,
+    waiting: AHashMap<u64, Waiting>,
+    next_id: u64,
+    queued: usize,
+    finished: usize,
+    /// The last level uploaded, until it's taken.
+    level: Option<level::LoadedLevel>,
+    pub textures: Cache<texture::Texture>,
+    pub models: Cache<model::Model>,
+}
+
+impl AssetManager {
+    pub fn new() -> Self {
+        let (jobs, job_rx) = mpsc::channel::<(u64, Job)>();
+        let (result_tx, results) = mpsc::channel();
+
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+
+        let workers = (0..threads)
+            .map(|i| {
+                let job_rx = job_rx.clone();
+                let result_tx = result_tx.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("asset loader {}", i))
+                    .spawn(move || loop {
+                        // the lock is released as soon as a job is taken
+                        let job = job_rx.lock().unwrap().recv();
+
+                        let (id, job) = match job {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+
+                        let loaded = match job {
+                            Job::Model(path) => Loaded::Model(resources::parse_model(&path)),
+                            Job::Level(path) => Loaded::Level(level::LevelAssets::load(&path).map(Box::new)),
+                        };
+
+                        if result_tx.send((id, loaded)).is_err() {
+                            break;
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+            waiting: AHashMap::new(),
+            next_id: 0,
+            queued: 0,
+            finished: 0,
+            level: None,
+            textures: Cache::new(),
+            models: Cache::new(),
+        }
+    }
+
+    pub fn load_model(&mut self, file_name: &str) -> Handle<model::Model> {
+        if let Some(handle) = self.models.get(file_name) {
+            return handle;
+        }
+
+        let handle = Handle::new(file_name);
+        self.models.insert(handle.clone());
+
+        self.submit(Job::Model(file_name.to_string()), Waiting::Model(handle.clone()));
+
+        handle
+    }
+
+    /// Starts loading the level in `file_name`, a `.map` or `.bsp`. It's
+    /// handed over by [`take_level`] once it's uploaded.
+    ///
+    /// [`take_level`]: AssetManager::take_level
+    pub fn load_level(&mut self, file_name: &str) {
+        self.submit(Job::Level(file_name.to_string()), Waiting::Level(file_name.to_string()));
+    }
+
+    fn submit(&mut self, job: Job, waiting: Waiting) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.waiting.insert(id, waiting);
+        self.queued += 1;
+
+        if let Some(jobs) = &self.jobs {
+            jobs.send((id, job)).unwrap();
+        }
+    }
+
+    /// Uploads everything the workers have finished since the last call,
+    /// levels with pipelines for `sample_count`. Returns how many assets
+    /// were completed.
+    pub fn poll(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        registry: &mut Registry,
+        sample_count: u32,
+    ) -> usize {
+        let mut completed = 0;
+
+        while let Ok((id, loaded)) = self.results.try_recv() {
+            let waiting = match self.waiting.remove(&id) {
+                Some(waiting) => waiting,
+                None => continue,
+            };
+
+            match (waiting, loaded) {
+                (Waiting::Model(handle), Loaded::Model(decoded)) => {
+                    let result = decoded.and_then(|data| {
+                        resources::upload_model(handle.path(), &data, device, queue, registry, &mut self.textures)
+                    });
+                    finish(&handle, result);
+                }
+                (Waiting::Level(path), Loaded::Level(loaded)) => {
+                    let result = loaded.and_then(|assets| {
+                        assets.upload(device, queue, registry, &mut self.textures, sample_count).map_err(Into::into)
+                    });
+
+                    match result {
+                        Ok(level) => {
+                            log::info!("loaded {}", path);
+                            self.level = Some(level);
+                        }
+                        Err(e) => log::error!("couldn't load map {}: {:#}", path, e),
+                    }
+                }
+                _ => unreachable!("jobs come back as what they were sent as"),
+            }
+
+            self.finished += 1;
+            completed += 1;
+        }
+
+        completed
+    }
+
+    /// The level [`poll`] uploaded, once.
+    ///
+    /// [`poll`]: AssetManager::poll
+    pub fn take_level(&mut self) -> Option<level::LoadedLevel> {
+        self.level.take()
+    }
+
+    /// Fraction of everything queued so far that is ready or failed.
+    pub fn progress(&self) -> f32 {
+        if self.queued == 0 {
+            return 1.0;
+        }
+
+        self.finished as f32 / self.queued as f32
+    }
+
+    /// Nothing is queued or waiting to be uploaded.
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// The paths of the models and the level still on their way.
+    pub fn loading(&self) -> impl Iterator<Item = &str> {
+        self.waiting.values().map(Waiting::path)
+    }
+
+    /// Frees models and then textures that are no longer referenced, so
+    /// textures only held by a dropped model go in the same call.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.models.collect_garbage() + self.textures.collect_garbage()
+    }
+}
+
+impl Drop for AssetManager {
+    fn drop(&mut self) {
+        // closing the job channel lets the workers fall out of their loop
+        self.jobs = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn finish<T>(handle: &Handle<T>, result: Result<T, AssetError>) {
+    match &result {
+        Ok(_) => log::info!("loaded {}", handle.path()),
+        Err(e) => log::error!("failed to load {}", e),
+    }
+
+    handle.finish(result);
+}
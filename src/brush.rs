@@ -0,0 +1,309 @@
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use shalrath::repr::{BrushPlane, Entity, TextureOffset};
+
+/// Half the size of the polygon every face starts as before it's clipped to
+/// its brush, bigger than any map.
+const HUGE: f64 = 65536.0;
+
+/// Tool textures that mark volumes rather than surfaces. Their faces are
+/// neither drawn nor lit, and brushes made only of them cast no shadows.
+const TOOL_TEXTURES: [&str; 4] = ["clip", "skip", "trigger", "hint"];
+
+/// Plane of points `p` with `normal.dot(p) == dist`, the normal pointing out
+/// of the brush.
+#[derive(Debug, Clone, Copy)]
+pub struct Plane {
+    pub normal: Vector3<f64>,
+    pub dist: f64,
+}
+
+impl Plane {
+    /// The plane through three points of a map file, wound so the normal
+    /// points out of the brush.
+    fn from_points(p0: Point3<f64>, p1: Point3<f64>, p2: Point3<f64>) -> Option<Self> {
+        let normal = (p0 - p1).cross(p2 - p1);
+        if normal.magnitude2() < 1e-12 {
+            return None;
+        }
+
+        let normal = normal.normalize();
+        Some(Self {
+            normal,
+            dist: normal.dot(p1.to_vec()),
+        })
+    }
+
+    pub fn distance(&self, point: Point3<f64>) -> f64 {
+        self.normal.dot(point.to_vec()) - self.dist
+    }
+}
+
+/// Maps positions on a face to texel coordinates of its texture, before
+/// dividing by the texture's size.
+#[derive(Debug, Clone, Copy)]
+pub struct TexAxes {
+    pub s: Vector3<f64>,
+    pub t: Vector3<f64>,
+    pub offset: [f64; 2],
+}
+
+impl TexAxes {
+    /// Texture coordinates of `point` on a texture of `size` texels.
+    pub fn uv(&self, point: Point3<f64>, size: (u32, u32)) -> [f32; 2] {
+        [
+            ((self.s.dot(point.to_vec()) + self.offset[0]) / size.0 as f64) as f32,
+            ((self.t.dot(point.to_vec()) + self.offset[1]) / size.1 as f64) as f32,
+        ]
+    }
+}
+
+/// A convex polygon on the surface of a brush, in map units with z up.
+#[derive(Debug, Clone)]
+pub struct Face {
+    pub texture: String,
+    pub plane: Plane,
+    /// Counter-clockwise seen from in front of the face.
+    pub vertices: Vec<Point3<f64>>,
+    pub tex_axes: TexAxes,
+}
+
+impl Face {
+    pub fn is_tool(&self) -> bool {
+        TOOL_TEXTURES.contains(&self.texture_name().as_str())
+    }
+
+    /// Sky faces aren't drawn so the skybox shows through them, but they
+    /// still block light.
+    pub fn is_sky(&self) -> bool {
+        self.texture_name().starts_with("sky")
+    }
+
+    pub fn is_drawn(&self) -> bool {
+        !self.is_tool() && !self.is_sky()
+    }
+
+    /// The texture's name without its directory, lowercase.
+    fn texture_name(&self) -> String {
+        self.texture.rsplit('/').next().unwrap_or(&self.texture).to_ascii_lowercase()
+    }
+}
+
+/// A convex solid, the intersection of the half spaces behind its planes.
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub planes: Vec<Plane>,
+    pub faces: Vec<Face>,
+    pub min: Point3<f64>,
+    pub max: Point3<f64>,
+}
+
+impl Brush {
+    /// Whether anything but tool faces make up the brush, so it's seen and
+    /// blocks light.
+    pub fn is_solid(&self) -> bool {
+        self.faces.iter().any(|face| !face.is_tool())
+    }
+
+    /// Whether the segment from `from` to `to` passes through the brush,
+    /// ignoring `margin` units at either end.
+    pub fn intersects_segment(&self, from: Point3<f64>, to: Point3<f64>, margin: f64) -> bool {
+        let length = from.distance(to);
+        if length <= margin * 2.0 {
+            return false;
+        }
+
+        for axis in 0..3 {
+            if from[axis].max(to[axis]) < self.min[axis] || from[axis].min(to[axis]) > self.max[axis] {
+                return false;
+            }
+        }
+
+        let (mut enter, mut exit) = (margin / length, 1.0 - margin / length);
+
+        for plane in &self.planes {
+            let (d0, d1) = (plane.distance(from), plane.distance(to));
+
+            if d0 > 0.0 && d1 > 0.0 {
+                return false;
+            }
+            if d0 > 0.0 {
+                enter = enter.max(d0 / (d0 - d1));
+            } else if d1 > 0.0 {
+                exit = exit.min(d0 / (d0 - d1));
+            }
+
+            if enter >= exit {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The box around `brushes`, in map units.
+pub fn extent(brushes: &[Brush]) -> Option<(Point3<f64>, Point3<f64>)> {
+    let first = brushes.first()?;
+
+    Some(brushes.iter().fold((first.min, first.max), |(min, max), brush| {
+        (
+            Point3::new(min.x.min(brush.min.x), min.y.min(brush.min.y), min.z.min(brush.min.z)),
+            Point3::new(max.x.max(brush.max.x), max.y.max(brush.max.y), max.z.max(brush.max.z)),
+        )
+    }))
+}
+
+/// The brushes of `entity`, with each face clipped to its brush. Brushes
+/// whose planes don't close a volume are skipped.
+pub fn compile(entity: &Entity) -> Vec<Brush> {
+    entity.brushes.0.iter()
+        .filter_map(|brush| compile_brush(&brush.0))
+        .collect()
+}
+
+fn compile_brush(brush_planes: &[BrushPlane]) -> Option<Brush> {
+    let point = |p: shalrath::repr::Point| Point3::new(p.x as f64, p.y as f64, p.z as f64);
+
+    let planes = brush_planes.iter()
+        .map(|bp| Plane::from_points(point(bp.plane.v0), point(bp.plane.v1), point(bp.plane.v2)))
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut faces = Vec::new();
+
+    for (i, (plane, brush_plane)) in planes.iter().zip(brush_planes).enumerate() {
+        let mut polygon = base_polygon(plane);
+
+        for (j, other) in planes.iter().enumerate() {
+            if i != j {
+                polygon = clip(&polygon, other);
+            }
+        }
+
+        if polygon.len() >= 3 {
+            faces.push(Face {
+                texture: brush_plane.texture.clone(),
+                plane: *plane,
+                vertices: polygon,
+                tex_axes: tex_axes(plane, brush_plane),
+            });
+        }
+    }
+
+    if faces.len() < 4 {
+        return None;
+    }
+
+    let mut min = Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+    let mut max = Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+
+    for vertex in faces.iter().flat_map(|face| &face.vertices) {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(vertex[axis]);
+            max[axis] = max[axis].max(vertex[axis]);
+        }
+    }
+
+    Some(Brush { planes, faces, min, max })
+}
+
+/// A square on `plane` covering the whole map, wound counter-clockwise
+/// around the normal.
+fn base_polygon(plane: &Plane) -> Vec<Point3<f64>> {
+    let up = if plane.normal.z.abs() > 0.9 { Vector3::unit_x() } else { Vector3::unit_z() };
+    let right = up.cross(plane.normal).normalize() * HUGE;
+    let up = plane.normal.cross(right);
+    let center = Point3::from_vec(plane.normal * plane.dist);
+
+    vec![
+        center - right - up,
+        center + right - up,
+        center + right + up,
+        center - right + up,
+    ]
+}
+
+/// The part of `polygon` behind `plane`.
+fn clip(polygon: &[Point3<f64>], plane: &Plane) -> Vec<Point3<f64>> {
+    const EPSILON: f64 = 1e-6;
+
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (da, db) = (plane.distance(a), plane.distance(b));
+
+        if da <= EPSILON {
+            clipped.push(a);
+        }
+        if (da < -EPSILON && db > EPSILON) || (da > EPSILON && db < -EPSILON) {
+            clipped.push(a + (b - a) * (da / (da - db)));
+        }
+    }
+
+    clipped
+}
+
+/// Texture axes of a face, either given by a Valve 220 map or derived from
+/// the axis the face is closest to facing, like Quake's tools do.
+fn tex_axes(plane: &Plane, brush_plane: &BrushPlane) -> TexAxes {
+    let scale = |s: f32| if s == 0.0 { 1.0 } else { s as f64 };
+    let (scale_x, scale_y) = (scale(brush_plane.scale_x), scale(brush_plane.scale_y));
+
+    match brush_plane.texture_offset {
+        TextureOffset::Valve { u, v } => TexAxes {
+            s: Vector3::new(u.x as f64, u.y as f64, u.z as f64) / scale_x,
+            t: Vector3::new(v.x as f64, v.y as f64, v.z as f64) / scale_y,
+            offset: [u.d as f64, v.d as f64],
+        },
+
+        TextureOffset::Standard { u, v } => {
+            // normal, s and t of the floor, ceiling and four walls
+            let base = [
+                ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+                ([0.0, 0.0, -1.0], [1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+                ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]),
+                ([-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]),
+                ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+                ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ];
+
+            // the first of equal maxima wins, like Quake's TextureAxisFromPlane,
+            // which matters on 45 degree faces
+            let (_, s, t) = base.iter()
+                .map(|&(n, s, t)| (Vector3::from(n), Vector3::from(s), Vector3::from(t)))
+                .fold(None, |best: Option<(f64, _)>, axes| {
+                    let dot = plane.normal.dot(axes.0);
+                    match best {
+                        Some((best_dot, _)) if best_dot >= dot => best,
+                        _ => Some((dot, axes)),
+                    }
+                })
+                .map(|(_, axes)| axes)
+                .unwrap();
+
+            let (sin, cos) = (brush_plane.angle as f64).to_radians().sin_cos();
+            let rotate = |v: Vector3<f64>| {
+                // rotate within the two axes the base vectors span
+                let (a, b) = if s.x != 0.0 && t.y != 0.0 || s.y != 0.0 && t.x != 0.0 {
+                    (0, 1)
+                } else if s.x != 0.0 || t.x != 0.0 {
+                    (0, 2)
+                } else {
+                    (1, 2)
+                };
+
+                let mut rotated = v;
+                rotated[a] = cos * v[a] - sin * v[b];
+                rotated[b] = sin * v[a] + cos * v[b];
+                rotated
+            };
+
+            TexAxes {
+                s: rotate(s) / scale_x,
+                t: rotate(t) / scale_y,
+                offset: [u as f64, v as f64],
+            }
+        }
+    }
+}
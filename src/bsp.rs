@@ -0,0 +1,785 @@
+use std::ops::Range;
+
+use ahash::AHashMap;
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use shalrath::repr::Map;
+
+use crate::{
+    brush::{Face, Plane, TexAxes},
+    error::AssetError,
+    lightmap::{self, LUXEL_SIZE},
+    lightstyle,
+    map,
+    model::Bounds,
+    resources,
+};
+
+/// Texinfo flag of liquids and sky, which have no lightmap and are drawn
+/// fullbright.
+const TEX_SPECIAL: i32 = 1;
+
+/// Ends a face's list of light styles.
+const NO_STYLE: u8 = 255;
+
+/// The 256 RGB colors miptex texels index.
+const PALETTE: &str = "gfx/palette.lmp";
+
+const ENTITIES: usize = 0;
+const PLANES: usize = 1;
+const TEXTURES: usize = 2;
+const VERTICES: usize = 3;
+const VISIBILITY: usize = 4;
+const NODES: usize = 5;
+const TEXINFO: usize = 6;
+const FACES: usize = 7;
+const LIGHTING: usize = 8;
+const LEAVES: usize = 10;
+const EDGES: usize = 12;
+const SURFEDGES: usize = 13;
+const MODELS: usize = 14;
+const LUMP_COUNT: usize = 15;
+
+/// The two layouts of the same lumps. BSP2 widens the counts and indices
+/// that BSP29 keeps in 16 bits, for bigger maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Bsp29,
+    Bsp2,
+}
+
+/// A compiled Quake level: its faces with the light the compiler baked
+/// into them, embedded textures, visibility and entities.
+pub struct Bsp {
+    /// The entity lump, parsed like a `.map` without brushes. Brush
+    /// entities name their model as `"model" "*n"`.
+    pub entities: Map,
+    pub faces: Vec<BspFace>,
+    /// The faces of each model, the world's first.
+    pub models: Vec<Range<usize>>,
+    /// The embedded textures by name, when the palette to decode them was
+    /// found.
+    pub textures: AHashMap<String, image::DynamicImage>,
+    /// Missing for maps that were never run through `vis`.
+    pub visibility: Option<Visibility>,
+}
+
+pub struct BspFace {
+    pub face: Face,
+    pub lightmap: FaceSamples,
+}
+
+/// A face's lightmap samples, one per `LUXEL_SIZE` texels of its texture
+/// along the texture axes, with the light styles that aren't animated
+/// added up.
+pub struct FaceSamples {
+    /// The face's texture axes and its first sample along them, in
+    /// samples. Faces lit evenly have a single sample and no axes.
+    axes: Option<(TexAxes, [f64; 2])>,
+    pub size: [u32; 2],
+    texels: Vec<[u8; 4]>,
+}
+
+impl FaceSamples {
+    /// One sample lighting the whole face with `value`.
+    fn uniform(value: u8) -> Self {
+        Self {
+            axes: None,
+            size: [1, 1],
+            texels: vec![[value, value, value, 255]],
+        }
+    }
+
+    /// Size of the block in the atlas, with the border.
+    pub fn block_size(&self) -> [u32; 2] {
+        self.size.map(|size| size + 2)
+    }
+
+    /// The samples with a texel of border around them repeating the edge,
+    /// so filtering doesn't bleed in the neighbouring blocks.
+    pub fn bordered(&self) -> Vec<[u8; 4]> {
+        let [width, height] = self.block_size();
+
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let x = x.saturating_sub(1).min(self.size[0] - 1);
+                let y = y.saturating_sub(1).min(self.size[1] - 1);
+                self.texels[(y * self.size[0] + x) as usize]
+            })
+            .collect()
+    }
+
+    /// Lightmap coordinates of `point` on the face, with its block at
+    /// `offset` in the atlas.
+    pub fn uv(&self, point: Point3<f64>, offset: [u32; 2], atlas_size: u32) -> [f32; 2] {
+        let luxels = match self.axes {
+            Some((tex_axes, min)) => {
+                let [s, t] = tex_axes.uv(point, (1, 1));
+                [s as f64 / LUXEL_SIZE - min[0], t as f64 / LUXEL_SIZE - min[1]]
+            }
+            None => [0.0; 2],
+        };
+
+        [0, 1].map(|i| {
+            // samples sit on the texel grid, so their centers are half a
+            // texel in, past the border
+            let texel = luxels[i] + 1.5 + offset[i] as f64;
+            (texel / atlas_size as f64) as f32
+        })
+    }
+}
+
+/// The BSP tree and which of its leaves can see which, in world space.
+pub struct Visibility {
+    planes: Vec<(Vector3<f32>, f32)>,
+    nodes: Vec<[i32; 3]>,
+    /// Where each leaf's row of the compressed visibility data starts.
+    rows: Vec<Option<usize>>,
+    data: Vec<u8>,
+    /// Leaves the rows have a bit for, leaf 1 onwards. Leaf 0 is the
+    /// solid outside of the map.
+    leaf_count: usize,
+    root: i32,
+}
+
+impl Visibility {
+    /// Which leaves can be seen from `point`, or `None` if any can, when
+    /// it's outside of the map.
+    pub fn visible_from(&self, point: Point3<f32>) -> Option<Vec<bool>> {
+        let mut child = self.root;
+        while child >= 0 {
+            let [plane, front, back] = self.nodes[child as usize];
+            let (normal, dist) = self.planes[plane as usize];
+            child = if normal.dot(point.to_vec()) >= dist { front } else { back };
+        }
+
+        let start = self.rows[(-1 - child) as usize]?;
+
+        // runs of zero bytes are stored as a zero and their length
+        let mut visible = vec![false; self.leaf_count + 1];
+        let mut leaf = 1;
+        let mut bytes = self.data[start..].iter();
+
+        while leaf <= self.leaf_count {
+            let &byte = bytes.next()?;
+
+            if byte == 0 {
+                leaf += 8 * *bytes.next()? as usize;
+                continue;
+            }
+
+            for bit in 0..8 {
+                if byte & (1 << bit) != 0 && leaf + bit <= self.leaf_count {
+                    visible[leaf + bit] = true;
+                }
+            }
+            leaf += 8;
+        }
+
+        Some(visible)
+    }
+
+    /// Whether any leaf `bounds` reaches into is in `visible`.
+    pub fn touches(&self, bounds: &Bounds, visible: &[bool]) -> bool {
+        let corners = bounds.corners();
+        let mut stack = vec![self.root];
+
+        while let Some(child) = stack.pop() {
+            if child < 0 {
+                if visible.get((-1 - child) as usize) == Some(&true) {
+                    return true;
+                }
+                continue;
+            }
+
+            let [plane, front, back] = self.nodes[child as usize];
+            let (normal, dist) = self.planes[plane as usize];
+            let distances = corners.map(|corner| normal.dot(corner.to_vec()) - dist);
+
+            if distances.iter().any(|&distance| distance >= 0.0) {
+                stack.push(front);
+            }
+            if distances.iter().any(|&distance| distance < 0.0) {
+                stack.push(back);
+            }
+        }
+
+        false
+    }
+}
+
+/// Reads a BSP29 or BSP2 file, with its colored lighting from the `.lit`
+/// beside it if there is one. Embedded textures are decoded with Quake's
+/// palette, `gfx/palette.lmp`.
+pub fn load(file_name: &str) -> Result<Bsp, AssetError> {
+    let data = resources::load_binary(file_name)?;
+
+    // `.BSP` as well as `.bsp`, which `LevelSource::load` accepts too
+    let lit_name = std::path::Path::new(file_name).with_extension("lit").to_string_lossy().into_owned();
+    let lit = match resources::load_binary(&lit_name) {
+        Ok(lit) if lit.starts_with(b"QLIT") && lit.len() >= 8 => Some(lit[8..].to_vec()),
+        Ok(_) => {
+            log::warn!("{} isn't a version 1 .lit, using the white light in the map", lit_name);
+            None
+        }
+        Err(AssetError::Missing { .. }) => None,
+        Err(e) => return Err(e),
+    };
+
+    let palette = match resources::load_binary(PALETTE) {
+        Ok(palette) if palette.len() >= 768 => Some(palette),
+        Ok(_) => return Err(AssetError::parse(PALETTE, "shorter than 256 colors")),
+        Err(AssetError::Missing { .. }) => {
+            log::warn!("no {}, loading textures from textures/ instead of the map", PALETTE);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    Parser::new(file_name, &data)?.parse(lit.as_deref(), palette.as_deref())
+}
+
+/// The lumps of a BSP file, each a slice of fixed size records.
+struct Parser<'a> {
+    file_name: &'a str,
+    format: Format,
+    lumps: [&'a [u8]; LUMP_COUNT],
+}
+
+impl<'a> Parser<'a> {
+    fn new(file_name: &'a str, data: &'a [u8]) -> Result<Self, AssetError> {
+        let error = |message: &str| AssetError::parse(file_name, message);
+
+        let format = match data.get(0..4) {
+            Some(b"BSP2") => Format::Bsp2,
+            Some([29, 0, 0, 0]) => Format::Bsp29,
+            Some(b"2PSB") => return Err(AssetError::Unsupported { path: file_name.to_string(), format: "2PSB".to_string() }),
+            _ => return Err(error("not a BSP29 or BSP2 file")),
+        };
+
+        let mut lumps = [&data[..0]; LUMP_COUNT];
+        for (i, lump) in lumps.iter_mut().enumerate() {
+            let offset = read_i32(data, 4 + i * 8).ok_or_else(|| error("truncated header"))?;
+            let length = read_i32(data, 8 + i * 8).ok_or_else(|| error("truncated header"))?;
+
+            *lump = usize::try_from(offset).ok()
+                .zip(usize::try_from(length).ok())
+                .and_then(|(offset, length)| data.get(offset..offset.checked_add(length)?))
+                .ok_or_else(|| error(&format!("lump {} is past the end of the file", i)))?;
+        }
+
+        Ok(Self { file_name, format, lumps })
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> AssetError {
+        AssetError::parse(self.file_name, message)
+    }
+
+    /// The records of `lump`, `bsp29` or `bsp2` bytes each.
+    fn records(&self, lump: usize, bsp29: usize, bsp2: usize) -> Result<std::slice::ChunksExact<'a, u8>, AssetError> {
+        let size = match self.format {
+            Format::Bsp29 => bsp29,
+            Format::Bsp2 => bsp2,
+        };
+
+        let data = self.lumps[lump];
+        if !data.len().is_multiple_of(size) {
+            return Err(self.error(format!("lump {} isn't a whole number of {} byte records", lump, size)));
+        }
+
+        Ok(data.chunks_exact(size))
+    }
+
+    /// A BSP29 field that BSP2 widens to 32 bits, `bsp2_offset` in.
+    fn index(&self, record: &[u8], bsp29_offset: usize, bsp2_offset: usize) -> i64 {
+        match self.format {
+            Format::Bsp29 => read_u16(record, bsp29_offset).unwrap() as i64,
+            Format::Bsp2 => read_i32(record, bsp2_offset).unwrap() as i64,
+        }
+    }
+
+    fn parse(&self, lit: Option<&[u8]>, palette: Option<&[u8]>) -> Result<Bsp, AssetError> {
+        let entities = self.entities()?;
+
+        let vertices = self.records(VERTICES, 12, 12)?
+            .map(|record| read_vector(record, 0).cast::<f64>().unwrap())
+            .map(Point3::from_vec)
+            .collect::<Vec<_>>();
+
+        let edges = self.records(EDGES, 4, 8)?
+            .map(|record| match self.format {
+                Format::Bsp29 => [read_u16(record, 0).unwrap() as usize, read_u16(record, 2).unwrap() as usize],
+                Format::Bsp2 => [read_u32(record, 0).unwrap() as usize, read_u32(record, 4).unwrap() as usize],
+            })
+            .collect::<Vec<_>>();
+
+        let surfedges = self.records(SURFEDGES, 4, 4)?
+            .map(|record| read_i32(record, 0).unwrap())
+            .collect::<Vec<_>>();
+
+        let planes = self.records(PLANES, 20, 20)?
+            .map(|record| Plane {
+                normal: read_vector(record, 0).cast().unwrap(),
+                dist: read_f32(record, 12).unwrap() as f64,
+            })
+            .collect::<Vec<_>>();
+
+        let (texture_names, textures) = self.textures(palette)?;
+
+        let texinfo = self.records(TEXINFO, 40, 40)?
+            .map(|record| {
+                let tex_axes = TexAxes {
+                    s: read_vector(record, 0).cast().unwrap(),
+                    t: read_vector(record, 16).cast().unwrap(),
+                    offset: [read_f32(record, 12).unwrap() as f64, read_f32(record, 28).unwrap() as f64],
+                };
+                let texture = usize::try_from(read_i32(record, 32).unwrap()).ok()
+                    .and_then(|i| texture_names.get(i))
+                    .cloned()
+                    .unwrap_or_default();
+                (tex_axes, texture, read_i32(record, 36).unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        let lighting = self.lumps[LIGHTING];
+
+        let faces = self.records(FACES, 20, 28)?
+            .enumerate()
+            .map(|(i, record)| {
+                let bad = |what: &str| self.error(format!("face {} has a bad {}", i, what));
+
+                let mut plane = *planes.get(self.index(record, 0, 0) as usize).ok_or_else(|| bad("plane"))?;
+                if self.index(record, 2, 4) != 0 {
+                    plane = Plane { normal: -plane.normal, dist: -plane.dist };
+                }
+
+                let first_edge = read_i32(record, if self.format == Format::Bsp29 { 4 } else { 8 }).unwrap();
+                let edge_count = self.index(record, 8, 12);
+                let face_edges = usize::try_from(first_edge).ok()
+                    .zip(usize::try_from(edge_count).ok())
+                    .and_then(|(first, count)| surfedges.get(first..first.checked_add(count)?))
+                    .ok_or_else(|| bad("edge range"))?;
+
+                let mut points = face_edges.iter()
+                    .map(|&edge| {
+                        let vertex = match edge {
+                            0.. => edges.get(edge as usize)?[0],
+                            _ => edges.get(edge.unsigned_abs() as usize)?[1],
+                        };
+                        vertices.get(vertex).copied()
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| bad("edge"))?;
+
+                if points.len() < 3 {
+                    return Err(bad("edge count"));
+                }
+
+                // compilers disagree on the winding, faces want it
+                // counter-clockwise from the front
+                if (points[1] - points[0]).cross(points[2] - points[0]).dot(plane.normal) < 0.0 {
+                    points.reverse();
+                }
+
+                let (tex_axes, texture, flags) = texinfo.get(self.index(record, 10, 16) as usize)
+                    .cloned()
+                    .ok_or_else(|| bad("texinfo"))?;
+
+                let styles_at = if self.format == Format::Bsp29 { 12 } else { 20 };
+                let styles = record[styles_at..styles_at + 4].iter().copied().take_while(|&style| style != NO_STYLE).collect::<Vec<_>>();
+                let light_offset = read_i32(record, styles_at + 4).unwrap();
+
+                let face = Face { texture, plane, vertices: points, tex_axes };
+                let lightmap = if lighting.is_empty() || flags & TEX_SPECIAL != 0 {
+                    FaceSamples::uniform(lightmap::FULLBRIGHT)
+                } else {
+                    match usize::try_from(light_offset) {
+                        Ok(offset) if !styles.is_empty() => {
+                            let extents = extents(&face).ok_or_else(|| bad("extents"))?;
+                            samples(&face, extents, lighting, lit, offset, &styles).ok_or_else(|| bad("light offset"))?
+                        }
+                        _ => FaceSamples::uniform(0),
+                    }
+                };
+
+                Ok(BspFace { face, lightmap })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let face_count = faces.len();
+        let models = self.records(MODELS, 64, 64)?
+            .map(|record| {
+                let first = read_i32(record, 56).unwrap();
+                let count = read_i32(record, 60).unwrap();
+
+                usize::try_from(first).ok()
+                    .zip(usize::try_from(count).ok())
+                    .map(|(first, count)| first..first + count)
+                    .filter(|faces| faces.end <= face_count)
+                    .ok_or_else(|| self.error("a model has faces past the end"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if models.is_empty() {
+            return Err(self.error("no world model"));
+        }
+
+        let visibility = self.visibility(&planes)?;
+
+        Ok(Bsp { entities, faces, models, textures, visibility })
+    }
+
+    fn entities(&self) -> Result<Map, AssetError> {
+        let data = self.lumps[ENTITIES];
+        let text = String::from_utf8_lossy(&data[..data.iter().position(|&b| b == 0).unwrap_or(data.len())]);
+
+        text.parse::<Map>().map_err(|e| {
+            let offset = text.len() - e.input.len();
+            AssetError::parse(self.file_name, format!("bad entity at byte {} ({:?})", offset, e.code))
+        })
+    }
+
+    /// The name of each miptex, and the images of the ones embedded in the
+    /// map when there's a palette to decode them with.
+    fn textures(&self, palette: Option<&[u8]>) -> Result<(Vec<String>, AHashMap<String, image::DynamicImage>), AssetError> {
+        let data = self.lumps[TEXTURES];
+        if data.is_empty() {
+            return Ok((Vec::new(), AHashMap::new()));
+        }
+
+        // checked against the offsets that fit in the lump before it sizes
+        // anything
+        let count = read_i32(data, 0).and_then(|count| usize::try_from(count).ok())
+            .filter(|&count| count <= data.len().saturating_sub(4) / 4)
+            .ok_or_else(|| self.error("bad texture count"))?;
+
+        let mut names = Vec::with_capacity(count);
+        let mut images = AHashMap::new();
+
+        for i in 0..count {
+            let offset = read_i32(data, 4 + i * 4).ok_or_else(|| self.error("truncated texture offsets"))?;
+
+            // -1 marks a texture the compiler didn't find
+            let Some(miptex) = usize::try_from(offset).ok().and_then(|offset| data.get(offset..)) else {
+                names.push(String::new());
+                continue;
+            };
+
+            let name = miptex.get(..16).ok_or_else(|| self.error(format!("texture {} is truncated", i)))?;
+            let name = String::from_utf8_lossy(&name[..name.iter().position(|&b| b == 0).unwrap_or(16)]).to_ascii_lowercase();
+
+            let image = palette.and_then(|palette| {
+                let width = read_u32(miptex, 16)?;
+                let height = read_u32(miptex, 20)?;
+                let pixels = read_u32(miptex, 24).filter(|&offset| offset > 0)?;
+                let pixels = miptex.get(pixels as usize..)?.get(..(width as usize).checked_mul(height as usize)?)?;
+                Some(decode_miptex(&name, width, height, pixels, palette))
+            });
+
+            match image {
+                Some(image) => {
+                    images.insert(name.clone(), image);
+                }
+                None if palette.is_some() => log::warn!("{} isn't embedded in the map", name),
+                None => {}
+            }
+
+            names.push(name);
+        }
+
+        Ok((names, images))
+    }
+
+    fn visibility(&self, planes: &[Plane]) -> Result<Option<Visibility>, AssetError> {
+        let data = self.lumps[VISIBILITY];
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        let world = self.lumps[MODELS];
+        let root = read_i32(world, 36).unwrap();
+        let leaf_count = read_i32(world, 52).unwrap().max(0) as usize;
+
+        let nodes = self.records(NODES, 24, 44)?
+            .map(|record| match self.format {
+                Format::Bsp29 => [
+                    read_i32(record, 0).unwrap(),
+                    read_i16(record, 4).unwrap() as i32,
+                    read_i16(record, 6).unwrap() as i32,
+                ],
+                Format::Bsp2 => [read_i32(record, 0).unwrap(), read_i32(record, 4).unwrap(), read_i32(record, 8).unwrap()],
+            })
+            .collect::<Vec<_>>();
+
+        let rows = self.records(LEAVES, 28, 44)?
+            .map(|record| usize::try_from(read_i32(record, 4).unwrap()).ok().filter(|&row| row < data.len()))
+            .collect::<Vec<_>>();
+
+        let valid = |child: i32| match child {
+            0.. => (child as usize) < nodes.len(),
+            _ => ((-1 - child) as usize) < rows.len(),
+        };
+        // compilers write children after their parents, which also keeps
+        // the walks down the tree from going round in circles
+        let after = |parent: usize, child: i32| child < 0 || child as usize > parent;
+        let planes_valid = nodes.iter().enumerate().all(|(i, &[plane, front, back])| {
+            usize::try_from(plane).is_ok_and(|plane| plane < planes.len())
+                && valid(front) && valid(back)
+                && after(i, front) && after(i, back)
+        });
+
+        if !valid(root) || !planes_valid {
+            return Err(self.error("the BSP tree has bad node, leaf or plane indices"));
+        }
+
+        Ok(Some(Visibility {
+            planes: planes.iter()
+                .map(|plane| (Vector3::from(map::to_world_direction(plane.normal)), (plane.dist * map::MAP_SCALE) as f32))
+                .collect(),
+            nodes,
+            rows,
+            data: data.to_vec(),
+            leaf_count,
+            root,
+        }))
+    }
+}
+
+/// The first lightmap sample of `face` along its texture axes and how many
+/// samples it spans, or `None` when that's more than an atlas holds, like
+/// Quake's "Bad surface extents".
+fn extents(face: &Face) -> Option<([f64; 2], [u32; 2])> {
+    let range = |axis: usize| {
+        let values = face.vertices.iter().map(|&vertex| face.tex_axes.uv(vertex, (1, 1))[axis] as f64 / LUXEL_SIZE);
+        let min = values.clone().fold(f64::INFINITY, f64::min).floor();
+        let max = values.fold(f64::NEG_INFINITY, f64::max).ceil();
+
+        // also false for the infinities and NaNs of broken texture axes
+        let fits = (0.0..lightmap::MAX_ATLAS_SIZE as f64).contains(&(max - min));
+        fits.then(|| (min, (max - min) as u32 + 1))
+    };
+
+    let (s, t) = (range(0)?, range(1)?);
+    Some(([s.0, t.0], [s.1, t.1]))
+}
+
+/// The lightmap of `face`, adding up the maps of its `styles` from `offset`
+/// in `lighting`, or in the colored `lit` if there is one. Animated and
+/// switchable styles are skipped, their lights are drawn live.
+fn samples(
+    face: &Face,
+    (min, size): ([f64; 2], [u32; 2]),
+    lighting: &[u8],
+    lit: Option<&[u8]>,
+    offset: usize,
+    styles: &[u8],
+) -> Option<FaceSamples> {
+    let count = size[0].checked_mul(size[1])? as usize;
+
+    let texels = (0..count)
+        .map(|i| {
+            let mut light = [0u32; 3];
+
+            for (slot, &style) in styles.iter().enumerate() {
+                if lightstyle::is_live(style as usize) {
+                    continue;
+                }
+
+                let sample = offset + slot * count + i;
+                let color = match lit {
+                    Some(lit) => lit.get(sample * 3..sample * 3 + 3)?.try_into().ok()?,
+                    None => [*lighting.get(sample)?; 3],
+                };
+
+                for (light, value) in light.iter_mut().zip(color) {
+                    *light += value as u32;
+                }
+            }
+
+            let [r, g, b] = light.map(|value| value.min(255) as u8);
+            Some([r, g, b, 255])
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(FaceSamples {
+        axes: Some((face.tex_axes, min)),
+        size,
+        texels,
+    })
+}
+
+/// The first mip level of a miptex. Names starting with `{` are alpha
+/// tested, with their last palette entry see-through.
+fn decode_miptex(name: &str, width: u32, height: u32, pixels: &[u8], palette: &[u8]) -> image::DynamicImage {
+    let masked = name.starts_with('{');
+
+    let rgba = pixels.iter()
+        .flat_map(|&index| {
+            let color = &palette[index as usize * 3..index as usize * 3 + 3];
+            let alpha = if masked && index == 255 { 0 } else { 255 };
+            [color[0], color[1], color[2], alpha]
+        })
+        .collect::<Vec<_>>();
+
+    image::RgbaImage::from_raw(width, height, rgba)
+        .expect("one texel per pixel")
+        .into()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_i16(data: &[u8], offset: usize) -> Option<i16> {
+    Some(i16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_f32(data: &[u8], offset: usize) -> Option<f32> {
+    Some(f32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_vector(data: &[u8], offset: usize) -> Vector3<f32> {
+    Vector3::new(
+        read_f32(data, offset).unwrap(),
+        read_f32(data, offset + 4).unwrap(),
+        read_f32(data, offset + 8).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_SIZE: usize = 4 + LUMP_COUNT * 8;
+
+    /// A BSP29 header followed by `lumps`, laid out one after the other.
+    fn bsp29(lumps: &[&[u8]; LUMP_COUNT]) -> Vec<u8> {
+        let mut data = vec![29, 0, 0, 0];
+        let mut offset = HEADER_SIZE;
+
+        for lump in lumps {
+            data.extend((offset as i32).to_le_bytes());
+            data.extend((lump.len() as i32).to_le_bytes());
+            offset += lump.len();
+        }
+        for lump in lumps {
+            data.extend_from_slice(lump);
+        }
+
+        data
+    }
+
+    fn parse_error(data: &[u8]) -> String {
+        match Parser::new("test.bsp", data) {
+            Ok(_) => panic!("parsed a broken header"),
+            Err(AssetError::Parse { message, .. }) => message,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn reads_lumps() {
+        let mut lumps = [&[][..]; LUMP_COUNT];
+        lumps[PLANES] = &[1; 20];
+        lumps[VERTICES] = &[2; 24];
+
+        let data = bsp29(&lumps);
+        let parser = Parser::new("test.bsp", &data).unwrap();
+
+        assert_eq!(parser.format, Format::Bsp29);
+        assert_eq!(parser.lumps[PLANES], &[1; 20]);
+        assert_eq!(parser.lumps[VERTICES], &[2; 24]);
+        assert_eq!(parser.records(VERTICES, 12, 12).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(parse_error(b"IBSP"), "not a BSP29 or BSP2 file");
+        assert_eq!(parse_error(&[29, 0]), "not a BSP29 or BSP2 file");
+        assert_eq!(parse_error(&bsp29(&[&[]; LUMP_COUNT])[..10]), "truncated header");
+
+        assert!(matches!(Parser::new("test.bsp", b"2PSB"), Err(AssetError::Unsupported { .. })));
+    }
+
+    #[test]
+    fn rejects_lumps_out_of_bounds() {
+        let mut data = bsp29(&[&[0; 8]; LUMP_COUNT]);
+        let length = data.len();
+
+        // the last lump one byte longer than the file
+        data[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&9i32.to_le_bytes());
+        assert_eq!(parse_error(&data), format!("lump {} is past the end of the file", MODELS));
+
+        // a negative offset
+        data[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&8i32.to_le_bytes());
+        data[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+        assert_eq!(parse_error(&data), "lump 0 is past the end of the file");
+
+        // an offset and length that overflow
+        data[4..8].copy_from_slice(&(length as i32).to_le_bytes());
+        data[8..12].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert_eq!(parse_error(&data), "lump 0 is past the end of the file");
+    }
+
+    #[test]
+    fn rejects_bad_surface_extents() {
+        let floats = |values: &[f32]| values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        let ints = |values: &[i32]| values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+
+        // one lit triangle, facing up, with its texture scaled by `scale`
+        let face_error = |scale: f32| {
+            let planes = [floats(&[0.0, 0.0, 1.0, 0.0]), ints(&[2])].concat();
+            let vertices = floats(&[0.0, 0.0, 0.0, 64.0, 0.0, 0.0, 0.0, 64.0, 0.0]);
+            let edges = [0u16, 1, 1, 2, 2, 0].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+            let surfedges = ints(&[0, 1, 2]);
+            let texinfo = [floats(&[scale, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]), ints(&[0, 0])].concat();
+            let faces = [vec![0, 0, 0, 0], ints(&[0]), vec![3, 0, 0, 0], vec![0, NO_STYLE, NO_STYLE, NO_STYLE], ints(&[0])].concat();
+
+            let mut lumps = [&[][..]; LUMP_COUNT];
+            lumps[ENTITIES] = b"{\n\"classname\" \"worldspawn\"\n}\n";
+            lumps[PLANES] = &planes;
+            lumps[VERTICES] = &vertices;
+            lumps[EDGES] = &edges;
+            lumps[SURFEDGES] = &surfedges;
+            lumps[TEXINFO] = &texinfo;
+            lumps[FACES] = &faces;
+            lumps[LIGHTING] = &[0; 16];
+
+            let data = bsp29(&lumps);
+            match Parser::new("test.bsp", &data).unwrap().parse(None, None) {
+                Err(AssetError::Parse { message, .. }) => message,
+                Ok(_) => panic!("parsed a broken face"),
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        };
+
+        // 5 by 5 samples fit, but there's only light for 16
+        assert_eq!(face_error(1.0), "face 0 has a bad light offset");
+        // as many samples as an atlas holds, plus the one past the edge
+        assert_eq!(face_error(lightmap::MAX_ATLAS_SIZE as f32 / 4.0), "face 0 has a bad extents");
+        // more than a u32 counts
+        assert_eq!(face_error(1e12), "face 0 has a bad extents");
+        assert_eq!(face_error(f32::INFINITY), "face 0 has a bad extents");
+        assert_eq!(face_error(f32::NAN), "face 0 has a bad extents");
+    }
+
+    #[test]
+    fn rejects_partial_records() {
+        let mut lumps = [&[][..]; LUMP_COUNT];
+        lumps[PLANES] = &[0; 30];
+
+        let data = bsp29(&lumps);
+        let parser = Parser::new("test.bsp", &data).unwrap();
+
+        assert!(parser.records(PLANES, 20, 20).is_err());
+    }
+}
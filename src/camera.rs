@@ -1,4 +1,8 @@
-use winit::event::*;
+use crate::{
+    ecs::{Entity, World},
+    game::Script,
+    input::Input,
+};
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -23,7 +27,18 @@ impl Camera {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
 
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+        OPENGL_TO_WGPU_MATRIX * proj * view
+    }
+
+    /// Inverse view projection with the eye moved to the origin, taking
+    /// clip space positions to view directions for the skybox.
+    pub fn build_sky_matrix(&self) -> cgmath::Matrix4<f32> {
+        use cgmath::{EuclideanSpace, SquareMatrix};
+
+        let view = cgmath::Matrix4::look_to_rh(cgmath::Point3::origin(), self.target - self.eye, self.up);
+        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+
+        (OPENGL_TO_WGPU_MATRIX * proj * view).invert().unwrap_or_else(cgmath::Matrix4::identity)
     }
 }
 
@@ -31,6 +46,7 @@ impl Camera {
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
     view_proj: [[f32; 4]; 4],
+    view_position: [f32; 4],
 }
 
 impl CameraUniform {
@@ -38,81 +54,46 @@ impl CameraUniform {
         use cgmath::SquareMatrix;
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
+            view_position: [0.0; 4],
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera) {
         self.view_proj = camera.build_view_projection_matrix().into();
+        self.view_position = camera.eye.to_homogeneous().into();
     }
 }
 
+/// The bindings the controller reads, see `res/bindings.cfg`.
+const FORWARD: &str = "forward";
+const RIGHT: &str = "right";
+const LOOK: &str = "look";
+const TURN: &str = "turn";
+
+/// Moves the camera towards the point it looks at and orbits around it,
+/// `speed` units a frame at full tilt.
 pub struct CameraController {
     speed: f32,
-    is_forward_pressed: bool,
-    is_backward_pressed: bool,
-    is_left_pressed: bool,
-    is_right_pressed: bool,
 }
 
 impl CameraController {
     pub fn new(speed: f32) -> Self {
-        Self {
-            speed,
-            is_forward_pressed: false,
-            is_backward_pressed: false,
-            is_left_pressed: false,
-            is_right_pressed: false,
-        }
-    }
-
-    pub fn process_events(&mut self, event: &WindowEvent) -> bool {
-        match event {
-            WindowEvent::KeyboardInput {
-                input: KeyboardInput {
-                    state,
-                    virtual_keycode: Some(keycode),
-                    ..
-                },
-                ..
-            } => {
-                let is_pressed = *state == ElementState::Pressed;
-                match keycode {
-                    VirtualKeyCode::W | VirtualKeyCode::Up => {
-                        self.is_forward_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::A | VirtualKeyCode::Left => {
-                        self.is_left_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::S | VirtualKeyCode::Down => {
-                        self.is_backward_pressed = is_pressed;
-                        true
-                    }
-                    VirtualKeyCode::D | VirtualKeyCode::Right => {
-                        self.is_right_pressed = is_pressed;
-                        true
-                    }
-                    _ => false,
-                }
-            }
-            _ => false,
-        }
+        Self { speed }
     }
 
-    pub fn update_camera(&self, camera: &mut Camera) {
+    fn update_camera(&self, camera: &mut Camera, input: &Input) {
         use cgmath::InnerSpace;
         let forward = camera.target - camera.eye;
         let forward_norm = forward.normalize();
         let forward_mag = forward.magnitude();
 
+        // keys bound twice, like W and Up, don't go twice as fast
+        let step = input.axis(FORWARD).clamp(-1.0, 1.0) * self.speed;
+
         // Prevents glitching when camera gets too close to the
         // center of the scene.
-        if self.is_forward_pressed && forward_mag > self.speed {
-            camera.eye += forward_norm * self.speed;
-        }
-        if self.is_backward_pressed {
-            camera.eye -= forward_norm * self.speed;
+        if step < 0.0 || forward_mag > step {
+            camera.eye += forward_norm * step;
         }
 
         let right = forward_norm.cross(camera.up);
@@ -121,14 +102,28 @@ impl CameraController {
         let forward = camera.target - camera.eye;
         let forward_mag = forward.magnitude();
 
-        if self.is_right_pressed {
+        let mut orbit = input.axis(RIGHT).clamp(-1.0, 1.0);
+        if input.held(LOOK) {
+            orbit += input.axis(TURN);
+        }
+
+        if orbit != 0.0 {
             // Rescale the distance between the target and eye so 
             // that it doesn't change. The eye therefore still 
             // lies on the circle made by the target and eye.
-            camera.eye = camera.target - (forward + right * self.speed).normalize() * forward_mag;
+            camera.eye = camera.target - (forward + right * orbit * self.speed).normalize() * forward_mag;
         }
-        if self.is_left_pressed {
-            camera.eye = camera.target - (forward - right * self.speed).normalize() * forward_mag;
+    }
+}
+
+impl Script for CameraController {
+    fn update(&mut self, entity: Entity, world: &mut World, input: &Input, _dt: f32) {
+        if let Some(camera) = world.get_mut::<Camera>(entity) {
+            self.update_camera(camera, input);
         }
     }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(egui::Slider::new(&mut self.speed, 0.01..=5.0).logarithmic(true).text("speed"));
+    }
 }
@@ -0,0 +1,6 @@
+// The camera uniform, see CameraUniform in camera.rs
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
+};
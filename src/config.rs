@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use crate::adapter::AdapterPreference;
+
+/// Startup options, read from the command line and the environment.
+#[derive(Debug)]
+pub struct Config {
+    /// Asset roots in mount order, directories or archives.
+    pub res_paths: Vec<PathBuf>,
+    /// Graphics APIs adapters may come from.
+    pub backends: wgpu::Backends,
+    pub adapter: AdapterPreference,
+    /// Requested MSAA sample count, lowered to what the adapter supports.
+    pub msaa: u32,
+    /// FXAA after tonemapping, cheaper than MSAA on slow adapters.
+    pub fxaa: bool,
+    /// Quake `.map` or compiled `.bsp` file, relative to the asset roots.
+    pub map: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            res_paths: Vec::new(),
+            backends: wgpu::Backends::all(),
+            adapter: AdapterPreference::default(),
+            msaa: 4,
+            fxaa: false,
+            map: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the environment and then the command line, so flags take
+    /// priority:
+    ///
+    /// - `RES_PATH` (a list in the platform's `PATH` format) and `--res <path>`
+    /// - `WGPU_BACKEND` and `--backend <vulkan,metal,dx12,dx11,gl>`
+    /// - `WGPU_ADAPTER` and `--adapter <discrete|integrated|software>`
+    /// - `MSAA` and `--msaa <1|2|4|8>`
+    /// - `--fxaa`
+    /// - `--map <file>`
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+
+        if let Some(paths) = std::env::var_os("RES_PATH") {
+            config.res_paths.extend(std::env::split_paths(&paths));
+        }
+
+        if let Some(backends) = wgpu::util::backend_bits_from_env() {
+            config.backends = backends;
+        }
+
+        if let Ok(adapter) = std::env::var("WGPU_ADAPTER") {
+            config.adapter = adapter.parse()?;
+        }
+
+        if let Ok(msaa) = std::env::var("MSAA") {
+            config.msaa = parse_msaa(&msaa)?;
+        }
+
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--res" => {
+                    let path = args.next()
+                        .ok_or_else(|| anyhow::anyhow!("--res expects a path"))?;
+                    config.res_paths.push(path.into());
+                }
+
+                "--backend" => {
+                    let backends = args.next()
+                        .ok_or_else(|| anyhow::anyhow!("--backend expects a list of backends"))?;
+                    config.backends = wgpu::util::parse_backends_from_comma_list(&backends);
+                }
+
+                "--adapter" => {
+                    config.adapter = args.next()
+                        .ok_or_else(|| anyhow::anyhow!("--adapter expects an adapter type"))?
+                        .parse()?;
+                }
+
+                "--msaa" => {
+                    let msaa = args.next()
+                        .ok_or_else(|| anyhow::anyhow!("--msaa expects a sample count"))?;
+                    config.msaa = parse_msaa(&msaa)?;
+                }
+
+                "--fxaa" => config.fxaa = true,
+
+                "--map" => {
+                    config.map = Some(args.next()
+                        .ok_or_else(|| anyhow::anyhow!("--map expects a map file"))?);
+                }
+
+                _ => anyhow::bail!("unknown argument {}", arg),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_msaa(s: &str) -> anyhow::Result<u32> {
+    match s.parse() {
+        Ok(count @ (1 | 2 | 4 | 8)) => Ok(count),
+        _ => anyhow::bail!("unknown MSAA sample count {}, expected 1, 2, 4 or 8", s),
+    }
+}
@@ -0,0 +1,28 @@
+// Directions through the faces of a cubemap being rendered
+
+// Direction through `uv` of a face, in the +X, -X, +Y, -Y, +Z, -Z layer
+// order cube views use
+fn face_direction(index: u32, uv: vec2<f32>) -> vec3<f32> {
+    let st = uv * 2.0 - 1.0;
+
+    switch index {
+        case 0u: {
+            return vec3<f32>(1.0, -st.y, -st.x);
+        }
+        case 1u: {
+            return vec3<f32>(-1.0, -st.y, st.x);
+        }
+        case 2u: {
+            return vec3<f32>(st.x, 1.0, st.y);
+        }
+        case 3u: {
+            return vec3<f32>(st.x, -1.0, -st.y);
+        }
+        case 4u: {
+            return vec3<f32>(st.x, -st.y, 1.0);
+        }
+        default: {
+            return vec3<f32>(-st.x, -st.y, -1.0);
+        }
+    }
+}
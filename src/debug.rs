@@ -0,0 +1,351 @@
+use std::rc::Rc;
+
+use cgmath::prelude::*;
+
+use crate::{
+    model::Bounds,
+    registry::{self, RenderPipelineDesc, Registry},
+    shaders,
+    texture::Texture,
+};
+
+/// The name `DebugShading` registers its bind group layout under.
+pub const LAYOUT: &str = "debug";
+
+/// What the main shader outputs instead of the textured surface.
+#[repr(u32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DebugMode {
+    #[default]
+    Shaded = 0,
+    Normals = 1,
+    UvChecker = 2,
+    Depth = 3,
+    MeshColors = 4,
+}
+
+impl DebugMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Shaded => Self::Normals,
+            Self::Normals => Self::UvChecker,
+            Self::UvChecker => Self::Depth,
+            Self::Depth => Self::MeshColors,
+            Self::MeshColors => Self::Shaded,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugUniform {
+    mode: u32,
+    znear: f32,
+    zfar: f32,
+    _padding: u32,
+    mesh_color: [f32; 4],
+}
+
+/// Per-mesh uniform for the debug shading modes.
+///
+/// The buffer holds one slot per mesh, each aligned for a dynamic offset,
+/// so a draw picks its mesh color with [`mesh_offset`]. Every slot repeats
+/// the mode so the shader doesn't need a second binding.
+///
+/// [`mesh_offset`]: DebugShading::mesh_offset
+pub struct DebugShading {
+    pub mode: DebugMode,
+    pub wireframe: bool,
+    pub bounds: bool,
+    /// Outlines the planes of the level's faces.
+    pub planes: bool,
+    buffer: wgpu::Buffer,
+    stride: u32,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl DebugShading {
+    const SLOTS: u32 = 256;
+
+    pub fn new(device: &wgpu::Device, registry: &mut Registry) -> Self {
+        let alignment = device.limits().min_uniform_buffer_offset_alignment;
+        let size = std::mem::size_of::<DebugUniform>() as u32;
+        let stride = size.div_ceil(alignment) * alignment;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Buffer"),
+            size: (stride * Self::SLOTS) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = registry.bind_group_layout(device, LAYOUT, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(size as u64),
+                },
+                count: None,
+            }
+        ]);
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(size as u64),
+                    }),
+                }
+            ],
+            label: Some("debug_bind_group"),
+        });
+
+        Self {
+            mode: DebugMode::default(),
+            wireframe: false,
+            bounds: false,
+            planes: false,
+            buffer,
+            stride,
+            bind_group,
+        }
+    }
+
+    /// Rewrites every slot, call after changing `mode`.
+    pub fn write(&self, queue: &wgpu::Queue, znear: f32, zfar: f32) {
+        let mut data = vec![0u8; (self.stride * Self::SLOTS) as usize];
+
+        for slot in 0..Self::SLOTS {
+            let uniform = DebugUniform {
+                mode: self.mode as u32,
+                znear,
+                zfar,
+                _padding: 0,
+                mesh_color: mesh_color(slot),
+            };
+
+            let start = (slot * self.stride) as usize;
+            data[start..start + std::mem::size_of::<DebugUniform>()]
+                .copy_from_slice(bytemuck::bytes_of(&uniform));
+        }
+
+        queue.write_buffer(&self.buffer, 0, &data);
+    }
+
+    pub fn mesh_offset(&self, mesh: usize) -> u32 {
+        (mesh as u32 % Self::SLOTS) * self.stride
+    }
+}
+
+/// A stable, saturated color for mesh number `index`.
+fn mesh_color(index: u32) -> [f32; 4] {
+    // golden ratio steps around the hue circle keep neighbours apart
+    let hue = (index as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+
+    [r * 0.8 + 0.2, g * 0.8 + 0.2, b * 0.8 + 0.2, 1.0]
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineVertex {
+    position: [f32; 3],
+    color: [f32; 3],
+}
+
+impl LineVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x3];
+
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Immediate mode line drawing for bounding boxes, planes and light
+/// volumes. Queue lines during the frame, [`flush`] uploads them and
+/// [`draw`] renders them inside the main pass.
+///
+/// [`flush`]: DebugLines::flush
+/// [`draw`]: DebugLines::draw
+pub struct DebugLines {
+    vertices: Vec<LineVertex>,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    count: u32,
+    format: wgpu::TextureFormat,
+    pipeline: Rc<wgpu::RenderPipeline>,
+}
+
+impl DebugLines {
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        registry: &mut Registry,
+    ) -> Self {
+        let pipeline = registry.render_pipeline(device, &Self::pipeline_desc(format, sample_count));
+
+        let capacity = 1024;
+        let buffer = Self::create_buffer(device, capacity);
+
+        Self {
+            vertices: Vec::new(),
+            buffer,
+            capacity,
+            count: 0,
+            format,
+            pipeline,
+        }
+    }
+
+    /// Switches to the pipeline for targets with a different MSAA sample
+    /// count.
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, registry: &mut Registry, sample_count: u32) {
+        self.pipeline = registry.render_pipeline(device, &Self::pipeline_desc(self.format, sample_count));
+    }
+
+    fn pipeline_desc(format: wgpu::TextureFormat, sample_count: u32) -> RenderPipelineDesc {
+        RenderPipelineDesc {
+            label: "Debug Line Pipeline",
+            shader: "debug_lines.wgsl",
+            defines: shaders::Defines::new(),
+            bind_group_layouts: vec![registry::CAMERA],
+            vertex_entry: "vs_main",
+            vertex_buffers: vec![LineVertex::desc()],
+            fragment_entry: "fs_main",
+            targets: vec![Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            // lines are tested against the scene but never occlude it
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Line Buffer"),
+            size: (capacity * std::mem::size_of::<LineVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn line(&mut self, a: cgmath::Point3<f32>, b: cgmath::Point3<f32>, color: [f32; 3]) {
+        self.vertices.push(LineVertex { position: a.into(), color });
+        self.vertices.push(LineVertex { position: b.into(), color });
+    }
+
+    /// A closed outline through `points`.
+    pub fn polygon(&mut self, points: &[cgmath::Point3<f32>], color: [f32; 3]) {
+        for (i, &a) in points.iter().enumerate() {
+            self.line(a, points[(i + 1) % points.len()], color);
+        }
+    }
+
+    /// The 12 edges of `bounds` after moving it by `transform`.
+    pub fn bounds(&mut self, bounds: &Bounds, transform: cgmath::Matrix4<f32>, color: [f32; 3]) {
+        let corners = bounds.corners().map(|c| transform.transform_point(c));
+
+        // corners are indexed by bits x, y, z; edges join corners one bit apart
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.line(corners[i], corners[i | bit], color);
+                }
+            }
+        }
+    }
+
+    /// A square patch of the plane through `point` facing `normal`, with
+    /// a short line showing the normal.
+    pub fn plane(&mut self, point: cgmath::Point3<f32>, normal: cgmath::Vector3<f32>, size: f32, color: [f32; 3]) {
+        let normal = normal.normalize();
+        let helper = if normal.y.abs() < 0.99 { cgmath::Vector3::unit_y() } else { cgmath::Vector3::unit_x() };
+        let u = normal.cross(helper).normalize() * size * 0.5;
+        let v = normal.cross(u);
+
+        self.polygon(&[point + u + v, point - u + v, point - u - v, point + u - v], color);
+        self.line(point, point + normal * size * 0.25, color);
+    }
+
+    /// Three circles outlining the sphere a point light reaches.
+    pub fn sphere(&mut self, center: cgmath::Point3<f32>, radius: f32, color: [f32; 3]) {
+        const SEGMENTS: usize = 24;
+
+        let axes = [
+            (cgmath::Vector3::unit_x(), cgmath::Vector3::unit_y()),
+            (cgmath::Vector3::unit_y(), cgmath::Vector3::unit_z()),
+            (cgmath::Vector3::unit_z(), cgmath::Vector3::unit_x()),
+        ];
+
+        for (u, v) in axes {
+            let points = (0..SEGMENTS)
+                .map(|i| {
+                    let angle = i as f32 / SEGMENTS as f32 * std::f32::consts::TAU;
+                    center + (u * angle.cos() + v * angle.sin()) * radius
+                })
+                .collect::<Vec<_>>();
+
+            self.polygon(&points, color);
+        }
+    }
+
+    /// Uploads the lines queued this frame and clears the queue.
+    pub fn flush(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.vertices.len() > self.capacity {
+            self.capacity = self.vertices.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.capacity);
+        }
+
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&self.vertices));
+        self.count = self.vertices.len() as u32;
+        self.vertices.clear();
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if self.count == 0 {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+        render_pass.draw(0..self.count, 0..1);
+    }
+}
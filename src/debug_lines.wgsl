@@ -0,0 +1,36 @@
+// Vertex shader
+
+#include "camera.wgsl"
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec3<f32>,
+};
+
+@vertex
+fn vs_main( in: VertexInput ) -> VertexOutput {
+
+    var out: VertexOutput;
+    out.color = in.color;
+    out.clip_position = camera.view_proj * vec4<f32>(in.position, 1.0);
+
+    return out;
+
+}
+
+// Fragment shader
+
+@fragment
+fn fs_main( in: VertexOutput ) -> @location(0) vec4<f32> {
+
+    return vec4<f32>(in.color, 1.0);
+
+}
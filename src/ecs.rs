@@ -0,0 +1,251 @@
+use std::any::{Any, TypeId};
+
+use ahash::AHashMap;
+
+/// A game object, only meaningful to the [`World`] that spawned it. The
+/// generation tells a despawned entity apart from a later one reusing its
+/// slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity {
+    index: u32,
+    generation: u32,
+}
+
+/// Entities and their components, each type kept in a sparse set so
+/// queries walk a packed array.
+#[derive(Default)]
+pub struct World {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    free: Vec<u32>,
+    storages: AHashMap<TypeId, Box<dyn Storage>>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn(&mut self) -> Entity {
+        match self.free.pop() {
+            Some(index) => {
+                self.alive[index as usize] = true;
+                Entity { index, generation: self.generations[index as usize] }
+            }
+            None => {
+                self.generations.push(0);
+                self.alive.push(true);
+                Entity { index: self.generations.len() as u32 - 1, generation: 0 }
+            }
+        }
+    }
+
+    /// Drops the entity's components. Returns false if it was already gone.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        if !self.contains(entity) {
+            return false;
+        }
+
+        for storage in self.storages.values_mut() {
+            storage.remove(entity.index);
+        }
+
+        let index = entity.index as usize;
+        self.alive[index] = false;
+        self.generations[index] += 1;
+        self.free.push(entity.index);
+
+        true
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        let index = entity.index as usize;
+        self.alive.get(index) == Some(&true) && self.generations[index] == entity.generation
+    }
+
+    /// Adds `component` to `entity`, replacing the one of the same type.
+    /// Ignored if the entity was despawned.
+    pub fn insert<C: 'static>(&mut self, entity: Entity, component: C) {
+        if !self.contains(entity) {
+            log::warn!("inserting a component into despawned {:?}", entity);
+            return;
+        }
+
+        self.storages
+            .entry(TypeId::of::<C>())
+            .or_insert_with(|| Box::new(SparseSet::<C>::default()))
+            .as_any_mut()
+            .downcast_mut::<SparseSet<C>>()
+            .expect("storages are keyed by their component type")
+            .insert(entity, component);
+    }
+
+    pub fn remove<C: 'static>(&mut self, entity: Entity) -> Option<C> {
+        if !self.contains(entity) {
+            return None;
+        }
+
+        self.storage_mut::<C>()?.take(entity.index)
+    }
+
+    pub fn get<C: 'static>(&self, entity: Entity) -> Option<&C> {
+        if !self.contains(entity) {
+            return None;
+        }
+
+        self.storage::<C>()?.get(entity.index)
+    }
+
+    pub fn get_mut<C: 'static>(&mut self, entity: Entity) -> Option<&mut C> {
+        if !self.contains(entity) {
+            return None;
+        }
+
+        self.storage_mut::<C>()?.get_mut(entity.index)
+    }
+
+    /// Every entity with a `C`, in no particular order. Other components
+    /// of the same entity are looked up with [`World::get`].
+    pub fn query<C: 'static>(&self) -> impl Iterator<Item = (Entity, &C)> {
+        self.storage::<C>()
+            .into_iter()
+            .flat_map(|storage| storage.dense.iter().map(|(entity, component)| (*entity, component)))
+    }
+
+    pub fn query_mut<C: 'static>(&mut self) -> impl Iterator<Item = (Entity, &mut C)> {
+        self.storage_mut::<C>()
+            .into_iter()
+            .flat_map(|storage| storage.dense.iter_mut().map(|(entity, component)| (*entity, component)))
+    }
+
+    fn storage<C: 'static>(&self) -> Option<&SparseSet<C>> {
+        self.storages.get(&TypeId::of::<C>())?.as_any().downcast_ref()
+    }
+
+    fn storage_mut<C: 'static>(&mut self) -> Option<&mut SparseSet<C>> {
+        self.storages.get_mut(&TypeId::of::<C>())?.as_any_mut().downcast_mut()
+    }
+}
+
+/// The part of a component storage that doesn't depend on its type, so
+/// despawning can reach every storage.
+trait Storage {
+    fn remove(&mut self, index: u32);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Components packed in `dense`, with `sparse` mapping entity indices to
+/// their place in it.
+struct SparseSet<C> {
+    dense: Vec<(Entity, C)>,
+    sparse: Vec<Option<usize>>,
+}
+
+impl<C> Default for SparseSet<C> {
+    fn default() -> Self {
+        Self { dense: Vec::new(), sparse: Vec::new() }
+    }
+}
+
+impl<C> SparseSet<C> {
+    fn insert(&mut self, entity: Entity, component: C) {
+        let index = entity.index as usize;
+        if self.sparse.len() <= index {
+            self.sparse.resize(index + 1, None);
+        }
+
+        match self.sparse[index] {
+            Some(slot) => self.dense[slot] = (entity, component),
+            None => {
+                self.sparse[index] = Some(self.dense.len());
+                self.dense.push((entity, component));
+            }
+        }
+    }
+
+    fn take(&mut self, index: u32) -> Option<C> {
+        let slot = self.sparse.get_mut(index as usize)?.take()?;
+        let (_, component) = self.dense.swap_remove(slot);
+
+        // the last component moved into the hole
+        if let Some((moved, _)) = self.dense.get(slot) {
+            self.sparse[moved.index as usize] = Some(slot);
+        }
+
+        Some(component)
+    }
+
+    fn get(&self, index: u32) -> Option<&C> {
+        let slot = (*self.sparse.get(index as usize)?)?;
+        Some(&self.dense[slot].1)
+    }
+
+    fn get_mut(&mut self, index: u32) -> Option<&mut C> {
+        let slot = (*self.sparse.get(index as usize)?)?;
+        Some(&mut self.dense[slot].1)
+    }
+}
+
+impl<C: 'static> Storage for SparseSet<C> {
+    fn remove(&mut self, index: u32) {
+        self.take(index);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_slots_get_a_new_generation() {
+        let mut world = World::new();
+        let old = world.spawn();
+        world.insert(old, 1u32);
+
+        assert!(world.despawn(old));
+        assert!(!world.despawn(old));
+
+        let new = world.spawn();
+        assert_eq!(new.index, old.index);
+        assert_ne!(new, old);
+
+        // the stale handle reaches neither the old component nor the new
+        // entity
+        assert!(!world.contains(old));
+        assert_eq!(world.get::<u32>(new), None);
+        world.insert(old, 2u32);
+        assert_eq!(world.get::<u32>(new), None);
+        assert_eq!(world.get::<u32>(old), None);
+
+        world.insert(new, 3u32);
+        assert_eq!(world.get::<u32>(new), Some(&3));
+        assert_eq!(world.remove::<u32>(old), None);
+    }
+
+    #[test]
+    fn removing_keeps_the_others() {
+        let mut world = World::new();
+        let entities = (0..3u32).map(|i| {
+            let entity = world.spawn();
+            world.insert(entity, i);
+            entity
+        }).collect::<Vec<_>>();
+
+        // the last component moves into the removed one's place
+        assert_eq!(world.remove::<u32>(entities[0]), Some(0));
+        assert_eq!(world.get::<u32>(entities[1]), Some(&1));
+        assert_eq!(world.get::<u32>(entities[2]), Some(&2));
+
+        world.despawn(entities[2]);
+        assert_eq!(world.query::<u32>().collect::<Vec<_>>(), [(entities[1], &1)]);
+    }
+}
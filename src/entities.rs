@@ -0,0 +1,462 @@
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use shalrath::repr::Entity;
+
+use crate::model::Bounds;
+use crate::{lightstyle, map};
+
+/// How far past a door its trigger field reaches, in map units, sideways
+/// and up and down.
+const DOOR_FIELD: [f64; 2] = [60.0, 8.0];
+
+/// How far a plat's trigger field stays inside its edges, in map units.
+const PLAT_FIELD_INSET: f64 = 25.0;
+
+/// Seconds a plat waits at the top once nobody is on it.
+const PLAT_WAIT: f32 = 3.0;
+
+/// How far a button can be pressed from, in map units.
+const BUTTON_REACH: f64 = 16.0;
+
+/// A map entity with a behavior: the brush entities, other than the
+/// worldspawn, the point entities that only pass uses on and the lights
+/// that are switched.
+///
+/// An entity *fires* when it's set off, using every entity whose
+/// `targetname` is its `target`, see [`Dispatcher`]. Being used makes
+/// doors open, plats come down, relays and triggers fire and counters
+/// count and switchable lights toggle.
+///
+/// [`Dispatcher`]: crate::events::Dispatcher
+pub struct MapEntity {
+    pub classname: String,
+    pub targetname: Option<String>,
+    pub target: Option<String>,
+    /// Seconds between firing and using the targets.
+    pub delay: f32,
+    /// Shown to the player when it fires.
+    pub message: Option<String>,
+    behavior: Behavior,
+}
+
+enum Behavior {
+    /// Drawn and never moves, like `func_wall`.
+    Static,
+    Mover(Mover),
+    Trigger(Trigger),
+    /// `trigger_relay`, fires when used.
+    Relay,
+    /// `trigger_counter`, fires once it's been used `count` times.
+    Counter { remaining: u32 },
+    /// A light with a `targetname` and a style from
+    /// [`lightstyle::SWITCHABLE`] up, toggling every light of its style
+    /// when used.
+    LightSwitch { style: usize, on: bool },
+}
+
+impl MapEntity {
+    /// Reads the entity's behavior from its classname and properties.
+    /// `extent` is the box around its brushes, in map units, which gives it
+    /// its size. Unknown classes with brushes are drawn and stay where they
+    /// are, other point entities aren't map entities.
+    pub fn new(entity: &Entity, extent: Option<(Point3<f64>, Point3<f64>)>) -> Option<Self> {
+        let classname = map::property(entity, "classname")?;
+
+        let behavior = match (classname, extent) {
+            ("trigger_relay", _) => Behavior::Relay,
+            ("trigger_counter", _) => Behavior::Counter {
+                remaining: map::number(entity, "count").map_or(2, |count| count.max(1.0) as u32),
+            },
+            (_, None) if classname.starts_with("light") => {
+                let style = map::number(entity, "style").map_or(0, |style| style as usize);
+                if style < lightstyle::SWITCHABLE || map::property(entity, "targetname").is_none_or(str::is_empty) {
+                    return None;
+                }
+
+                let starts_off = map::number(entity, "spawnflags").is_some_and(|flags| flags as u32 & 1 != 0);
+                Behavior::LightSwitch { style, on: !starts_off }
+            }
+            (_, None) => return None,
+            ("func_door", Some((min, max))) => Behavior::Mover(Mover::door(entity, min, max)),
+            ("func_plat", Some((min, max))) => Behavior::Mover(Mover::plat(entity, min, max)),
+            ("func_button", Some((min, max))) => Behavior::Mover(Mover::button(entity, min, max)),
+            (_, Some((min, max))) if classname.starts_with("trigger_") => {
+                Behavior::Trigger(Trigger::new(entity, classname, min, max))
+            }
+            (_, Some(_)) => Behavior::Static,
+        };
+
+        let text = |key| map::property(entity, key).filter(|value| !value.is_empty()).map(str::to_string);
+
+        Some(Self {
+            classname: classname.to_string(),
+            targetname: text("targetname"),
+            target: text("target"),
+            delay: map::number(entity, "delay").unwrap_or(0.0).max(0.0) as f32,
+            message: text("message"),
+            behavior,
+        })
+    }
+
+    /// Whether its brushes are drawn. Triggers are invisible volumes.
+    pub fn is_drawn(&self) -> bool {
+        matches!(self.behavior, Behavior::Static | Behavior::Mover(_))
+    }
+
+    /// Where it is relative to where its brushes were built, in world space.
+    pub fn offset(&self) -> Vector3<f32> {
+        match &self.behavior {
+            Behavior::Mover(mover) => mover.offset,
+            _ => Vector3::zero(),
+        }
+    }
+
+    /// The volume the player has to be in to set it off, if anything does.
+    pub fn field(&self) -> Option<&Bounds> {
+        match &self.behavior {
+            Behavior::Mover(mover) => mover.field.as_ref(),
+            Behavior::Trigger(trigger) => Some(&trigger.volume),
+            _ => None,
+        }
+    }
+
+    /// Advances it by `dt` seconds with the player at `player`, in world
+    /// space, returning whether it fired.
+    pub fn update(&mut self, dt: f32, player: Point3<f32>) -> bool {
+        match &mut self.behavior {
+            Behavior::Mover(mover) => mover.update(dt, player),
+            Behavior::Trigger(trigger) => trigger.update(dt, player),
+            _ => false,
+        }
+    }
+
+    /// The style a switchable light sets, and whether it's on now.
+    pub fn light_style(&self) -> Option<(usize, bool)> {
+        match self.behavior {
+            Behavior::LightSwitch { style, on } => Some((style, on)),
+            _ => None,
+        }
+    }
+
+    /// Uses it, returning whether it fired.
+    pub fn activate(&mut self) -> bool {
+        match &mut self.behavior {
+            Behavior::Static => false,
+            Behavior::Mover(mover) => mover.activate(),
+            Behavior::Trigger(trigger) => trigger.fire(),
+            Behavior::Relay => true,
+            Behavior::Counter { remaining } => {
+                if *remaining == 0 {
+                    return false;
+                }
+
+                *remaining -= 1;
+                *remaining == 0
+            }
+            Behavior::LightSwitch { on, .. } => {
+                *on = !*on;
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoverKind {
+    /// Fires when it starts opening.
+    Door,
+    Plat,
+    /// Fires once it's pressed all the way in.
+    Button,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoverState {
+    Closed,
+    Opening,
+    Open,
+    Closing,
+    /// Open until it's used, like a plat with a `targetname`.
+    Held,
+}
+
+/// A door, plat or button, sliding between its closed and open offsets. It
+/// opens while the player is in its field or when it's used, and closes
+/// again `wait` seconds after.
+struct Mover {
+    kind: MoverKind,
+    closed: Vector3<f32>,
+    open: Vector3<f32>,
+    /// World units per second.
+    speed: f32,
+    /// Seconds to stay open, none to never close.
+    wait: Option<f32>,
+    /// None when it only opens when used.
+    field: Option<Bounds>,
+    state: MoverState,
+    offset: Vector3<f32>,
+    timer: f32,
+}
+
+impl Mover {
+    /// A `func_door`, closed where it was built and sliding `angle` by its
+    /// own size less `lip` to open. Doors with a `targetname` only open
+    /// when they're used.
+    fn door(entity: &Entity, min: Point3<f64>, max: Point3<f64>) -> Self {
+        let [side, height] = DOOR_FIELD;
+        let reach = Vector3::new(side, side, height);
+        let field = map::property(entity, "targetname").is_none_or(str::is_empty).then_some((min - reach, max + reach));
+
+        Self::new(
+            MoverKind::Door,
+            Vector3::zero(),
+            slide(entity, min, max, 8.0),
+            map::number(entity, "speed").unwrap_or(100.0),
+            map::number(entity, "wait").unwrap_or(3.0),
+            field,
+        )
+    }
+
+    /// A `func_button`, pressed in along `angle` when the player comes up
+    /// to it, and out again after `wait`.
+    fn button(entity: &Entity, min: Point3<f64>, max: Point3<f64>) -> Self {
+        let reach = Vector3::new(BUTTON_REACH, BUTTON_REACH, BUTTON_REACH);
+
+        Self::new(
+            MoverKind::Button,
+            Vector3::zero(),
+            slide(entity, min, max, 4.0),
+            map::number(entity, "speed").unwrap_or(40.0),
+            map::number(entity, "wait").unwrap_or(1.0),
+            Some((min - reach, max + reach)),
+        )
+    }
+
+    /// A `func_plat`, built at the top and resting `height` below until the
+    /// player steps on it. Plats with a `targetname` wait at the top until
+    /// they're used.
+    fn plat(entity: &Entity, min: Point3<f64>, max: Point3<f64>) -> Self {
+        let size = max - min;
+        let height = map::number(entity, "height").unwrap_or(size.z - 8.0).max(0.0);
+
+        // a field over the top, spanning the whole travel, inset from the
+        // edges unless the plat is too narrow for it
+        let mut field_min = min + Vector3::new(PLAT_FIELD_INSET, PLAT_FIELD_INSET, 0.0);
+        let mut field_max = max - Vector3::new(PLAT_FIELD_INSET, PLAT_FIELD_INSET, -8.0);
+        field_min.z = field_max.z - (height + 8.0);
+
+        for axis in 0..2 {
+            if size[axis] <= PLAT_FIELD_INSET * 2.0 {
+                field_min[axis] = (min[axis] + max[axis]) * 0.5;
+                field_max[axis] = field_min[axis] + 1.0;
+            }
+        }
+
+        let mut plat = Self::new(
+            MoverKind::Plat,
+            Vector3::new(0.0, 0.0, -height),
+            Vector3::zero(),
+            map::number(entity, "speed").unwrap_or(150.0),
+            PLAT_WAIT as f64,
+            Some((field_min, field_max)),
+        );
+
+        if map::property(entity, "targetname").is_some_and(|name| !name.is_empty()) {
+            plat.state = MoverState::Held;
+            plat.offset = plat.open;
+        }
+
+        plat
+    }
+
+    /// A closed mover with offsets and field in map units. A negative
+    /// `wait` keeps it open.
+    fn new(
+        kind: MoverKind,
+        closed: Vector3<f64>,
+        open: Vector3<f64>,
+        speed: f64,
+        wait: f64,
+        field: Option<(Point3<f64>, Point3<f64>)>,
+    ) -> Self {
+        let offset = |v: Vector3<f64>| Vector3::from(map::to_world_direction(v * map::MAP_SCALE));
+        let closed = offset(closed);
+
+        Self {
+            kind,
+            closed,
+            open: offset(open),
+            speed: (speed * map::MAP_SCALE) as f32,
+            wait: (wait >= 0.0).then_some(wait as f32),
+            field: field.map(|(min, max)| Bounds::from_points([map::to_world(min), map::to_world(max)])),
+            state: MoverState::Closed,
+            offset: closed,
+            timer: 0.0,
+        }
+    }
+
+    /// Opens it, or lets a held plat down. Returns whether it fired.
+    fn activate(&mut self) -> bool {
+        match self.state {
+            MoverState::Closed | MoverState::Closing => {
+                self.state = MoverState::Opening;
+                self.kind == MoverKind::Door
+            }
+            MoverState::Open => {
+                self.timer = self.wait.unwrap_or(0.0);
+                false
+            }
+            MoverState::Opening => false,
+            MoverState::Held => {
+                self.state = MoverState::Closing;
+                false
+            }
+        }
+    }
+
+    fn update(&mut self, dt: f32, player: Point3<f32>) -> bool {
+        let touched = self.state != MoverState::Held && self.field.as_ref().is_some_and(|field| field.contains(player));
+        let mut fired = touched && self.activate();
+
+        match self.state {
+            MoverState::Closed | MoverState::Held => {}
+            MoverState::Opening => {
+                if self.move_towards(self.open, dt) {
+                    self.state = MoverState::Open;
+                    self.timer = self.wait.unwrap_or(0.0);
+                    fired |= self.kind == MoverKind::Button;
+                }
+            }
+            MoverState::Open => {
+                if self.wait.is_some() {
+                    self.timer -= dt;
+                    if self.timer <= 0.0 {
+                        self.state = MoverState::Closing;
+                    }
+                }
+            }
+            MoverState::Closing => {
+                if self.move_towards(self.closed, dt) {
+                    self.state = MoverState::Closed;
+                }
+            }
+        }
+
+        fired
+    }
+
+    /// Moves `speed * dt` towards `target`, returning whether it got there.
+    fn move_towards(&mut self, target: Vector3<f32>, dt: f32) -> bool {
+        let to_target = target - self.offset;
+        let step = self.speed * dt;
+
+        if to_target.magnitude() <= step {
+            self.offset = target;
+            true
+        } else {
+            self.offset += to_target.normalize() * step;
+            false
+        }
+    }
+}
+
+/// A `trigger_*` volume. `trigger_once` and `trigger_secret` fire the first
+/// time the player touches them or they're used, the others at most every
+/// `wait` seconds, so while the player is inside.
+struct Trigger {
+    volume: Bounds,
+    /// Seconds between firings, none to fire only once.
+    wait: Option<f32>,
+    cooldown: f32,
+    fired: bool,
+}
+
+impl Trigger {
+    fn new(entity: &Entity, classname: &str, min: Point3<f64>, max: Point3<f64>) -> Self {
+        let once = matches!(classname, "trigger_once" | "trigger_secret");
+        let wait = map::number(entity, "wait").unwrap_or(0.2);
+
+        Self {
+            volume: Bounds::from_points([map::to_world(min), map::to_world(max)]),
+            wait: (!once && wait >= 0.0).then_some(wait as f32),
+            cooldown: 0.0,
+            fired: false,
+        }
+    }
+
+    fn update(&mut self, dt: f32, player: Point3<f32>) -> bool {
+        self.cooldown -= dt;
+        self.volume.contains(player) && self.fire()
+    }
+
+    fn fire(&mut self) -> bool {
+        if self.cooldown > 0.0 || (self.fired && self.wait.is_none()) {
+            return false;
+        }
+
+        self.fired = true;
+        self.cooldown = self.wait.unwrap_or(0.0);
+        true
+    }
+}
+
+/// How far a mover slides to open: along its `angle` by its own size
+/// less `lip`, in map units.
+fn slide(entity: &Entity, min: Point3<f64>, max: Point3<f64>, default_lip: f64) -> Vector3<f64> {
+    let direction = move_direction(map::number(entity, "angle").unwrap_or(0.0));
+    let size = max - min;
+    let lip = map::number(entity, "lip").unwrap_or(default_lip);
+
+    direction * (direction.x.abs() * size.x + direction.y.abs() * size.y + direction.z.abs() * size.z - lip).max(0.0)
+}
+
+/// The direction an `angle` property points, in map space. -1 is up and -2
+/// down, anything else a yaw in degrees.
+fn move_direction(angle: f64) -> Vector3<f64> {
+    if angle == -1.0 {
+        Vector3::unit_z()
+    } else if angle == -2.0 {
+        -Vector3::unit_z()
+    } else {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        Vector3::new(cos, sin, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn func_door(properties: &str) -> MapEntity {
+        let map = format!("{{\n\"classname\" \"func_door\"\n{}}}", properties)
+            .parse::<shalrath::repr::Map>()
+            .unwrap();
+        let extent = (Point3::new(0.0, 0.0, 0.0), Point3::new(64.0, 8.0, 128.0));
+
+        MapEntity::new(&map.0[0], Some(extent)).unwrap()
+    }
+
+    #[test]
+    fn named_doors_open_when_used() {
+        let inside = map::to_world(Point3::new(32.0, 4.0, 64.0)).into();
+
+        // a door on its own opens, and fires, when the player comes close
+        let mut door = func_door("");
+        assert!(door.field().is_some());
+        assert!(door.update(0.1, inside));
+
+        let mut door = func_door("\"targetname\" \"gate\"\n");
+        assert!(door.field().is_none());
+
+        for _ in 0..100 {
+            assert!(!door.update(0.1, inside));
+        }
+        assert_eq!(door.offset(), Vector3::zero());
+
+        assert!(door.activate());
+        for _ in 0..10 {
+            door.update(0.1, inside);
+        }
+        assert_ne!(door.offset(), Vector3::zero());
+    }
+}
@@ -0,0 +1,35 @@
+// Renders one face of a cubemap from an equirectangular panorama
+
+struct Face {
+    index: u32,
+    // a vec3 would be aligned to 16 bytes and double the struct's size
+    _padding0: u32,
+    _padding1: u32,
+    _padding2: u32,
+};
+
+@group(0) @binding(0)
+var t_equirect: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> face: Face;
+
+#include "fullscreen.wgsl"
+
+#include "cube_face.wgsl"
+
+@fragment
+fn fs_main(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let dir = normalize(face_direction(face.index, in.uv));
+
+    let pi = 3.14159265;
+    let u = atan2(dir.z, dir.x) / (2.0 * pi) + 0.5;
+    let v = acos(clamp(dir.y, -1.0, 1.0)) / pi;
+
+    // 32 bit float textures can't be filtered everywhere, so take the
+    // nearest texel
+    let dimensions = textureDimensions(t_equirect);
+    let texel = vec2<i32>(vec2<f32>(u, v) * vec2<f32>(dimensions));
+    let clamped = clamp(texel, vec2<i32>(0), dimensions - vec2<i32>(1));
+
+    return vec4<f32>(textureLoad(t_equirect, clamped, 0).rgb, 1.0);
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+/// Why an asset couldn't be loaded. Every variant carries the asset path.
+#[derive(Debug)]
+pub enum AssetError {
+    /// None of the mounted sources has the file.
+    Missing { path: String },
+
+    /// The file exists but reading it failed.
+    Io { path: String, source: std::io::Error },
+
+    /// The file was read but its contents are malformed. The position is
+    /// only known for formats we parse ourselves.
+    Parse {
+        path: String,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+
+    /// There is no decoder for the file's format.
+    Unsupported { path: String, format: String },
+
+    /// The asset is valid but bigger than the device allows.
+    GpuLimit {
+        path: String,
+        limit: &'static str,
+        requested: u32,
+        max: u32,
+    },
+}
+
+impl AssetError {
+    pub fn parse(path: &str, message: impl fmt::Display) -> Self {
+        Self::Parse {
+            path: path.to_string(),
+            line: None,
+            column: None,
+            message: message.to_string(),
+        }
+    }
+
+    /// A parse error at byte `offset` of `text`, with 1-based line and column.
+    pub fn parse_at(path: &str, text: &[u8], offset: usize, message: impl fmt::Display) -> Self {
+        let before = &text[..offset.min(text.len())];
+        let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+        let line_start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+
+        Self::Parse {
+            path: path.to_string(),
+            line: Some(line),
+            column: Some(offset - line_start + 1),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn io(path: &str, source: impl Into<std::io::Error>) -> Self {
+        Self::Io {
+            path: path.to_string(),
+            source: source.into(),
+        }
+    }
+
+    pub fn image(path: &str, error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::IoError(source) => Self::io(path, source),
+            image::ImageError::Unsupported(e) => Self::Unsupported {
+                path: path.to_string(),
+                format: e.to_string(),
+            },
+            e => Self::parse(path, e),
+        }
+    }
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { path } => write!(f, "{}: not found in any mounted asset source", path),
+
+            Self::Io { path, source } => write!(f, "{}: {}", path, source),
+
+            Self::Parse { path, line: Some(line), column: Some(column), message } => {
+                write!(f, "{}:{}:{}: {}", path, line, column, message)
+            }
+            Self::Parse { path, line: Some(line), message, .. } => write!(f, "{}:{}: {}", path, line, message),
+            Self::Parse { path, message, .. } => write!(f, "{}: {}", path, message),
+
+            Self::Unsupported { path, format } => write!(f, "{}: unsupported format ({})", path, format),
+
+            Self::GpuLimit { path, limit, requested, max } => {
+                write!(f, "{}: {} of {} is over the device limit of {}", path, limit, requested, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AssetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Why the renderer couldn't start.
+#[derive(Debug)]
+pub enum InitError {
+    NoAdapter,
+    RequestDevice(wgpu::RequestDeviceError),
+    Asset(AssetError),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoAdapter => write!(f, "no graphics adapter compatible with the window surface"),
+            Self::RequestDevice(e) => write!(f, "couldn't open the graphics device: {}", e),
+            Self::Asset(e) => write!(f, "couldn't load a built-in asset: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::RequestDevice(e) => Some(e),
+            Self::Asset(e) => Some(e),
+            Self::NoAdapter => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for InitError {
+    fn from(e: wgpu::RequestDeviceError) -> Self {
+        Self::RequestDevice(e)
+    }
+}
+
+impl From<AssetError> for InitError {
+    fn from(e: AssetError) -> Self {
+        Self::Asset(e)
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+
+use ahash::AHashMap;
+
+use crate::entities::MapEntity;
+
+/// Uses handled in one frame before giving up on entities that keep
+/// setting each other off, like a relay targeting itself.
+const MAX_USES: usize = 1024;
+
+/// What the map's entities did this frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// An entity fired, using its `target` if it has one.
+    Fired { classname: String, target: Option<String> },
+    /// The `message` of an entity that fired, for the player to read.
+    Message(String),
+    /// A switchable light was used, turning every light of `style` on or
+    /// off.
+    LightStyle { style: usize, on: bool },
+}
+
+/// Passes uses along the `target` and `targetname` links between map
+/// entities. The links are resolved once, when the map is loaded, and
+/// entities with a `delay` use their targets that many seconds after they
+/// fire.
+pub struct Dispatcher {
+    /// Entities by their `targetname`.
+    targets: AHashMap<String, Vec<usize>>,
+    /// Entities that fired, and the seconds left until they use their
+    /// targets.
+    delayed: Vec<(f32, usize)>,
+}
+
+impl Dispatcher {
+    pub fn new(entities: &[MapEntity]) -> Self {
+        let mut targets = AHashMap::<String, Vec<usize>>::new();
+
+        for (i, entity) in entities.iter().enumerate() {
+            if let Some(name) = &entity.targetname {
+                targets.entry(name.clone()).or_default().push(i);
+            }
+        }
+
+        for entity in entities {
+            if let Some(target) = entity.target.as_ref().filter(|target| !targets.contains_key(*target)) {
+                log::warn!("{} targets {}, which nothing is called", entity.classname, target);
+            }
+        }
+
+        Self {
+            targets,
+            delayed: Vec::new(),
+        }
+    }
+
+    /// Passes on the uses of the entities in `fired`, indices into
+    /// `entities`, and of the delayed ones whose time is up after `dt`
+    /// seconds, and of every entity those set off in turn.
+    pub fn dispatch(&mut self, entities: &mut [MapEntity], dt: f32, fired: impl IntoIterator<Item = usize>) -> Vec<Event> {
+        // entities firing, and whether their delay is over
+        let mut queue = fired.into_iter().map(|i| (i, false)).collect::<VecDeque<_>>();
+
+        self.delayed.retain_mut(|(left, i)| {
+            *left -= dt;
+            if *left > 0.0 {
+                return true;
+            }
+
+            queue.push_back((*i, true));
+            false
+        });
+
+        let mut events = Vec::new();
+        let mut uses = 0;
+
+        while let Some((i, waited)) = queue.pop_front() {
+            let entity = &entities[i];
+
+            if entity.delay > 0.0 && !waited {
+                self.delayed.push((entity.delay, i));
+                continue;
+            }
+
+            events.push(Event::Fired { classname: entity.classname.clone(), target: entity.target.clone() });
+            events.extend(entity.message.clone().map(Event::Message));
+
+            let Some(targets) = entity.target.as_ref().and_then(|target| self.targets.get(target)) else {
+                continue;
+            };
+
+            for &target in targets {
+                uses += 1;
+                if uses > MAX_USES {
+                    log::warn!("entities kept using each other, stopping after {} uses", MAX_USES);
+                    return events;
+                }
+
+                if entities[target].activate() {
+                    queue.push_back((target, false));
+                }
+                events.extend(entities[target].light_style().map(|(style, on)| Event::LightStyle { style, on }));
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entities(map: &str) -> Vec<MapEntity> {
+        let map = map.trim().parse::<shalrath::repr::Map>().unwrap();
+        map.0.iter().filter_map(|entity| MapEntity::new(entity, None)).collect()
+    }
+
+    fn fired(events: &[Event], classname: &str) -> usize {
+        events.iter()
+            .filter(|event| matches!(event, Event::Fired { classname: fired, .. } if fired == classname))
+            .count()
+    }
+
+    #[test]
+    fn counters_fire_on_their_count() {
+        let mut entities = entities(r#"
+{
+"classname" "trigger_relay"
+"target" "counter"
+}
+{
+"classname" "trigger_counter"
+"targetname" "counter"
+"count" "3"
+}
+"#);
+        let mut dispatcher = Dispatcher::new(&entities);
+
+        let counts = (0..4)
+            .map(|_| fired(&dispatcher.dispatch(&mut entities, 0.0, [0]), "trigger_counter"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(counts, [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn delayed_targets_wait() {
+        // a counter of one fires like a relay, but can be told apart
+        let mut entities = entities(r#"
+{
+"classname" "trigger_relay"
+"target" "late"
+}
+{
+"classname" "trigger_counter"
+"targetname" "late"
+"count" "1"
+"delay" "1"
+"message" "finally"
+}
+"#);
+        let mut dispatcher = Dispatcher::new(&entities);
+
+        let events = dispatcher.dispatch(&mut entities, 0.0, [0]);
+        assert_eq!(fired(&events, "trigger_relay"), 1);
+        assert_eq!(fired(&events, "trigger_counter"), 0);
+
+        assert_eq!(dispatcher.dispatch(&mut entities, 0.6, []), []);
+
+        let events = dispatcher.dispatch(&mut entities, 0.6, []);
+        assert_eq!(fired(&events, "trigger_counter"), 1);
+        assert!(events.contains(&Event::Message("finally".to_string())));
+
+        assert_eq!(dispatcher.dispatch(&mut entities, 1.0, []), []);
+    }
+
+    #[test]
+    fn loops_stop() {
+        let mut entities = entities(r#"
+{
+"classname" "trigger_relay"
+"targetname" "loop"
+"target" "loop"
+}
+"#);
+        let mut dispatcher = Dispatcher::new(&entities);
+
+        // the first firing, then one for each use until the limit
+        let events = dispatcher.dispatch(&mut entities, 0.0, [0]);
+        assert_eq!(fired(&events, "trigger_relay"), MAX_USES + 1);
+
+        // nothing is left queued for the next frame
+        assert_eq!(dispatcher.dispatch(&mut entities, 0.0, []), []);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{
+    assets::{AssetManager, Handle},
+    camera::{Camera, CameraController},
+    ecs::{Entity, World},
+    input::Input,
+    instance::{InstanceGrid, Transform},
+    lightmap::{self, FULLBRIGHT},
+    lightstyle::{self, LightStyle},
+    map,
+    model::{Bounds, Model},
+};
+
+/// Draws `model` at the entity's [`Transform`].
+pub struct Renderable {
+    pub model: Handle<Model>,
+}
+
+/// A point light at the entity's [`Transform`].
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub color: [f32; 3],
+    /// Distance at which the light has faded out.
+    pub radius: f32,
+    /// Brightness at the light, 1 being the lightmap's full brightness.
+    pub intensity: f32,
+    /// Already in the level's lightmap, so the level isn't lit by it
+    /// again.
+    pub baked: bool,
+}
+
+/// Turns the entity's [`Light`] on and off with the other lights of
+/// `style`, a style from [`lightstyle::SWITCHABLE`] up.
+#[derive(Debug, Clone, Copy)]
+pub struct LightSwitch {
+    pub style: usize,
+    /// [`Light::intensity`] while it's on.
+    pub intensity: f32,
+}
+
+/// The space the entity takes up, in its [`Transform`]'s frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Collider {
+    pub bounds: Bounds,
+}
+
+/// Gameplay behavior attached to an entity as a `Box<dyn Script>`
+/// component.
+pub trait Script {
+    /// Runs once a frame with the script taken out of `world`, so it can
+    /// reach every component of its entity, and `input` as it is this
+    /// frame.
+    fn update(&mut self, entity: Entity, world: &mut World, input: &Input, dt: f32);
+
+    /// Settings shown in the overlay next to the entity's other
+    /// components.
+    fn ui(&mut self, _ui: &mut egui::Ui) {}
+}
+
+/// The game objects, kept apart from the renderer, which only reads them
+/// once a frame.
+pub struct Game {
+    pub world: World,
+    /// Lays out the copies of `model`, respawned by [`Game::spawn_grid`].
+    pub grid: InstanceGrid,
+    model: Handle<Model>,
+    grid_entities: Vec<Entity>,
+}
+
+impl Game {
+    pub fn new(assets: &mut AssetManager, aspect: f32) -> Self {
+        let mut world = World::new();
+
+        let camera = world.spawn();
+        world.insert(camera, Camera {
+            // position the camera one unit up and 2 units back
+            // +z is out of the screen
+            eye: (0.0, 1.0, 2.0).into(),
+            // have it look at the origin
+            target: (0.0, 0.0, 0.0).into(),
+            // which way is "up"
+            up: cgmath::Vector3::unit_y(),
+            aspect,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+        });
+        world.insert::<Box<dyn Script>>(camera, Box::new(CameraController::new(0.2)));
+
+        let mut game = Self {
+            world,
+            grid: InstanceGrid::default(),
+            model: assets.load_model("teapot.obj"),
+            grid_entities: Vec::new(),
+        };
+        game.spawn_grid();
+
+        game
+    }
+
+    /// Replaces the model copies with ones laid out by `grid`.
+    pub fn spawn_grid(&mut self) {
+        for entity in self.grid_entities.drain(..) {
+            self.world.despawn(entity);
+        }
+
+        for transform in self.grid.instances() {
+            let entity = self.world.spawn();
+            self.world.insert(entity, transform);
+            self.world.insert(entity, Renderable { model: self.model.clone() });
+            self.grid_entities.push(entity);
+        }
+    }
+
+    pub fn update(&mut self, input: &Input, dt: f32) {
+        let scripted = self.world.query::<Box<dyn Script>>().map(|(entity, _)| entity).collect::<Vec<_>>();
+
+        for entity in scripted {
+            let Some(mut script) = self.world.remove::<Box<dyn Script>>(entity) else {
+                continue;
+            };
+
+            script.update(entity, &mut self.world, input, dt);
+
+            // unless it despawned its own entity
+            if self.world.contains(entity) {
+                self.world.insert(entity, script);
+            }
+        }
+
+        self.fit_colliders();
+    }
+
+    /// Spawns the `lights` of a level that finished loading.
+    pub fn spawn_lights(&mut self, lights: &[lightmap::Light]) {
+        for light in lights {
+            let intensity = (light.intensity / FULLBRIGHT as f64) as f32;
+            let entity = self.world.spawn();
+            self.world.insert(entity, Transform::at(map::to_world(light.origin).into()));
+            self.world.insert(entity, Light {
+                color: light.color.map(|c| c as f32),
+                radius: (light.intensity / light.wait * map::MAP_SCALE) as f32,
+                intensity: if light.starts_off { 0.0 } else { intensity },
+                baked: light.style.is_none(),
+            });
+
+            match light.style {
+                Some(style) if style >= lightstyle::SWITCHABLE => {
+                    self.world.insert(entity, LightSwitch { style, intensity });
+                }
+                Some(style) => {
+                    self.world.insert::<Box<dyn Script>>(entity, Box::new(LightStyle::new(lightstyle::PATTERNS[style], intensity)));
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Turns the switchable lights of `style` on or off.
+    pub fn switch_lights(&mut self, style: usize, on: bool) {
+        let switched = self.world.query::<LightSwitch>()
+            .filter(|(_, switch)| switch.style == style)
+            .map(|(entity, switch)| (entity, if on { switch.intensity } else { 0.0 }))
+            .collect::<Vec<_>>();
+
+        for (entity, intensity) in switched {
+            if let Some(light) = self.world.get_mut::<Light>(entity) {
+                light.intensity = intensity;
+            }
+        }
+    }
+
+    /// Gives renderables without a collider one around their model, once
+    /// it has loaded.
+    fn fit_colliders(&mut self) {
+        let fitted = self.world.query::<Renderable>()
+            .filter(|&(entity, _)| self.world.get::<Collider>(entity).is_none())
+            .filter_map(|(entity, renderable)| {
+                let model = renderable.model.get()?;
+                let corners = model.meshes.iter().flat_map(|mesh| [mesh.bounds.min.into(), mesh.bounds.max.into()]);
+                Some((entity, Collider { bounds: Bounds::from_points(corners) }))
+            })
+            .collect::<Vec<_>>();
+
+        for (entity, collider) in fitted {
+            self.world.insert(entity, collider);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use ahash::AHashMap;
+
+/// The swapchain image of the frame being drawn. Every graph has it without
+/// declaring it.
+pub const SURFACE: &str = "surface";
+
+/// Shared by every graph so a rebuilt graph never reuses a generation.
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// How to allocate a texture the graph owns. It's recreated whenever the
+/// surface is resized.
+#[derive(Debug, Clone, Copy)]
+pub struct TextureDesc {
+    pub format: wgpu::TextureFormat,
+    /// Size relative to the surface, so 0.5 is a half resolution target.
+    pub scale: f32,
+    pub sample_count: u32,
+    /// Usages on top of `RENDER_ATTACHMENT`, e.g. `TEXTURE_BINDING` for
+    /// textures a later pass samples.
+    pub usage: wgpu::TextureUsages,
+}
+
+impl TextureDesc {
+    pub fn new(format: wgpu::TextureFormat) -> Self {
+        Self {
+            format,
+            scale: 1.0,
+            sample_count: 1,
+            usage: wgpu::TextureUsages::empty(),
+        }
+    }
+}
+
+struct Transient {
+    desc: TextureDesc,
+    texture: Option<wgpu::Texture>,
+    view: Option<wgpu::TextureView>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ColorTarget {
+    pub texture: &'static str,
+    /// Single sampled texture a multisampled `texture` is resolved into. The
+    /// multisampled samples themselves are discarded after the pass.
+    pub resolve: Option<&'static str>,
+    /// `None` keeps what earlier passes drew.
+    pub clear: Option<wgpu::Color>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DepthTarget {
+    pub texture: &'static str,
+    /// `None` keeps what earlier passes wrote.
+    pub clear: Option<f32>,
+}
+
+/// A render pass and the textures it touches. `key` is what the owner of
+/// the graph matches on to record the pass's draws.
+#[derive(Debug, Clone)]
+pub struct PassDesc<K> {
+    pub key: K,
+    pub name: &'static str,
+    /// Textures the pass samples, see `RenderGraph` for how that orders it.
+    pub reads: Vec<&'static str>,
+    pub color: Vec<ColorTarget>,
+    pub depth: Option<DepthTarget>,
+}
+
+impl<K> PassDesc<K> {
+    pub fn new(key: K, name: &'static str) -> Self {
+        Self {
+            key,
+            name,
+            reads: Vec::new(),
+            color: Vec::new(),
+            depth: None,
+        }
+    }
+
+    pub fn read(mut self, texture: &'static str) -> Self {
+        self.reads.push(texture);
+        self
+    }
+
+    pub fn color(mut self, texture: &'static str, clear: Option<wgpu::Color>) -> Self {
+        self.color.push(ColorTarget { texture, resolve: None, clear });
+        self
+    }
+
+    /// A multisampled color target resolved into `resolve` at the end of
+    /// the pass.
+    pub fn color_resolved(mut self, texture: &'static str, resolve: &'static str, clear: Option<wgpu::Color>) -> Self {
+        self.color.push(ColorTarget { texture, resolve: Some(resolve), clear });
+        self
+    }
+
+    pub fn depth(mut self, texture: &'static str, clear: Option<f32>) -> Self {
+        self.depth = Some(DepthTarget { texture, clear });
+        self
+    }
+
+    fn writes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.color.iter().flat_map(|target| std::iter::once(target.texture).chain(target.resolve))
+            .chain(self.depth.iter().map(|target| target.texture))
+    }
+}
+
+/// The passes of a frame and the transient textures between them.
+///
+/// `compile` sorts the passes added with `add_pass` by the textures they
+/// touch. Passes writing the same texture keep their declaration order, so
+/// an overlay declared after the scene draws on top of it. A pass reading a
+/// texture sees what the writers declared before it left there, and writers
+/// declared after it wait for it to finish. If nothing declared earlier
+/// writes the texture, the pass waits for all of its writers instead, so
+/// consumers can be declared before producers.
+///
+/// The graph doesn't know how to draw anything. Its owner walks `passes`,
+/// opens each with `begin_pass` on one encoder and records into it.
+pub struct RenderGraph<K> {
+    textures: AHashMap<&'static str, Transient>,
+    passes: Vec<PassDesc<K>>,
+    order: Vec<usize>,
+    size: (u32, u32),
+    generation: u32,
+}
+
+impl<K> RenderGraph<K> {
+    pub fn new() -> Self {
+        Self {
+            textures: AHashMap::new(),
+            passes: Vec::new(),
+            order: Vec::new(),
+            size: (0, 0),
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn add_texture(&mut self, name: &'static str, desc: TextureDesc) {
+        assert!(name != SURFACE, "{} is reserved for the swapchain image", SURFACE);
+
+        self.textures.insert(name, Transient {
+            desc,
+            texture: None,
+            view: None,
+        });
+    }
+
+    pub fn add_pass(&mut self, pass: PassDesc<K>) {
+        self.passes.push(pass);
+        self.order.clear();
+    }
+
+    /// Orders the passes by their dependencies.
+    ///
+    /// Panics on a pass using a texture that wasn't declared or on passes
+    /// that depend on each other, both of which are mistakes in the code
+    /// building the graph.
+    pub fn compile(&mut self) {
+        for pass in &self.passes {
+            for texture in pass.reads.iter().cloned().chain(pass.writes()) {
+                assert!(
+                    texture == SURFACE || self.textures.contains_key(texture),
+                    "render pass {} uses undeclared texture {}",
+                    pass.name,
+                    texture,
+                );
+            }
+        }
+
+        let mut dependencies = vec![Vec::new(); self.passes.len()];
+
+        for (i, pass) in self.passes.iter().enumerate() {
+            for (j, other) in self.passes.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+
+                if j < i && pass.writes().any(|write| other.writes().any(|w| w == write)) {
+                    dependencies[i].push(j);
+                }
+            }
+
+            for &read in &pass.reads {
+                let writers = self.passes.iter()
+                    .enumerate()
+                    .filter(|(_, other)| other.writes().any(|write| write == read))
+                    .map(|(j, _)| j)
+                    .collect::<Vec<_>>();
+
+                if writers.iter().any(|&j| j < i) {
+                    for j in writers {
+                        if j < i {
+                            dependencies[i].push(j);
+                        } else {
+                            // written again after this pass reads it
+                            dependencies[j].push(i);
+                        }
+                    }
+                } else {
+                    dependencies[i].extend(writers);
+                }
+            }
+        }
+
+        // Kahn's algorithm, taking the earliest declared ready pass each
+        // time so independent passes stay in declaration order
+        let mut remaining = dependencies.iter().map(Vec::len).collect::<Vec<_>>();
+        let mut done = vec![false; self.passes.len()];
+        self.order.clear();
+
+        while self.order.len() < self.passes.len() {
+            let next = (0..self.passes.len())
+                .find(|&i| !done[i] && remaining[i] == 0)
+                .unwrap_or_else(|| {
+                    let stuck = (0..self.passes.len())
+                        .filter(|&i| !done[i])
+                        .map(|i| self.passes[i].name)
+                        .collect::<Vec<_>>();
+                    panic!("render passes {:?} depend on each other", stuck)
+                });
+
+            done[next] = true;
+            self.order.push(next);
+
+            for (i, deps) in dependencies.iter().enumerate() {
+                remaining[i] -= deps.iter().filter(|&&dep| dep == next).count();
+            }
+        }
+    }
+
+    /// Reallocates every transient texture for a surface of `width` by
+    /// `height`.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        if self.size == (width, height) && self.textures.values().all(|t| t.texture.is_some()) {
+            return;
+        }
+
+        self.size = (width, height);
+        self.generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+
+        for (name, transient) in &mut self.textures {
+            let desc = transient.desc;
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(name),
+                size: wgpu::Extent3d {
+                    width: ((width as f32 * desc.scale) as u32).max(1),
+                    height: ((height as f32 * desc.scale) as u32).max(1),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: desc.sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format: desc.format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | desc.usage,
+            });
+
+            transient.view = Some(texture.create_view(&wgpu::TextureViewDescriptor::default()));
+            transient.texture = Some(texture);
+        }
+    }
+
+    /// Changes every time the transient textures are reallocated, so bind
+    /// groups sampling them know to be rebuilt.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.textures.contains_key(name)
+    }
+
+    /// The view of a transient texture. Panics if it wasn't declared or the
+    /// graph hasn't been sized yet.
+    pub fn view(&self, name: &str) -> &wgpu::TextureView {
+        self.textures.get(name)
+            .and_then(|transient| transient.view.as_ref())
+            .unwrap_or_else(|| panic!("no allocated render graph texture {}", name))
+    }
+
+    /// The passes in the order `compile` put them.
+    pub fn passes(&self) -> impl Iterator<Item = &PassDesc<K>> {
+        assert_eq!(self.order.len(), self.passes.len(), "render graph used before compile");
+        self.order.iter().map(|&i| &self.passes[i])
+    }
+
+    /// Opens `pass` on `encoder` with its attachments bound.
+    pub fn begin_pass<'a>(
+        &'a self,
+        pass: &PassDesc<K>,
+        encoder: &'a mut wgpu::CommandEncoder,
+        surface: &'a wgpu::TextureView,
+    ) -> wgpu::RenderPass<'a> {
+        let view = |name: &str| if name == SURFACE { surface } else { self.view(name) };
+
+        let color_attachments = pass.color.iter()
+            .map(|target| Some(wgpu::RenderPassColorAttachment {
+                view: view(target.texture),
+                resolve_target: target.resolve.map(view),
+                ops: wgpu::Operations {
+                    load: target.clear.map_or(wgpu::LoadOp::Load, wgpu::LoadOp::Clear),
+                    store: target.resolve.is_none(),
+                },
+            }))
+            .collect::<Vec<_>>();
+
+        let depth_stencil_attachment = pass.depth.map(|target| wgpu::RenderPassDepthStencilAttachment {
+            view: view(target.texture),
+            depth_ops: Some(wgpu::Operations {
+                load: target.clear.map_or(wgpu::LoadOp::Load, wgpu::LoadOp::Clear),
+                store: true,
+            }),
+            stencil_ops: None,
+        });
+
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(pass.name),
+            color_attachments: &color_attachments,
+            depth_stencil_attachment,
+        })
+    }
+}
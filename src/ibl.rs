@@ -0,0 +1,332 @@
+use std::rc::Rc;
+
+use wgpu::util::DeviceExt;
+
+use crate::registry::{RenderPipelineDesc, Registry};
+use crate::texture::Texture;
+use crate::shaders;
+
+/// The name `Environment` registers its bind group layout under.
+pub const LAYOUT: &str = "environment";
+
+/// The name the source cubemap's layout is registered under while the maps
+/// are generated.
+const GENERATOR_LAYOUT: &str = "ibl_source";
+
+/// Mip levels of the prefiltered specular cubemap, from mirror-like at 0
+/// to fully rough at the last. `shader.wgsl` picks the level by roughness
+/// with the same count.
+const PREFILTERED_MIPS: u32 = 5;
+
+const PREFILTERED_SIZE: u32 = 128;
+
+const IRRADIANCE_SIZE: u32 = 32;
+
+const BRDF_LUT_SIZE: u32 = 256;
+
+const CUBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct IblParams {
+    face: u32,
+    roughness: f32,
+    _padding: [u32; 2],
+}
+
+/// Image based lighting for the PBR shading path: diffuse irradiance and
+/// GGX prefiltered specular cubemaps of the environment, and the BRDF
+/// lookup table of the split sum approximation. All three are rendered once
+/// up front.
+///
+/// Bound as group 3 of the model pipelines.
+pub struct Environment {
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Environment {
+    /// Lighting from the skybox cubemap `source`.
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, source: &Texture, registry: &mut Registry) -> Self {
+        let generator = Generator::new(device, source, registry);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("IBL Encoder"),
+        });
+
+        let irradiance = create_cube(device, "irradiance_cubemap", IRRADIANCE_SIZE, 1);
+        generator.render_cube(&mut encoder, &generator.irradiance, &irradiance, 0, 0.0);
+
+        let prefiltered = create_cube(device, "prefiltered_cubemap", PREFILTERED_SIZE, PREFILTERED_MIPS);
+        for mip in 0..PREFILTERED_MIPS {
+            let roughness = mip as f32 / (PREFILTERED_MIPS - 1) as f32;
+            generator.render_cube(&mut encoder, &generator.prefilter, &prefiltered, mip, roughness);
+        }
+
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("brdf_lut"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+        });
+        let brdf_view = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+        generator.render(&mut encoder, &generator.brdf, &brdf_view, 0, 0.0);
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let cube_view = |texture: &wgpu::Texture| texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group_layout = registry.bind_group_layout(device, LAYOUT, &Self::bind_group_layout_entries());
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&cube_view(&irradiance)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&cube_view(&prefiltered)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&brdf_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("environment_bind_group"),
+        });
+
+        Self {
+            bind_group,
+        }
+    }
+
+    /// Lighting from a uniformly colored environment, for scenes without a
+    /// skybox. `color` is linear.
+    pub fn from_color(device: &wgpu::Device, queue: &wgpu::Queue, color: [f32; 3], registry: &mut Registry) -> Self {
+        // the faces are sRGB textures, so encode the color to come back out
+        // as given
+        let encode = |c: f32| {
+            let srgb = if c <= 0.003_130_8 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+            (srgb.clamp(0.0, 1.0) * 255.0).round() as u8
+        };
+        let pixel = image::Rgba([encode(color[0]), encode(color[1]), encode(color[2]), 255]);
+        let face = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, pixel));
+
+        let source = Texture::cubemap_from_faces(device, queue, &[(); 6].map(|_| face.clone()), "environment_color")
+            .expect("a 1x1 cubemap fits every device");
+
+        Self::new(device, queue, &source, registry)
+    }
+
+    fn bind_group_layout_entries() -> [wgpu::BindGroupLayoutEntry; 4] {
+        let texture = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+
+        [
+            texture(0, wgpu::TextureViewDimension::Cube),
+            texture(1, wgpu::TextureViewDimension::Cube),
+            texture(2, wgpu::TextureViewDimension::D2),
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ]
+    }
+}
+
+fn create_cube(device: &wgpu::Device, label: &str, size: u32, mip_level_count: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: CUBE_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+    })
+}
+
+/// The pipelines of `ibl.wgsl`, only alive while an `Environment` is built
+/// from `source`.
+struct Generator<'a> {
+    device: &'a wgpu::Device,
+    source: &'a Texture,
+    bind_group_layout: Rc<wgpu::BindGroupLayout>,
+    irradiance: Rc<wgpu::RenderPipeline>,
+    prefilter: Rc<wgpu::RenderPipeline>,
+    brdf: Rc<wgpu::RenderPipeline>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(device: &'a wgpu::Device, source: &'a Texture, registry: &mut Registry) -> Self {
+        let bind_group_layout = registry.bind_group_layout(device, GENERATOR_LAYOUT, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::Cube,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]);
+
+        let mut pipeline = |label, fragment_entry, format| registry.render_pipeline(device, &RenderPipelineDesc {
+            label,
+            shader: "ibl.wgsl",
+            defines: shaders::Defines::new(),
+            bind_group_layouts: vec![GENERATOR_LAYOUT],
+            vertex_entry: "vs_fullscreen",
+            vertex_buffers: Vec::new(),
+            fragment_entry,
+            targets: vec![Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        Self {
+            device,
+            source,
+            irradiance: pipeline("Irradiance Pipeline", "fs_irradiance", CUBE_FORMAT),
+            prefilter: pipeline("Prefilter Pipeline", "fs_prefilter", CUBE_FORMAT),
+            brdf: pipeline("BRDF LUT Pipeline", "fs_brdf", BRDF_LUT_FORMAT),
+            bind_group_layout,
+        }
+    }
+
+    /// Renders all six faces of mip level `mip` of `target`.
+    fn render_cube(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        target: &wgpu::Texture,
+        mip: u32,
+        roughness: f32,
+    ) {
+        for face in 0..6 {
+            let view = target.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_mip_level: mip,
+                mip_level_count: std::num::NonZeroU32::new(1),
+                base_array_layer: face,
+                array_layer_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            });
+
+            self.render(encoder, pipeline, &view, face, roughness);
+        }
+    }
+
+    fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        target: &wgpu::TextureView,
+        face: u32,
+        roughness: f32,
+    ) {
+        let params = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("IBL Params Buffer"),
+            contents: bytemuck::cast_slice(&[IblParams { face, roughness, _padding: [0; 2] }]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.source.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: params.as_entire_binding(),
+                },
+            ],
+            label: Some("ibl_bind_group"),
+        });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("IBL Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,138 @@
+// Precomputes image based lighting from an environment cubemap: diffuse
+// irradiance, GGX prefiltered specular and the split sum BRDF lookup
+
+struct Params {
+    face: u32,
+    roughness: f32,
+    _padding: vec2<u32>,
+};
+
+@group(0) @binding(0)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(1)
+var s_environment: sampler;
+@group(0) @binding(2)
+var<uniform> params: Params;
+
+let PI: f32 = 3.14159265;
+
+#include "fullscreen.wgsl"
+
+#include "cube_face.wgsl"
+
+// Orthonormal basis around `n`
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(n.y) > 0.999 {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// Half vector around `n` distributed like the GGX lobe of `roughness`
+fn importance_sample_ggx(xi: vec2<f32>, n: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    return normalize(tangent_frame(n) * h);
+}
+
+@fragment
+fn fs_irradiance(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let n = normalize(face_direction(params.face, in.uv));
+    let frame = tangent_frame(n);
+
+    // cosine weighted sum over the hemisphere on a fixed grid
+    let steps = 32u;
+    var irradiance = vec3<f32>(0.0);
+
+    for (var i = 0u; i < steps * 4u; i++) {
+        let phi = (f32(i) + 0.5) / f32(steps * 4u) * 2.0 * PI;
+
+        for (var j = 0u; j < steps; j++) {
+            let theta = (f32(j) + 0.5) / f32(steps) * 0.5 * PI;
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(t_environment, s_environment, frame * local, 0.0).rgb;
+
+            irradiance += color * cos(theta) * sin(theta);
+        }
+    }
+
+    irradiance = PI * irradiance / f32(steps * steps * 4u);
+    return vec4<f32>(irradiance, 1.0);
+}
+
+@fragment
+fn fs_prefilter(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    // the split sum approximation assumes the view is along the normal
+    let n = normalize(face_direction(params.face, in.uv));
+    let v = n;
+
+    let samples = 256u;
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+
+    for (var i = 0u; i < samples; i++) {
+        let h = importance_sample_ggx(hammersley(i, samples), n, params.roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+
+        let n_dot_l = dot(n, l);
+        if n_dot_l > 0.0 {
+            color += textureSampleLevel(t_environment, s_environment, l, 0.0).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+
+    return vec4<f32>(color / max(weight, 0.0001), 1.0);
+}
+
+fn geometry_schlick_ggx(n_dot_v: f32, roughness: f32) -> f32 {
+    // image based lighting remaps k differently from direct lights
+    let k = roughness * roughness / 2.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+@fragment
+fn fs_brdf(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    // texture coordinates are n dot v and roughness, as the lookup uses them
+    let n_dot_v = max(in.uv.x, 0.001);
+    let roughness = in.uv.y;
+
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let n = vec3<f32>(0.0, 0.0, 1.0);
+
+    let samples = 512u;
+    var scale = 0.0;
+    var bias = 0.0;
+
+    for (var i = 0u; i < samples; i++) {
+        let h = importance_sample_ggx(hammersley(i, samples), n, roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(v, h), 0.0);
+
+        if n_dot_l > 0.0 {
+            let g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+
+    return vec4<f32>(scale / f32(samples), bias / f32(samples), 0.0, 1.0);
+}
@@ -0,0 +1,414 @@
+use ahash::{AHashMap, AHashSet};
+use winit::event::*;
+
+use crate::{error::AssetError, resources};
+
+/// Where [`Bindings::load`] looks in the asset roots.
+const BINDINGS_FILE: &str = "bindings.cfg";
+
+/// Used when the asset roots have no bindings, or theirs don't parse.
+const DEFAULT_BINDINGS: &str = include_str!("../res/bindings.cfg");
+
+/// Mouse wheel lines a touchpad's pixel scroll counts as per pixel.
+const LINES_PER_PIXEL: f32 = 1.0 / 20.0;
+
+/// Declares `$name`, mirroring gilrs's `$gilrs` so bindings can name
+/// gamepad inputs without the `gamepad` feature.
+macro_rules! gamepad_enum {
+    ($(#[$meta:meta])* $name:ident($gilrs:ident) { $($variant:ident),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            fn parse(name: &str) -> Option<Self> {
+                match name {
+                    $(stringify!($variant) => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+
+            #[cfg(feature = "gamepad")]
+            fn from_gilrs(value: gilrs::$gilrs) -> Option<Self> {
+                match value {
+                    $(gilrs::$gilrs::$variant => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+gamepad_enum! {
+    PadButton(Button) {
+        South, East, North, West, C, Z,
+        LeftTrigger, LeftTrigger2, RightTrigger, RightTrigger2,
+        Select, Start, Mode, LeftThumb, RightThumb,
+        DPadUp, DPadDown, DPadLeft, DPadRight,
+    }
+}
+
+gamepad_enum! {
+    /// Sticks and analog triggers, from -1 to 1.
+    PadAxis(Axis) {
+        LeftStickX, LeftStickY, LeftZ, RightStickX, RightStickY, RightZ, DPadX, DPadY,
+    }
+}
+
+/// Declares `key`, looking up the keys bindings can name by their
+/// `VirtualKeyCode` names.
+macro_rules! keys {
+    ($($key:ident),* $(,)?) => {
+        fn key(name: &str) -> Option<VirtualKeyCode> {
+            match name {
+                $(stringify!($key) => Some(VirtualKeyCode::$key),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+keys! {
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    Snapshot, Scroll, Pause, Insert, Home, Delete, End, PageDown, PageUp,
+    Left, Up, Right, Down, Back, Return, Space, Tab, Capital,
+    Numlock, Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    NumpadAdd, NumpadSubtract, NumpadMultiply, NumpadDivide, NumpadDecimal, NumpadEnter,
+    Apostrophe, Backslash, Comma, Equals, Grave, LBracket, RBracket, Minus, Period, Semicolon, Slash,
+    LAlt, LControl, LShift, LWin, RAlt, RControl, RShift, RWin,
+}
+
+/// Mouse movement since the last frame, in pixels, and wheel turns, in
+/// lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MouseAxis {
+    X,
+    Y,
+    Wheel,
+}
+
+/// A physical input, named in bindings as a key (`W`, `Escape`, `F1`),
+/// `Mouse` and a button or axis (`MouseLeft`, `MouseX`), or `Pad` and a
+/// gamepad button or axis (`PadSouth`, `PadLeftStickX`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Key(VirtualKeyCode),
+    MouseButton(MouseButton),
+    MouseAxis(MouseAxis),
+    PadButton(PadButton),
+    PadAxis(PadAxis),
+}
+
+impl Source {
+    fn parse(name: &str) -> Option<Self> {
+        if let Some(mouse) = name.strip_prefix("Mouse") {
+            return match mouse {
+                "Left" => Some(Self::MouseButton(MouseButton::Left)),
+                "Right" => Some(Self::MouseButton(MouseButton::Right)),
+                "Middle" => Some(Self::MouseButton(MouseButton::Middle)),
+                "X" => Some(Self::MouseAxis(MouseAxis::X)),
+                "Y" => Some(Self::MouseAxis(MouseAxis::Y)),
+                "Wheel" => Some(Self::MouseAxis(MouseAxis::Wheel)),
+                other => other.parse().ok().map(|button| Self::MouseButton(MouseButton::Other(button))),
+            };
+        }
+
+        if let Some(pad) = name.strip_prefix("Pad") {
+            return PadButton::parse(pad).map(Self::PadButton)
+                .or_else(|| PadAxis::parse(pad).map(Self::PadAxis));
+        }
+
+        key(name).map(Self::Key)
+    }
+}
+
+/// Whether `event` lets go of a key or button, or of everything when the
+/// window loses focus. Those have to reach [`Input`] even when the overlay
+/// takes them, or the input would stay held.
+pub fn is_release(event: &WindowEvent) -> bool {
+    match event {
+        WindowEvent::KeyboardInput { input, .. } => input.state == ElementState::Released,
+        WindowEvent::MouseInput { state, .. } => *state == ElementState::Released,
+        WindowEvent::Focused(focused) => !focused,
+        _ => false,
+    }
+}
+
+/// Named actions and axes and the inputs bound to them, from a file of
+/// lines like
+///
+/// ```text
+/// # comment
+/// action quit Escape PadStart
+/// axis forward W S*-1 PadLeftStickY
+/// ```
+///
+/// An action is on while any of its inputs is held. An axis adds up its
+/// inputs times their scale, 1 if there's none, with held keys and buttons
+/// counting as 1.
+#[derive(Debug, Default)]
+pub struct Bindings {
+    actions: AHashMap<String, Vec<Source>>,
+    axes: AHashMap<String, Vec<(Source, f32)>>,
+}
+
+impl Bindings {
+    /// The bindings in `bindings.cfg` from the asset roots, or the built in
+    /// ones if there's none or it doesn't parse.
+    pub fn load() -> Self {
+        match resources::load_string(BINDINGS_FILE).and_then(|text| Self::parse(BINDINGS_FILE, &text)) {
+            Ok(bindings) => return bindings,
+            Err(AssetError::Missing { .. }) => {}
+            Err(e) => log::error!("{}, using the default bindings", e),
+        }
+
+        Self::parse(BINDINGS_FILE, DEFAULT_BINDINGS).expect("the default bindings parse")
+    }
+
+    pub fn parse(path: &str, text: &str) -> Result<Self, AssetError> {
+        let mut bindings = Self::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| AssetError::Parse {
+                path: path.to_string(),
+                line: Some(i + 1),
+                column: None,
+                message,
+            };
+            let source = |name: &str| Source::parse(name).ok_or_else(|| error(format!("unknown input {:?}", name)));
+
+            let mut words = line.split('#').next().unwrap_or_default().split_whitespace();
+            let Some(kind) = words.next() else {
+                continue;
+            };
+            let name = words.next().ok_or_else(|| error(format!("{} without a name", kind)))?.to_string();
+
+            match kind {
+                "action" => {
+                    let sources = words.map(source).collect::<Result<Vec<_>, _>>()?;
+                    bindings.actions.entry(name).or_default().extend(sources);
+                }
+                "axis" => {
+                    let sources = words
+                        .map(|word| match word.split_once('*') {
+                            Some((name, scale)) => {
+                                let scale = scale.parse().map_err(|_| error(format!("bad scale {:?}", scale)))?;
+                                Ok((source(name)?, scale))
+                            }
+                            None => Ok((source(word)?, 1.0)),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    bindings.axes.entry(name).or_default().extend(sources);
+                }
+                _ => return Err(error(format!("expected action or axis, found {:?}", kind))),
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    fn is_bound(&self, source: Source) -> bool {
+        self.actions.values().flatten().any(|&bound| bound == source)
+            || self.axes.values().flatten().any(|&(bound, _)| bound == source)
+    }
+}
+
+/// The state of the inputs this frame, read through their [`Bindings`].
+/// Fed window and device events as they come, and gamepads once a frame
+/// with the `gamepad` feature.
+pub struct Input {
+    bindings: Bindings,
+    held: AHashSet<Source>,
+    /// Went down since the last frame.
+    pressed: AHashSet<Source>,
+    mouse: AHashMap<MouseAxis, f32>,
+    pad_axes: AHashMap<PadAxis, f32>,
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<gilrs::Gilrs>,
+}
+
+impl Input {
+    pub fn new(bindings: Bindings) -> Self {
+        Self {
+            bindings,
+            held: AHashSet::new(),
+            pressed: AHashSet::new(),
+            mouse: AHashMap::new(),
+            pad_axes: AHashMap::new(),
+            #[cfg(feature = "gamepad")]
+            gilrs: gilrs::Gilrs::new()
+                .map_err(|e| log::warn!("gamepads are unavailable: {}", e))
+                .ok(),
+        }
+    }
+
+    /// Returns true if `event` is bound to an action or axis.
+    pub fn window_event(&mut self, event: &WindowEvent) -> bool {
+        let source = match event {
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    state,
+                    virtual_keycode: Some(keycode),
+                    ..
+                },
+                ..
+            } => {
+                self.set_held(Source::Key(*keycode), *state == ElementState::Pressed);
+                Source::Key(*keycode)
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                self.set_held(Source::MouseButton(*button), *state == ElementState::Pressed);
+                Source::MouseButton(*button)
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 * LINES_PER_PIXEL,
+                };
+                *self.mouse.entry(MouseAxis::Wheel).or_default() += lines;
+                Source::MouseAxis(MouseAxis::Wheel)
+            }
+            // keys released while away never come back up
+            WindowEvent::Focused(false) => {
+                self.held.clear();
+                return false;
+            }
+            _ => return false,
+        };
+
+        self.bindings.is_bound(source)
+    }
+
+    /// Takes mouse movement from the raw device events, which keep coming
+    /// at the edges of the screen.
+    pub fn device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (x, y) } = event {
+            *self.mouse.entry(MouseAxis::X).or_default() += *x as f32;
+            *self.mouse.entry(MouseAxis::Y).or_default() += *y as f32;
+        }
+    }
+
+    /// Reads what the gamepads did since the last call, without the
+    /// `gamepad` feature there are none.
+    pub fn poll_gamepads(&mut self) {
+        #[cfg(feature = "gamepad")]
+        while let Some(gilrs::Event { event, .. }) = self.gilrs.as_mut().and_then(gilrs::Gilrs::next_event) {
+            match event {
+                gilrs::EventType::ButtonPressed(button, _) | gilrs::EventType::ButtonReleased(button, _) => {
+                    if let Some(button) = PadButton::from_gilrs(button) {
+                        self.set_held(Source::PadButton(button), matches!(event, gilrs::EventType::ButtonPressed(..)));
+                    }
+                }
+                gilrs::EventType::AxisChanged(axis, value, _) => {
+                    if let Some(axis) = PadAxis::from_gilrs(axis) {
+                        self.pad_axes.insert(axis, value);
+                    }
+                }
+                gilrs::EventType::Disconnected => self.pad_axes.clear(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Forgets this frame's presses and mouse movement. Called once the
+    /// frame's reads are done.
+    pub fn end_frame(&mut self) {
+        self.pressed.clear();
+        self.mouse.clear();
+    }
+
+    /// Whether any input of `action` is held.
+    pub fn held(&self, action: &str) -> bool {
+        self.action(action).any(|source| self.held.contains(source))
+    }
+
+    /// Whether an input of `action` went down this frame.
+    pub fn pressed(&self, action: &str) -> bool {
+        self.action(action).any(|source| self.pressed.contains(source))
+    }
+
+    /// The sum of `axis`'s inputs, 0 for axes that aren't bound.
+    pub fn axis(&self, axis: &str) -> f32 {
+        self.bindings.axes
+            .get(axis)
+            .into_iter()
+            .flatten()
+            .map(|(source, scale)| self.value(*source) * scale)
+            .sum()
+    }
+
+    fn action(&self, action: &str) -> impl Iterator<Item = &Source> {
+        self.bindings.actions.get(action).into_iter().flatten()
+    }
+
+    fn value(&self, source: Source) -> f32 {
+        match source {
+            Source::MouseAxis(axis) => self.mouse.get(&axis).copied().unwrap_or(0.0),
+            Source::PadAxis(axis) => self.pad_axes.get(&axis).copied().unwrap_or(0.0),
+            held => if self.held.contains(&held) { 1.0 } else { 0.0 },
+        }
+    }
+
+    fn set_held(&mut self, source: Source, held: bool) {
+        if !held {
+            self.held.remove(&source);
+        } else if self.held.insert(source) {
+            // not on key repeats
+            self.pressed.insert(source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(text: &str) -> (Option<usize>, String) {
+        match Bindings::parse("test.cfg", text) {
+            Err(AssetError::Parse { line, message, .. }) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn actions_and_scaled_axes() {
+        let bindings = Bindings::parse("test.cfg", "\
+# a comment line
+
+action quit Escape MouseLeft # and one after
+action quit PadStart
+axis forward W S*-1 MouseWheel*0.5
+").unwrap();
+
+        assert_eq!(bindings.actions["quit"], [
+            Source::Key(VirtualKeyCode::Escape),
+            Source::MouseButton(MouseButton::Left),
+            Source::PadButton(PadButton::Start),
+        ]);
+        assert_eq!(bindings.axes["forward"], [
+            (Source::Key(VirtualKeyCode::W), 1.0),
+            (Source::Key(VirtualKeyCode::S), -1.0),
+            (Source::MouseAxis(MouseAxis::Wheel), 0.5),
+        ]);
+        assert_eq!(bindings.actions.len(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_inputs() {
+        assert_eq!(parse_error("action quit Escape\naction jump Spacebar"), (Some(2), "unknown input \"Spacebar\"".to_string()));
+        assert_eq!(parse_error("axis turn MouseZ*2"), (Some(1), "unknown input \"MouseZ\"".to_string()));
+        assert_eq!(parse_error("axis turn MouseX*fast"), (Some(1), "bad scale \"fast\"".to_string()));
+        assert_eq!(parse_error("bind quit Escape"), (Some(1), "expected action or axis, found \"bind\"".to_string()));
+        assert_eq!(parse_error("action # quit Escape"), (Some(1), "action without a name".to_string()));
+    }
+
+    #[test]
+    fn default_bindings_parse() {
+        let bindings = Bindings::parse(BINDINGS_FILE, DEFAULT_BINDINGS).unwrap();
+        assert!(bindings.actions.contains_key("quit"));
+    }
+}
@@ -1,16 +1,80 @@
-pub struct Instance {
+use cgmath::prelude::*;
+
+const NUM_INSTANCES_PER_ROW: u32 = 10;
+const SPACE_BETWEEN: f32 = 3.0;
+
+/// Where a game object sits, and the per-instance data its model is drawn
+/// with.
+#[derive(Debug, Clone)]
+pub struct Transform {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
 }
 
-impl Instance {
+impl Transform {
+    /// Unrotated at `position`.
+    pub fn at(position: cgmath::Vector3<f32>) -> Self {
+        Self {
+            position,
+            rotation: cgmath::Quaternion::one(),
+        }
+    }
+
+    pub fn matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)
+    }
+
     pub fn to_raw(&self) -> InstanceRaw {
         InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),
+            model: self.matrix().into(),
+        }
+    }
+}
+
+/// Lays instances out on a square grid around the origin, each tilted
+/// away from the center.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstanceGrid {
+    pub per_row: u32,
+    pub spacing: f32,
+    pub tilt: f32,
+}
+
+impl Default for InstanceGrid {
+    fn default() -> Self {
+        Self {
+            per_row: NUM_INSTANCES_PER_ROW,
+            spacing: SPACE_BETWEEN,
+            tilt: 45.0,
         }
     }
 }
 
+impl InstanceGrid {
+    pub fn instances(&self) -> Vec<Transform> {
+        let InstanceGrid { per_row, spacing, tilt } = *self;
+
+        (0..per_row).flat_map(|z| {
+            (0..per_row).map(move |x| {
+                let x = spacing * (x as f32 - per_row as f32 / 2.0);
+                let z = spacing * (z as f32 - per_row as f32 / 2.0);
+
+                let position = cgmath::Vector3 { x, y: 0.0, z };
+
+                let rotation = if position.is_zero() {
+                    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
+                } else {
+                    cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(tilt))
+                };
+
+                Transform {
+                    position, rotation,
+                }
+            })
+        }).collect::<Vec<_>>()
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
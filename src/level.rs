@@ -0,0 +1,795 @@
+use std::rc::Rc;
+
+use ahash::AHashMap;
+use image::GenericImageView;
+use shalrath::repr::Map;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets,
+    brush::{self, Brush, Face},
+    bsp::{Bsp, Visibility},
+    entities::MapEntity,
+    events::{Dispatcher, Event},
+    error::AssetError,
+    game::Light,
+    instance::{InstanceRaw, Transform},
+    lightmap::{self, Atlas},
+    map,
+    model::{self, AlphaMode, DrawModel, Vertex},
+    post::HDR_FORMAT,
+    registry::{self, RenderPipelineDesc, Registry},
+    resources,
+    shaders,
+    sky::CubemapImages,
+    texture::Texture,
+};
+
+const TEXTURE_EXTENSIONS: [&str; 3] = ["png", "tga", "jpg"];
+
+/// The name `Level` registers its lightmap bind group layout under.
+const LIGHTMAP_LAYOUT: &str = "lightmap";
+
+/// Lights the level shader adds on top of the lightmap, the nearest to the
+/// player when there are more.
+const MAX_LIGHTS: usize = 32;
+
+/// `ModelVertex` with a second set of texture coordinates into the
+/// lightmap atlas.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MapVertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+    pub lightmap_coords: [f32; 2],
+}
+
+impl Vertex for MapVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<MapVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+            ],
+        }
+    }
+}
+
+/// A light that isn't in the lightmap, see `PointLight` in level.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightRaw {
+    position: [f32; 3],
+    radius: f32,
+    /// Premultiplied by the light's intensity.
+    color: [f32; 3],
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsUniform {
+    count: u32,
+    _padding: [u32; 3],
+    lights: [LightRaw; MAX_LIGHTS],
+}
+
+/// One mesh per texture of the level's faces.
+pub struct LevelMeshData {
+    pub vertices: Vec<MapVertex>,
+    pub indices: Vec<u32>,
+    pub material: usize,
+}
+
+/// The meshes of the world or of one brush entity.
+pub struct LevelModelData {
+    pub meshes: Vec<LevelMeshData>,
+    pub materials: Vec<model::MaterialData>,
+    pub planes: Vec<FacePlane>,
+}
+
+/// Where the debug view draws a face's plane, in world space.
+#[derive(Debug, Clone, Copy)]
+pub struct FacePlane {
+    pub center: cgmath::Point3<f32>,
+    pub normal: cgmath::Vector3<f32>,
+    /// Across the face.
+    pub size: f32,
+}
+
+impl FacePlane {
+    fn new(face: &Face) -> Self {
+        use cgmath::{EuclideanSpace, InnerSpace};
+
+        let points = face.vertices.iter().map(|&point| cgmath::Point3::from(map::to_world(point))).collect::<Vec<_>>();
+        let center = cgmath::Point3::centroid(&points);
+        let radius = points.iter().map(|&point| (point - center).magnitude()).fold(0.0, f32::max);
+
+        Self {
+            center,
+            normal: map::to_world_direction(face.plane.normal).into(),
+            size: radius * 2.0,
+        }
+    }
+}
+
+/// A map entity and its model, if it's drawn.
+pub struct EntityData {
+    pub entity: MapEntity,
+    pub model: Option<LevelModelData>,
+}
+
+/// A level as it's stored: a `.map` of brushes, compiled and lit when it's
+/// loaded, or a `.bsp` the map compiler already did that for.
+pub enum LevelSource {
+    Map(Map),
+    Bsp(Box<Bsp>),
+}
+
+impl LevelSource {
+    /// Reads a `.bsp` or, for any other extension, a `.map`.
+    pub fn load(file_name: &str) -> Result<Self, AssetError> {
+        if file_name.to_ascii_lowercase().ends_with(".bsp") {
+            crate::bsp::load(file_name).map(|bsp| Self::Bsp(Box::new(bsp)))
+        } else {
+            map::load(file_name).map(Self::Map)
+        }
+    }
+
+    /// The level's entities. A `.bsp`'s have no brushes.
+    pub fn entities(&self) -> &Map {
+        match self {
+            Self::Map(map) => map,
+            Self::Bsp(bsp) => &bsp.entities,
+        }
+    }
+}
+
+/// CPU side copy of a level, compiled and lit, see [`Level`].
+pub struct LevelData {
+    pub world: LevelModelData,
+    pub entities: Vec<EntityData>,
+    pub lightmap: Atlas,
+    /// Only compiled levels have it.
+    pub visibility: Option<Visibility>,
+}
+
+impl LevelData {
+    pub fn build(source: LevelSource) -> anyhow::Result<Self> {
+        match source {
+            LevelSource::Map(map) => Self::compile(&map),
+            LevelSource::Bsp(bsp) => Self::from_bsp(*bsp),
+        }
+    }
+
+    /// Compiles the brushes of `map`, the worldspawn's and each map
+    /// entity's, and bakes their lightmap, or loads it from the cache when
+    /// the map was baked before. Only the worldspawn casts shadows.
+    /// Textures are looked up as `textures/<name>` in PNG, TGA or JPEG.
+    fn compile(map: &Map) -> anyhow::Result<Self> {
+        let worldspawn = map::worldspawn(map).ok_or_else(|| anyhow::anyhow!("the map has no worldspawn"))?;
+        let brushes = brush::compile(worldspawn);
+
+        let (entities, entity_brushes): (Vec<_>, Vec<_>) = map.0.iter()
+            .filter(|entity| map::property(entity, "classname") != Some("worldspawn"))
+            .filter_map(|entity| {
+                let brushes = brush::compile(entity);
+                Some((MapEntity::new(entity, brush::extent(&brushes))?, brushes))
+            })
+            .unzip();
+
+        // the world's faces first, then each entity's
+        let model_faces = std::iter::once(drawn_faces(&brushes))
+            .chain(entities.iter().zip(&entity_brushes).map(|(entity, brushes)| {
+                if entity.is_drawn() { drawn_faces(brushes) } else { Vec::new() }
+            }))
+            .collect::<Vec<_>>();
+        let faces = model_faces.concat();
+
+        let lights = lightmap::lights(map);
+        let lightmap = lightmap::bake(&map.to_string(), &faces, &brushes, &lights, lightmap::ambient(map))?;
+
+        log::info!(
+            "baked {} lights onto {} faces in a {2}x{2} lightmap",
+            lights.len(),
+            faces.len(),
+            lightmap.atlas.size,
+        );
+
+        let mut images = AHashMap::new();
+        let mut face_lightmaps = lightmap.faces.as_slice();
+        let mut models = model_faces.iter().map(|faces| {
+            let (lightmaps, rest) = face_lightmaps.split_at(faces.len());
+            face_lightmaps = rest;
+            build_model(faces, |i, point| lightmaps[i].uv(point, lightmap.atlas.size), &mut images)
+        });
+
+        let world = models.next().expect("the world is the first model");
+        let entities = entities.into_iter()
+            .zip(models)
+            .map(|(entity, model)| EntityData { model: entity.is_drawn().then_some(model), entity })
+            .collect::<Vec<_>>();
+
+        if !entities.is_empty() {
+            log::info!("compiled {} map entities", entities.len());
+        }
+
+        Ok(Self { world, entities, lightmap: lightmap.atlas, visibility: None })
+    }
+
+    /// Groups the faces of a compiled map into models and packs its
+    /// lightmaps. Textures missing from the map are looked up like a
+    /// `.map`'s.
+    fn from_bsp(bsp: Bsp) -> anyhow::Result<Self> {
+        let Bsp { entities: map, faces, models, textures: mut images, visibility } = bsp;
+
+        let sizes = faces.iter().map(|face| face.lightmap.block_size()).collect::<Vec<_>>();
+        let (mut lightmap, offsets) = Atlas::pack(&sizes)?;
+        for ((face, offset), size) in faces.iter().zip(&offsets).zip(&sizes) {
+            lightmap.blit(*offset, size[0], &face.lightmap.bordered());
+        }
+
+        let mut build = |model: &std::ops::Range<usize>| {
+            let drawn = model.clone().filter(|&i| faces[i].face.is_drawn()).collect::<Vec<_>>();
+            let drawn_faces = drawn.iter().map(|&i| &faces[i].face).collect::<Vec<_>>();
+
+            build_model(
+                &drawn_faces,
+                |i, point| faces[drawn[i]].lightmap.uv(point, offsets[drawn[i]], lightmap.size),
+                &mut images,
+            )
+        };
+
+        let world = build(&models[0]);
+
+        let entities = map.0.iter()
+            .filter(|entity| map::property(entity, "classname") != Some("worldspawn"))
+            .filter_map(|entity| {
+                // brush entities name their model `*n`
+                let model = map::property(entity, "model")
+                    .and_then(|model| model.strip_prefix('*')?.parse::<usize>().ok())
+                    .and_then(|model| models.get(model));
+
+                let extent = model.and_then(|model| extent(faces[model.clone()].iter().map(|face| &face.face)));
+                let entity = MapEntity::new(entity, extent)?;
+                let model = model.filter(|_| entity.is_drawn()).map(&mut build);
+
+                Some(EntityData { entity, model })
+            })
+            .collect::<Vec<_>>();
+
+        log::info!(
+            "loaded {} faces, {} map entities and a {2}x{2} lightmap",
+            faces.len(),
+            entities.len(),
+            lightmap.size,
+        );
+
+        Ok(Self { world, entities, lightmap, visibility })
+    }
+}
+
+/// Everything a level needs before it goes to the GPU, loaded on an asset
+/// worker: the compiled and lit level, the lights the game spawns and the
+/// sky its worldspawn names.
+pub struct LevelAssets {
+    pub data: LevelData,
+    pub lights: Vec<lightmap::Light>,
+    pub sky: Option<CubemapImages>,
+}
+
+impl LevelAssets {
+    /// Reads, compiles and lights `file_name`, see [`LevelSource::load`].
+    /// A sky that doesn't load is left out.
+    pub fn load(file_name: &str) -> anyhow::Result<Self> {
+        let source = LevelSource::load(file_name)?;
+        let map = source.entities();
+
+        let lights = lightmap::lights(map);
+        let sky = map::sky(map).and_then(|name| match CubemapImages::decode(name) {
+            Ok(images) => Some(images),
+            Err(e) => {
+                log::warn!("couldn't load sky {}: {}", name, e);
+                None
+            }
+        });
+
+        Ok(Self { data: LevelData::build(source)?, lights, sky })
+    }
+
+    /// Uploads the level with pipelines for `sample_count`, and the sky.
+    pub fn upload(
+        self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        registry: &mut Registry,
+        textures: &mut assets::Cache<Texture>,
+        sample_count: u32,
+    ) -> Result<LoadedLevel, AssetError> {
+        let sky = self.sky.and_then(|sky| match sky.upload(device, queue, registry) {
+            Ok(cubemap) => Some(cubemap),
+            Err(e) => {
+                log::warn!("couldn't upload the sky: {}", e);
+                None
+            }
+        });
+
+        Ok(LoadedLevel {
+            level: Level::new(device, queue, self.data, registry, textures, sample_count)?,
+            lights: self.lights,
+            sky,
+        })
+    }
+}
+
+/// A level uploaded by [`AssetManager::poll`], waiting for the game to
+/// take it.
+///
+/// [`AssetManager::poll`]: assets::AssetManager::poll
+pub struct LoadedLevel {
+    pub level: Level,
+    pub lights: Vec<lightmap::Light>,
+    /// The skybox cubemap, when the map names one and it loaded.
+    pub sky: Option<Texture>,
+}
+
+/// The box around the vertices of `faces`, in map units.
+fn extent<'a>(faces: impl Iterator<Item = &'a Face>) -> Option<(cgmath::Point3<f64>, cgmath::Point3<f64>)> {
+    let mut points = faces.flat_map(|face| &face.vertices);
+    let &first = points.next()?;
+
+    Some(points.fold((first, first), |(min, max), point| {
+        (
+            cgmath::Point3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z)),
+            cgmath::Point3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z)),
+        )
+    }))
+}
+
+fn drawn_faces(brushes: &[Brush]) -> Vec<&Face> {
+    brushes.iter()
+        .flat_map(|brush| &brush.faces)
+        .filter(|face| face.is_drawn())
+        .collect()
+}
+
+/// Groups `faces` into one mesh per texture, with `lightmap_coords` giving
+/// the lightmap coordinates of a point on the `i`th face. `images` holds
+/// the textures decoded so far, so models sharing one decode it once.
+fn build_model(
+    faces: &[&Face],
+    lightmap_coords: impl Fn(usize, cgmath::Point3<f64>) -> [f32; 2],
+    images: &mut AHashMap<String, image::DynamicImage>,
+) -> LevelModelData {
+    let mut materials = Vec::new();
+    let mut meshes: Vec<LevelMeshData> = Vec::new();
+    let mut by_texture = AHashMap::new();
+
+    for (i, face) in faces.iter().enumerate() {
+        let material = *by_texture.entry(face.texture.as_str()).or_insert_with(|| {
+            let image = images.entry(face.texture.clone()).or_insert_with(|| load_texture(&face.texture));
+            materials.push(model::MaterialData {
+                // Quake's fences and grates, see-through where alpha is 0
+                alpha_mode: if face.texture.starts_with('{') { AlphaMode::Mask } else { AlphaMode::Opaque },
+                // brush texture coordinates run across whole faces
+                address_mode: wgpu::AddressMode::Repeat,
+                ..model::MaterialData::unlit(face.texture.clone(), format!("textures/{}", face.texture), image.clone())
+            });
+            meshes.push(LevelMeshData { vertices: Vec::new(), indices: Vec::new(), material: materials.len() - 1 });
+            materials.len() - 1
+        });
+
+        let texture_size = materials[material].diffuse_image.dimensions();
+        let mesh = &mut meshes[material];
+        append_face(mesh, face, texture_size, |point| lightmap_coords(i, point));
+    }
+
+    let planes = faces.iter().map(|face| FacePlane::new(face)).collect();
+
+    LevelModelData { meshes, materials, planes }
+}
+
+fn load_texture(texture: &str) -> image::DynamicImage {
+    TEXTURE_EXTENSIONS.iter()
+        .map(|extension| resources::decode_image(&format!("textures/{}.{}", texture, extension)))
+        .find(|result| !matches!(result, Err(AssetError::Missing { .. })))
+        .unwrap_or_else(|| Err(AssetError::Missing { path: format!("textures/{}", texture) }))
+        .unwrap_or_else(|e| {
+            log::warn!("{}, using a placeholder", e);
+            Texture::placeholder_image()
+        })
+}
+
+/// Adds `face` as a triangle fan.
+fn append_face(
+    mesh: &mut LevelMeshData,
+    face: &Face,
+    texture_size: (u32, u32),
+    lightmap_coords: impl Fn(cgmath::Point3<f64>) -> [f32; 2],
+) {
+    let base = mesh.vertices.len() as u32;
+    let normal = map::to_world_direction(face.plane.normal);
+
+    mesh.vertices.extend(face.vertices.iter().map(|&point| MapVertex {
+        position: map::to_world(point),
+        tex_coords: face.tex_axes.uv(point, texture_size),
+        normal,
+        lightmap_coords: lightmap_coords(point),
+    }));
+
+    for i in 1..face.vertices.len() as u32 - 1 {
+        mesh.indices.extend_from_slice(&[base, base + i, base + i + 1]);
+    }
+}
+
+/// A model of the level and the transform it's drawn with.
+struct LevelModel {
+    model: model::Model,
+    offset: cgmath::Vector3<f32>,
+    instance_buffer: wgpu::Buffer,
+    /// Around the meshes, before the offset.
+    bounds: model::Bounds,
+    /// Whether the player can see it from where they are, always for
+    /// levels without visibility data.
+    visible: bool,
+    /// Before the offset.
+    planes: Vec<FacePlane>,
+}
+
+impl LevelModel {
+    fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &LevelModelData,
+        registry: &Registry,
+        textures: &mut assets::Cache<Texture>,
+    ) -> Result<Self, AssetError> {
+        use cgmath::Zero;
+
+        let materials = data.materials
+            .iter()
+            .map(|m| resources::upload_material(m, device, queue, registry, textures))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let meshes = data.meshes
+            .iter()
+            .map(|m| model::Mesh {
+                name: materials[m.material].name.clone(),
+                vertex_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Level Vertex Buffer"),
+                    contents: bytemuck::cast_slice(&m.vertices),
+                    usage: wgpu::BufferUsages::VERTEX,
+                }),
+                index_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Level Index Buffer"),
+                    contents: bytemuck::cast_slice(&m.indices),
+                    usage: wgpu::BufferUsages::INDEX,
+                }),
+                num_elements: m.indices.len() as u32,
+                material: m.material,
+                bounds: model::Bounds::from_points(m.vertices.iter().map(|v| v.position)),
+            })
+            .collect();
+
+        let offset = cgmath::Vector3::zero();
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Level Instance Buffer"),
+            contents: bytemuck::cast_slice(&[Transform::at(offset).to_raw()]),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bounds = model::Bounds::from_points(data.meshes.iter().flat_map(|m| &m.vertices).map(|v| v.position));
+
+        Ok(Self {
+            model: model::Model { meshes, materials },
+            offset,
+            instance_buffer,
+            bounds,
+            visible: true,
+            planes: data.planes.clone(),
+        })
+    }
+
+    fn set_offset(&mut self, queue: &wgpu::Queue, offset: cgmath::Vector3<f32>) {
+        if offset != self.offset {
+            self.offset = offset;
+            queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&[Transform::at(offset).to_raw()]));
+        }
+    }
+
+    /// Where it is now, in world space.
+    fn bounds(&self) -> model::Bounds {
+        model::Bounds {
+            min: self.bounds.min + self.offset,
+            max: self.bounds.max + self.offset,
+        }
+    }
+
+    /// Draws the meshes whose material is `alpha_mode`.
+    fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        alpha_mode: AlphaMode,
+    ) {
+        if !self.visible {
+            return;
+        }
+
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+
+        for mesh in &self.model.meshes {
+            let material = &self.model.materials[mesh.material];
+            if material.alpha_mode == alpha_mode {
+                render_pass.draw_mesh(mesh, material, camera_bind_group);
+            }
+        }
+    }
+}
+
+/// The brush geometry of a map, drawn with its diffuse textures modulated
+/// by the baked lightmap. Brush entities are models of their own, moved by
+/// [`update`].
+///
+/// [`update`]: Level::update
+pub struct Level {
+    world: LevelModel,
+    entities: Vec<MapEntity>,
+    /// The models of `entities`, for the ones that are drawn.
+    entity_models: Vec<Option<LevelModel>>,
+    dispatcher: Dispatcher,
+    /// Culls the entity models the player can't see. The world is drawn
+    /// whole.
+    visibility: Option<Visibility>,
+    light_buffer: wgpu::Buffer,
+    lightmap_bind_group: wgpu::BindGroup,
+    pipeline: Rc<wgpu::RenderPipeline>,
+    /// For the alpha tested `{` textures.
+    masked_pipeline: Rc<wgpu::RenderPipeline>,
+}
+
+impl Level {
+    /// Uploads `data`. The pipeline takes the material and camera bind
+    /// groups the models use, and the lightmap and lights as group 2.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: LevelData,
+        registry: &mut Registry,
+        textures: &mut assets::Cache<Texture>,
+        sample_count: u32,
+    ) -> Result<Self, AssetError> {
+        let world = LevelModel::new(device, queue, &data.world, registry, textures)?;
+
+        let mut entities = Vec::with_capacity(data.entities.len());
+        let mut entity_models = Vec::with_capacity(data.entities.len());
+
+        for EntityData { entity, model } in data.entities {
+            entities.push(entity);
+            entity_models.push(model.map(|model| LevelModel::new(device, queue, &model, registry, textures)).transpose()?);
+        }
+
+        let dispatcher = Dispatcher::new(&entities);
+
+        // the shader scales the lightmap itself, so it's stored linear
+        let lightmap_image = image::RgbaImage::from_raw(data.lightmap.size, data.lightmap.size, data.lightmap.pixels.clone())
+            .expect("lightmap pixels match its size");
+        let lightmap = Texture::lightmap(device, queue, &lightmap_image)?;
+
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Level Light Buffer"),
+            contents: bytemuck::bytes_of(&<LightsUniform as bytemuck::Zeroable>::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let lightmap_layout = registry.bind_group_layout(device, LIGHTMAP_LAYOUT, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]);
+
+        let lightmap_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &lightmap_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&lightmap.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&lightmap.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: light_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("lightmap_bind_group"),
+        });
+
+        let pipeline = registry.render_pipeline(device, &Self::pipeline_desc(sample_count, AlphaMode::Opaque));
+        let masked_pipeline = registry.render_pipeline(device, &Self::pipeline_desc(sample_count, AlphaMode::Mask));
+
+        Ok(Self {
+            world,
+            entities,
+            entity_models,
+            dispatcher,
+            visibility: data.visibility,
+            light_buffer,
+            lightmap_bind_group,
+            pipeline,
+            masked_pipeline,
+        })
+    }
+
+    /// Switches to the pipeline for targets with a different MSAA sample
+    /// count.
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, registry: &mut Registry, sample_count: u32) {
+        self.pipeline = registry.render_pipeline(device, &Self::pipeline_desc(sample_count, AlphaMode::Opaque));
+        self.masked_pipeline = registry.render_pipeline(device, &Self::pipeline_desc(sample_count, AlphaMode::Mask));
+    }
+
+    /// The pipeline for materials that are `alpha_mode`, either opaque or
+    /// masked; brush textures aren't blended.
+    fn pipeline_desc(sample_count: u32, alpha_mode: AlphaMode) -> RenderPipelineDesc {
+        let masked = alpha_mode == AlphaMode::Mask;
+
+        RenderPipelineDesc {
+            label: if masked { "Level Masked Pipeline" } else { "Level Pipeline" },
+            shader: "level.wgsl",
+            defines: if masked { shaders::Defines::new().with("ALPHA_MASK") } else { shaders::Defines::new() },
+            bind_group_layouts: vec![registry::MATERIAL, registry::CAMERA, LIGHTMAP_LAYOUT],
+            vertex_entry: "vs_main",
+            vertex_buffers: vec![MapVertex::desc(), InstanceRaw::desc()],
+            fragment_entry: "fs_main",
+            targets: vec![Some(wgpu::ColorTargetState {
+                format: HDR_FORMAT,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            primitive: wgpu::PrimitiveState {
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Moves the map entities on by `dt` seconds, with the player at
+    /// `player` in world space, and passes on the uses of the ones that
+    /// fired.
+    pub fn update(&mut self, queue: &wgpu::Queue, dt: f32, player: cgmath::Point3<f32>) -> Vec<Event> {
+        let fired = self.entities.iter_mut()
+            .enumerate()
+            .filter_map(|(i, entity)| entity.update(dt, player).then_some(i))
+            .collect::<Vec<_>>();
+
+        let events = self.dispatcher.dispatch(&mut self.entities, dt, fired);
+        let visible = self.visibility.as_ref().and_then(|visibility| visibility.visible_from(player));
+
+        for (entity, model) in self.entities.iter().zip(&mut self.entity_models) {
+            if let Some(model) = model {
+                model.set_offset(queue, entity.offset());
+                model.visible = match (&self.visibility, &visible) {
+                    (Some(visibility), Some(leaves)) => visibility.touches(&model.bounds(), leaves),
+                    _ => true,
+                };
+            }
+        }
+
+        events
+    }
+
+    /// Uploads the `lights` at their positions that aren't baked into the
+    /// lightmap, keeping the nearest to `eye`.
+    pub fn set_lights<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        lights: impl Iterator<Item = (cgmath::Vector3<f32>, &'a Light)>,
+        eye: cgmath::Point3<f32>,
+    ) {
+        use cgmath::{EuclideanSpace, InnerSpace};
+
+        let mut lights = lights.filter(|(_, light)| !light.baked).collect::<Vec<_>>();
+        let distance = |position: cgmath::Vector3<f32>| (position - eye.to_vec()).magnitude2();
+        lights.sort_by(|(a, _), (b, _)| distance(*a).total_cmp(&distance(*b)));
+        lights.truncate(MAX_LIGHTS);
+
+        let mut uniform: LightsUniform = bytemuck::Zeroable::zeroed();
+        uniform.count = lights.len() as u32;
+        for (raw, (position, light)) in uniform.lights.iter_mut().zip(lights) {
+            *raw = LightRaw {
+                position: position.into(),
+                radius: light.radius,
+                color: light.color.map(|c| c * light.intensity),
+                _padding: 0,
+            };
+        }
+
+        queue.write_buffer(&self.light_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    /// The planes of the faces of the world and the drawn map entities,
+    /// where they are now, for debug drawing.
+    pub fn planes(&self) -> impl Iterator<Item = FacePlane> + '_ {
+        std::iter::once(&self.world)
+            .chain(self.entity_models.iter().flatten())
+            .flat_map(|model| model.planes.iter().map(|plane| FacePlane { center: plane.center + model.offset, ..*plane }))
+    }
+
+    /// The volumes that set off the map entities, for debug drawing.
+    pub fn fields(&self) -> impl Iterator<Item = &model::Bounds> {
+        self.entities.iter().filter_map(MapEntity::field)
+    }
+
+    /// Draws the level. It has its own pipeline layout, so the scene's
+    /// bind groups need setting again afterwards.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        render_pass.set_bind_group(2, &self.lightmap_bind_group, &[]);
+
+        for (pipeline, alpha_mode) in [(&self.pipeline, AlphaMode::Opaque), (&self.masked_pipeline, AlphaMode::Mask)] {
+            render_pass.set_pipeline(pipeline);
+
+            self.world.draw(render_pass, camera_bind_group, alpha_mode);
+            for model in self.entity_models.iter().flatten() {
+                model.draw(render_pass, camera_bind_group, alpha_mode);
+            }
+        }
+    }
+}
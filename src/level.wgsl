@@ -0,0 +1,113 @@
+// Map brush geometry, the diffuse texture lit by the baked lightmap and the
+// animated lights left out of it
+
+#include "instance.wgsl"
+#include "camera.wgsl"
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+@group(2) @binding(0)
+var t_lightmap: texture_2d<f32>;
+@group(2) @binding(1)
+var s_lightmap: sampler;
+
+// See LightRaw in level.rs, the color is premultiplied by the intensity
+struct PointLight {
+    position: vec3<f32>,
+    radius: f32,
+    color: vec3<f32>,
+};
+
+struct LightsUniform {
+    count: u32,
+    // MAX_LIGHTS in level.rs
+    lights: array<PointLight, 32>,
+};
+
+@group(2) @binding(2)
+var<uniform> lights: LightsUniform;
+
+// A lightmap texel of 128 is full brightness, brighter ones overbright
+let OVERBRIGHT: f32 = 2.0;
+
+// Like shader.wgsl's, for the `{` textures
+let ALPHA_CUTOFF: f32 = 0.5;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) lightmap_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) lightmap_coords: vec2<f32>,
+    @location(2) world_position: vec3<f32>,
+    @location(3) normal: vec3<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    // brush entities are moved by their transform, the world's is identity
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.tex_coords = model.tex_coords;
+    out.lightmap_coords = model.lightmap_coords;
+    out.world_position = world_position.xyz;
+    // the transform only moves the model, so normals stay as they are
+    out.normal = model.normal;
+    return out;
+}
+
+// Quake's falloff, linear to zero at the radius, with surfaces at a grazing
+// angle still getting half. There are no shadows.
+fn point_lights(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var total = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i = i + 1u) {
+        let light = lights.lights[i];
+        let to_light = light.position - position;
+        let distance = length(to_light);
+        let facing = dot(normal, to_light) / max(distance, 0.0001);
+
+        let falloff = 1.0 - distance / light.radius;
+        if (facing > 0.0 && falloff > 0.0) {
+            total = total + light.color * falloff * (0.5 + 0.5 * facing);
+        }
+    }
+    return total;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let diffuse = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    // sampled before the discard, which would leave the derivatives behind
+    // it undefined
+    let baked = textureSample(t_lightmap, s_lightmap, in.lightmap_coords).rgb * OVERBRIGHT;
+
+#ifdef ALPHA_MASK
+    if diffuse.a < ALPHA_CUTOFF {
+        discard;
+    }
+#endif
+
+    let light = baked + point_lights(in.world_position, normalize(in.normal));
+
+    return vec4<f32>(diffuse.rgb * light, 1.0);
+}
@@ -0,0 +1,521 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use shalrath::repr::{Entity, Map};
+
+use crate::brush::{Brush, Face};
+use crate::{lightstyle, map};
+
+/// Map units covered by one lightmap texel, and in a compiled map the
+/// texels of a face's texture covered by one sample.
+pub const LUXEL_SIZE: f64 = 16.0;
+
+const MIN_ATLAS_SIZE: u32 = 256;
+pub const MAX_ATLAS_SIZE: u32 = 4096;
+
+/// How far off its face a luxel is lit from, so the face doesn't shadow
+/// itself.
+const SAMPLE_OFFSET: f64 = 1.0;
+
+/// Lightmap byte that the shader draws at full brightness, leaving room to
+/// overbright up to twice that.
+pub const FULLBRIGHT: u8 = 128;
+
+/// Where [`bake`] keeps lightmaps, under the platform's cache directory.
+const CACHE_DIR: &str = "learn-wgpu/lightmaps";
+const CACHE_MAGIC: &[u8; 4] = b"LMAP";
+/// Bumped whenever the bake changes, so lightmaps cached by older builds
+/// are baked again.
+const CACHE_VERSION: u32 = 3;
+
+/// A point light from a `light` entity, in map units.
+#[derive(Debug, Clone)]
+pub struct Light {
+    pub origin: Point3<f64>,
+    /// Brightness at the light, falling off linearly to zero.
+    pub intensity: f64,
+    pub color: [f64; 3],
+    /// Scales the falloff, higher values light a smaller radius.
+    pub wait: f64,
+    /// Index into [`lightstyle::PATTERNS`] for animated lights, or from
+    /// [`lightstyle::SWITCHABLE`] up for ones gameplay switches. Either are
+    /// drawn live instead of baked.
+    pub style: Option<usize>,
+    /// A switchable light with spawnflag 1, off until it's used.
+    pub starts_off: bool,
+}
+
+impl Light {
+    /// The light of an entity whose class starts with `light`, which also
+    /// covers the torches and flames, with Quake's defaults for missing
+    /// keys.
+    pub fn from_entity(entity: &Entity) -> Option<Self> {
+        if !map::property(entity, "classname")?.starts_with("light") {
+            return None;
+        }
+
+        let origin = Point3::from_vec(map::vector(entity, "origin")?);
+
+        // `_color` is 0 to 1 in most editors, but some write 0 to 255
+        let color = map::vector(entity, "_color")
+            .map(|color| if color.x > 1.0 || color.y > 1.0 || color.z > 1.0 { color / 255.0 } else { color })
+            .map_or([1.0; 3], Into::into);
+
+        let style = map::number(entity, "style").map(|style| style as usize).filter(|&style| style != 0);
+        if let Some(style) = style.filter(|&style| !lightstyle::is_live(style)) {
+            log::warn!("light at {:?} has style {}, which isn't animated", origin, style);
+        }
+        let style = style.filter(|&style| lightstyle::is_live(style));
+
+        Some(Self {
+            origin,
+            intensity: map::number(entity, "light").unwrap_or(300.0),
+            color,
+            wait: map::number(entity, "wait").filter(|&wait| wait > 0.0).unwrap_or(1.0),
+            style,
+            starts_off: style.is_some_and(|style| style >= lightstyle::SWITCHABLE)
+                && map::number(entity, "spawnflags").is_some_and(|flags| flags as u32 & 1 != 0),
+        })
+    }
+
+    /// Distance at which the light has fallen off to nothing.
+    fn reach(&self) -> f64 {
+        self.intensity / self.wait
+    }
+
+    /// Light reaching `point` on a surface facing `normal`, ignoring
+    /// shadows.
+    fn reaching(&self, point: Point3<f64>, normal: Vector3<f64>) -> Option<[f64; 3]> {
+        let to_light = self.origin - point;
+        let distance = to_light.magnitude();
+        let facing = normal.dot(to_light) / distance;
+
+        let value = self.intensity - distance * self.wait;
+        if facing <= 0.0 || value <= 0.0 {
+            return None;
+        }
+
+        // surfaces at a grazing angle still get half, like Quake's light
+        let value = value * (0.5 + 0.5 * facing);
+        Some(self.color.map(|c| c * value))
+    }
+}
+
+/// The lights of every `light` entity in `map`.
+pub fn lights(map: &Map) -> Vec<Light> {
+    map.0.iter().filter_map(Light::from_entity).collect()
+}
+
+/// Light everywhere gets at least, from the worldspawn's `light` or
+/// `_minlight`.
+pub fn ambient(map: &Map) -> f64 {
+    map::worldspawn(map)
+        .and_then(|worldspawn| map::number(worldspawn, "light").or_else(|| map::number(worldspawn, "_minlight")))
+        .unwrap_or(0.0)
+}
+
+/// Where a face's block of luxels sits in the atlas. Faces are projected
+/// onto the axis plane they face the most, one texel per `LUXEL_SIZE`
+/// units, with a texel of border around so filtering doesn't bleed in
+/// neighbours.
+#[derive(Debug, Clone, Copy)]
+pub struct FaceLightmap {
+    axes: [usize; 2],
+    /// The first luxel, in luxels along `axes`.
+    min: [i64; 2],
+    /// Luxels along `axes`, not counting the border.
+    size: [u32; 2],
+    /// Top left of the block in the atlas.
+    offset: [u32; 2],
+}
+
+impl FaceLightmap {
+    fn new(face: &Face) -> Self {
+        let normal = face.plane.normal;
+        let axes = match (0..3).max_by(|&a, &b| normal[a].abs().total_cmp(&normal[b].abs())).unwrap() {
+            0 => [1, 2],
+            1 => [0, 2],
+            _ => [0, 1],
+        };
+
+        let range = |axis: usize| {
+            let values = face.vertices.iter().map(|vertex| vertex[axis] / LUXEL_SIZE);
+            let min = values.clone().fold(f64::INFINITY, f64::min).floor() as i64;
+            let max = values.fold(f64::NEG_INFINITY, f64::max).ceil() as i64;
+            (min, (max - min + 1) as u32)
+        };
+
+        let (s, t) = (range(axes[0]), range(axes[1]));
+
+        Self {
+            axes,
+            min: [s.0, t.0],
+            size: [s.1, t.1],
+            offset: [0, 0],
+        }
+    }
+
+    /// Size of the block in the atlas, with the border.
+    fn block_size(&self) -> [u32; 2] {
+        self.size.map(|size| size + 2)
+    }
+
+    /// Lightmap coordinates of `point` on the face.
+    pub fn uv(&self, point: Point3<f64>, atlas_size: u32) -> [f32; 2] {
+        [0, 1].map(|i| {
+            // luxel centers sit half a texel in, past the border
+            let texel = point[self.axes[i]] / LUXEL_SIZE - self.min[i] as f64 + 1.5 + self.offset[i] as f64;
+            (texel / atlas_size as f64) as f32
+        })
+    }
+
+    /// The point on `face` luxel `(x, y)` of the block is lit from, border
+    /// texels repeating the luxel next to them.
+    fn sample_point(&self, face: &Face, x: u32, y: u32) -> Point3<f64> {
+        let [s, t] = [(x, 0), (y, 1)].map(|(texel, i)| {
+            let luxel = texel.saturating_sub(1).min(self.size[i] - 1);
+            (self.min[i] + luxel as i64) as f64 * LUXEL_SIZE
+        });
+
+        let normal = face.plane.normal;
+        let axis = 3 - self.axes[0] - self.axes[1];
+
+        let mut point = Point3::new(0.0, 0.0, 0.0);
+        point[self.axes[0]] = s;
+        point[self.axes[1]] = t;
+        point[axis] = (face.plane.dist - normal[self.axes[0]] * s - normal[self.axes[1]] * t) / normal[axis];
+
+        point + normal * SAMPLE_OFFSET
+    }
+}
+
+/// Lightmap blocks packed into one square RGBA texture.
+pub struct Atlas {
+    pub size: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Atlas {
+    /// An empty atlas big enough for blocks of `sizes`, and the top left
+    /// corner of each block in it. Blocks go in rows from the tallest down,
+    /// doubling the atlas until they fit.
+    pub fn pack(sizes: &[[u32; 2]]) -> anyhow::Result<(Self, Vec<[u32; 2]>)> {
+        let mut order = (0..sizes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse(sizes[i][1]));
+
+        let mut size = MIN_ATLAS_SIZE;
+        let mut offsets = vec![[0, 0]; sizes.len()];
+
+        'grow: loop {
+            let (mut x, mut y, mut row_height) = (0, 0, 0);
+
+            for &i in &order {
+                let [width, height] = sizes[i];
+
+                if x + width > size {
+                    x = 0;
+                    y += row_height;
+                    row_height = 0;
+                }
+
+                if x + width > size || y + height > size {
+                    if size == MAX_ATLAS_SIZE {
+                        anyhow::bail!("lightmaps don't fit a {0}x{0} atlas", MAX_ATLAS_SIZE);
+                    }
+
+                    size *= 2;
+                    continue 'grow;
+                }
+
+                offsets[i] = [x, y];
+                x += width;
+                row_height = row_height.max(height);
+            }
+
+            let pixels = vec![0; (size * size * 4) as usize];
+            return Ok((Self { size, pixels }, offsets));
+        }
+    }
+
+    /// Copies a block `width` texels wide to `offset`.
+    pub fn blit(&mut self, offset: [u32; 2], width: u32, texels: &[[u8; 4]]) {
+        let width = width as usize;
+
+        for (y, row) in texels.chunks(width).enumerate() {
+            let start = (((offset[1] as usize + y) * self.size as usize) + offset[0] as usize) * 4;
+            self.pixels[start..start + width * 4].copy_from_slice(bytemuck::cast_slice(row));
+        }
+    }
+}
+
+/// Baked light of a set of faces.
+pub struct Lightmap {
+    pub atlas: Atlas,
+    /// Parallel to the baked faces.
+    pub faces: Vec<FaceLightmap>,
+}
+
+/// Ray traces the direct light of `lights` onto `faces`, shadowed by the
+/// solid `occluders`. Animated lights are left out for the renderer to
+/// add. Without any lights the faces are fullbright, so maps that were
+/// never lit still show.
+///
+/// The atlas is kept in [`CACHE_DIR`] under a hash of `source`, the text of
+/// the map everything else comes from, and loaded from there instead while
+/// the map stays the same. Without a cache directory every load bakes.
+pub fn bake(
+    source: &str,
+    faces: &[&Face],
+    occluders: &[Brush],
+    lights: &[Light],
+    ambient: f64,
+) -> anyhow::Result<Lightmap> {
+    let key = CacheKey::new(source);
+    let path = dirs::cache_dir()
+        .map(|dir| dir.join(CACHE_DIR).join(format!("{:016x}.lightmap", key.hash)));
+
+    let (atlas, blocks) = layout(faces)?;
+
+    if let Some(path) = &path {
+        match read_cache(path, &key, atlas.size) {
+            Ok(pixels) => {
+                log::info!("loaded the lightmap from {}", path.display());
+                return Ok(Lightmap { atlas: Atlas { pixels, ..atlas }, faces: blocks });
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("couldn't read {}, baking again: {}", path.display(), e),
+        }
+    }
+
+    let lightmap = trace(atlas, blocks, faces, occluders, lights, ambient);
+
+    if let Some(path) = &path {
+        match write_cache(path, &key, &lightmap.atlas) {
+            Ok(()) => log::info!("cached the lightmap in {}", path.display()),
+            Err(e) => log::warn!("couldn't cache the lightmap in {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(lightmap)
+}
+
+/// Identifies the map a cached lightmap was baked from. The hash is FNV-1a,
+/// which unlike the standard library's hashers stays the same across Rust
+/// releases, and the length makes a collision even less likely to go
+/// unnoticed.
+#[derive(Debug, PartialEq, Eq)]
+struct CacheKey {
+    hash: u64,
+    length: u64,
+}
+
+impl CacheKey {
+    const SIZE: usize = 16;
+
+    fn new(source: &str) -> Self {
+        let hash = source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+
+        Self { hash, length: source.len() as u64 }
+    }
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.hash.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+}
+
+/// The atlas pixels cached at `path`, if they were baked by this version
+/// from the map `key` identifies, for an atlas of `size`.
+fn read_cache(path: &Path, key: &CacheKey, size: u32) -> io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(path)?;
+
+    let mut header = [0; 12 + CacheKey::SIZE];
+    file.read_exact(&mut header)?;
+
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let cached_size = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let stale = &header[..4] != CACHE_MAGIC
+        || version != CACHE_VERSION
+        || cached_size != size
+        || header[12..] != key.to_bytes();
+    if stale {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "stale cache"));
+    }
+
+    let mut pixels = vec![0; (size * size * 4) as usize];
+    file.read_exact(&mut pixels)?;
+
+    Ok(pixels)
+}
+
+fn write_cache(path: &Path, key: &CacheKey, atlas: &Atlas) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(CACHE_MAGIC)?;
+    file.write_all(&CACHE_VERSION.to_le_bytes())?;
+    file.write_all(&atlas.size.to_le_bytes())?;
+    file.write_all(&key.to_bytes())?;
+    file.write_all(&atlas.pixels)?;
+
+    file.flush()
+}
+
+/// The blocks of `faces`, packed into an empty atlas.
+fn layout(faces: &[&Face]) -> anyhow::Result<(Atlas, Vec<FaceLightmap>)> {
+    let mut blocks = faces.iter().map(|face| FaceLightmap::new(face)).collect::<Vec<_>>();
+    let (atlas, offsets) = Atlas::pack(&blocks.iter().map(FaceLightmap::block_size).collect::<Vec<_>>())?;
+    for (block, offset) in blocks.iter_mut().zip(offsets) {
+        block.offset = offset;
+    }
+
+    Ok((atlas, blocks))
+}
+
+/// Fills the `blocks` of `faces` in `atlas`, see [`bake`].
+fn trace(
+    mut atlas: Atlas,
+    blocks: Vec<FaceLightmap>,
+    faces: &[&Face],
+    occluders: &[Brush],
+    lights: &[Light],
+    ambient: f64,
+) -> Lightmap {
+    let occluders = occluders.iter().filter(|brush| brush.is_solid()).collect::<Vec<_>>();
+    let baked_lights = lights.iter().filter(|light| light.style.is_none()).collect::<Vec<_>>();
+
+    let bake_face = |face: &Face, block: &FaceLightmap| -> Vec<[u8; 4]> {
+        let [width, height] = block.block_size();
+
+        if lights.is_empty() {
+            return vec![[FULLBRIGHT, FULLBRIGHT, FULLBRIGHT, 255]; (width * height) as usize];
+        }
+
+        // the samples lie on a plane, so the corners bound them all
+        let corners = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)];
+        let face_box = bounds(corners.into_iter().map(|(x, y)| block.sample_point(face, x, y)));
+
+        // only brushes in the box around the face and the light can be in
+        // the way
+        let sources = baked_lights.iter()
+            .filter(|source| distance_to_box(source.origin, face_box) < source.reach())
+            .map(|source| {
+                let light_box = bounds([face_box.0, face_box.1, source.origin].into_iter());
+                let shadowing = occluders.iter()
+                    .filter(|brush| overlaps((brush.min, brush.max), light_box))
+                    .collect::<Vec<_>>();
+                (source, shadowing)
+            })
+            .collect::<Vec<_>>();
+
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let point = block.sample_point(face, x, y);
+                let mut light = [ambient; 3];
+
+                for (source, shadowing) in &sources {
+                    let Some(color) = source.reaching(point, face.plane.normal) else {
+                        continue;
+                    };
+
+                    if shadowing.iter().any(|brush| brush.intersects_segment(point, source.origin, 0.125)) {
+                        continue;
+                    }
+
+                    for (light, color) in light.iter_mut().zip(color) {
+                        *light += color;
+                    }
+                }
+
+                let [r, g, b] = light.map(|value| value.clamp(0.0, 255.0) as u8);
+                [r, g, b, 255]
+            })
+            .collect()
+    };
+
+    // faces are independent, so split them between threads
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = faces.len().div_ceil(threads).max(1);
+
+    let baked = std::thread::scope(|scope| {
+        let workers = faces.chunks(chunk_size)
+            .zip(blocks.chunks(chunk_size))
+            .map(|(faces, blocks)| scope.spawn(move || {
+                faces.iter().zip(blocks).map(|(face, block)| bake_face(face, block)).collect::<Vec<_>>()
+            }))
+            .collect::<Vec<_>>();
+
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    for (block, texels) in blocks.iter().zip(baked) {
+        atlas.blit(block.offset, block.block_size()[0], &texels);
+    }
+
+    Lightmap { atlas, faces: blocks }
+}
+
+/// The box around `points`, as its min and max corners.
+fn bounds(points: impl Iterator<Item = Point3<f64>>) -> (Point3<f64>, Point3<f64>) {
+    let min = Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+    let max = Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+
+    points.fold((min, max), |(min, max), point| {
+        (
+            Point3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z)),
+            Point3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z)),
+        )
+    })
+}
+
+fn overlaps(a: (Point3<f64>, Point3<f64>), b: (Point3<f64>, Point3<f64>)) -> bool {
+    (0..3).all(|axis| a.0[axis] <= b.1[axis] && b.0[axis] <= a.1[axis])
+}
+
+fn distance_to_box(point: Point3<f64>, (min, max): (Point3<f64>, Point3<f64>)) -> f64 {
+    let outside = Vector3::new(
+        (min.x - point.x).max(point.x - max.x).max(0.0),
+        (min.y - point.y).max(point.y - max.y).max(0.0),
+        (min.z - point.z).max(point.z - max.z).max(0.0),
+    );
+    outside.magnitude()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_key_is_stable() {
+        // the published FNV-1a test vectors
+        assert_eq!(CacheKey::new("").hash, 0xcbf2_9ce4_8422_2325);
+        assert_eq!(CacheKey::new("a").hash, 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(CacheKey::new("foobar").hash, 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn cache_rejects_other_maps() {
+        let path = std::env::temp_dir().join(format!("cache-{}.lightmap", std::process::id()));
+        let atlas = Atlas { size: 2, pixels: (0..16).collect() };
+        write_cache(&path, &CacheKey::new("map a"), &atlas).unwrap();
+
+        let read = |source: &str, size: u32| read_cache(&path, &CacheKey::new(source), size);
+        let hit = read("map a", 2);
+        let other_map = read("map b", 2);
+        let other_size = read("map a", 4);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(hit.unwrap(), atlas.pixels);
+        assert_eq!(other_map.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(other_size.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{
+    ecs::{Entity, World},
+    game::{Light, Script},
+    input::Input,
+};
+
+/// Steps of a pattern per second.
+const FRAME_RATE: f32 = 10.0;
+
+/// Quake's light styles from `world.qc`, picked by a light's `style` key.
+/// Each letter is a level from `a` (dark) to `z` (double bright), `m`
+/// being normal.
+pub const PATTERNS: [&str; 12] = [
+    // 0 normal
+    "m",
+    // 1 flicker
+    "mmnmmommommnonmmonqnmmo",
+    // 2 slow strong pulse
+    "abcdefghijklmnopqrstuvwxyzyxwvutsrqponmlkjihgfedcba",
+    // 3 candle
+    "mmmmmaaaaammmmmaaaaaabcdefgabcdefg",
+    // 4 fast strobe
+    "mamamamamama",
+    // 5 gentle pulse
+    "jklmnopqrstuvwxyzyxwvutsrqponmlkj",
+    // 6 flicker
+    "nmonqnmomnmomomno",
+    // 7 candle
+    "mmmaaaabcdefgmmmmaaaammmaamm",
+    // 8 candle
+    "mmmaaammmaaammmabcdefaaaammmmabcdefmmmaaaa",
+    // 9 slow strobe
+    "aaaaaaaazzzzzzzz",
+    // 10 fluorescent flicker
+    "mmamammmmammamamaaamammma",
+    // 11 slow pulse, not fading to black
+    "abcdefghijklmnopqrrqponmlkjihgfedcba",
+];
+
+/// The first of the styles gameplay switches on and off, instead of
+/// animating them through a pattern.
+pub const SWITCHABLE: usize = 32;
+
+/// Whether lights of `style` change while the level runs, so they're
+/// drawn live instead of baked.
+pub fn is_live(style: usize) -> bool {
+    (1..PATTERNS.len()).contains(&style) || style >= SWITCHABLE
+}
+
+/// Animates the entity's [`Light`] through a light style, stepping like
+/// Quake instead of blending between levels.
+pub struct LightStyle {
+    levels: Vec<f32>,
+    /// [`Light::intensity`] at the normal level.
+    intensity: f32,
+    time: f32,
+}
+
+impl LightStyle {
+    /// Plays `pattern` around `intensity`. Characters outside `a` to `z`
+    /// are dark.
+    pub fn new(pattern: &str, intensity: f32) -> Self {
+        let levels = pattern.bytes()
+            .map(|c| if c.is_ascii_lowercase() { (c - b'a') as f32 / 12.0 } else { 0.0 })
+            .collect();
+
+        Self { levels, intensity, time: 0.0 }
+    }
+
+    /// Level of the pattern now, 1 being normal.
+    pub fn level(&self) -> f32 {
+        if self.levels.is_empty() {
+            return 1.0;
+        }
+
+        let frame = (self.time * FRAME_RATE) as usize % self.levels.len();
+        self.levels[frame]
+    }
+}
+
+impl Script for LightStyle {
+    fn update(&mut self, entity: Entity, world: &mut World, _input: &Input, dt: f32) {
+        self.time += dt;
+
+        if let Some(light) = world.get_mut::<Light>(entity) {
+            light.intensity = self.intensity * self.level();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_at(pattern: &str, time: f32) -> f32 {
+        LightStyle { time, ..LightStyle::new(pattern, 1.0) }.level()
+    }
+
+    #[test]
+    fn letters_are_levels() {
+        assert_eq!(level_at("a", 0.0), 0.0);
+        assert_eq!(level_at("m", 0.0), 1.0);
+        assert_eq!(level_at("z", 0.0), 25.0 / 12.0);
+        assert_eq!(level_at("?", 0.0), 0.0);
+        assert_eq!(level_at("", 0.0), 1.0);
+    }
+
+    #[test]
+    fn steps_ten_times_a_second() {
+        // 4, fast strobe
+        let strobe = PATTERNS[4];
+        assert_eq!(level_at(strobe, 0.0), 1.0);
+        assert_eq!(level_at(strobe, 0.05), 1.0);
+        assert_eq!(level_at(strobe, 0.15), 0.0);
+
+        // 2, slow strong pulse, wraps after its 51 steps
+        assert_eq!(level_at(PATTERNS[2], 1.25), 1.0);
+        assert_eq!(level_at(PATTERNS[2], 5.15), 0.0);
+        assert_eq!(level_at(PATTERNS[2], 5.25), 1.0 / 12.0);
+    }
+
+    #[test]
+    fn live_styles() {
+        assert!(!is_live(0));
+        assert!(is_live(1));
+        assert!(is_live(11));
+        assert!(!is_live(12));
+        assert!(!is_live(31));
+        assert!(is_live(SWITCHABLE));
+    }
+}
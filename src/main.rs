@@ -4,6 +4,30 @@ mod camera;
 mod instance;
 mod model;
 mod resources;
+mod config;
+mod adapter;
+mod debug;
+mod ui;
+mod profiler;
+mod graph;
+mod post;
+mod error;
+mod assets;
+mod map;
+mod sky;
+mod ibl;
+mod brush;
+mod lightmap;
+mod level;
+mod shaders;
+mod registry;
+mod entities;
+mod events;
+mod ecs;
+mod game;
+mod bsp;
+mod lightstyle;
+mod input;
 
 use winit::{
     event::*,
@@ -11,14 +35,35 @@ use winit::{
     window::WindowBuilder,
 };
 
-use shalrath::repr::*;
+const TITLE: &str = "the game";
 
 async fn run() {
     env_logger::init();
+
+    let config = match config::Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{:#}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = resources::init(&config.res_paths) {
+        log::error!("{:#}", e);
+        return;
+    }
+
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
+    let window = WindowBuilder::new().with_title(TITLE).build(&event_loop).unwrap();
 
-    let mut state = state::State::new(&window).await;
+    let mut state = match state::State::new(&window, &event_loop, &config).await {
+        Ok(state) => state,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    let mut loading = true;
 
     event_loop.run(move |event, _, control_flow| {
 
@@ -26,42 +71,50 @@ async fn run() {
             
             Event::WindowEvent { ref event, window_id, }
 
-            if window_id == window.id() => {
-
-                if !state.input(event) {
+            if window_id == window.id() && !state.input(event) => {
 
-                    match event {
-                    
-                        WindowEvent::CloseRequested | WindowEvent::KeyboardInput {
-                            input:
-                                KeyboardInput {
-                                    state: ElementState::Pressed,
-                                    virtual_keycode: Some(VirtualKeyCode::Escape),
-                                    ..
-                                },
-                            ..
-                        } => *control_flow = ControlFlow::Exit,
+                match event {
+                
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
 
 
-                        WindowEvent::Resized(physical_size) => {
-                            state.resize(*physical_size);
-                        }
-
-                        WindowEvent::ScaleFactorChanged {new_inner_size, .. } => {
-                            state.resize(**new_inner_size);
-                        }
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                    }
 
-                        _ => {}
+                    WindowEvent::ScaleFactorChanged {new_inner_size, .. } => {
+                        state.resize(**new_inner_size);
                     }
 
+                    _ => {}
                 }
 
             }
 
+            Event::DeviceEvent { ref event, .. } => {
+                state.device_input(event);
+            }
+
             Event::RedrawRequested(window_id) if window_id == window.id() => {
+                // before the update ends the frame's input
+                if state.quit_requested() {
+                    *control_flow = ControlFlow::Exit;
+                }
+
                 state.update();
 
-                match state.render() {
+                if loading {
+                    let progress = state.loading_progress();
+
+                    if progress < 1.0 {
+                        window.set_title(&format!("{} (loading {:.0}%)", TITLE, progress * 100.0));
+                    } else {
+                        window.set_title(TITLE);
+                        loading = false;
+                    }
+                }
+
+                match state.render(&window) {
                     Ok(_) => {},
 
                     Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
@@ -85,11 +138,11 @@ async fn run() {
 }
 
 fn main(){
-    //let out = pollster::block_on(resources::load_string("cube.map"));
+    //let out = resources::load_string("cube.map");
 
     //let map = out.unwrap().parse::<Map>();
 
     //println!("{:?}", map.unwrap());
 
     pollster::block_on(run());
-} 
\ No newline at end of file
+}
@@ -0,0 +1,65 @@
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+use shalrath::repr::{Entity, Map};
+
+use crate::error::AssetError;
+use crate::resources;
+
+/// Parses a Quake `.map` file from the mounted asset sources.
+pub fn load(file_name: &str) -> Result<Map, AssetError> {
+    let text = resources::load_string(file_name)?;
+
+    text.parse::<Map>().map_err(|e| {
+        let offset = text.len() - e.input.len();
+        AssetError::parse_at(file_name, text.as_bytes(), offset, format!("unexpected input ({:?})", e.code))
+    })
+}
+
+/// The entity holding map wide settings, conventionally the first one.
+pub fn worldspawn(map: &Map) -> Option<&Entity> {
+    map.0.iter().find(|entity| property(entity, "classname") == Some("worldspawn"))
+}
+
+pub fn property<'a>(entity: &'a Entity, key: &str) -> Option<&'a str> {
+    entity.properties.0.iter()
+        .find(|property| property.key == key)
+        .map(|property| property.value.as_str())
+}
+
+/// Name of the map's skybox. Quake 2 era maps use `sky` and Quake 1 ports
+/// `_skybox`.
+pub fn sky(map: &Map) -> Option<&str> {
+    let worldspawn = worldspawn(map)?;
+
+    property(worldspawn, "sky")
+        .or_else(|| property(worldspawn, "_skybox"))
+        .filter(|name| !name.is_empty())
+}
+
+/// Size of a map unit in world units. Quake's player is 56 units tall.
+pub const MAP_SCALE: f64 = 1.0 / 32.0;
+
+/// Converts a position in map units, with z up, to world space, with y up.
+pub fn to_world(point: Point3<f64>) -> [f32; 3] {
+    to_world_direction(point.to_vec() * MAP_SCALE)
+}
+
+/// Converts a direction from map axes to world axes. It's a rotation, so
+/// windings stay counter-clockwise.
+pub fn to_world_direction(direction: Vector3<f64>) -> [f32; 3] {
+    [direction.x as f32, direction.z as f32, -direction.y as f32]
+}
+
+/// A property of three numbers separated by spaces, like `origin`.
+pub fn vector(entity: &Entity, key: &str) -> Option<Vector3<f64>> {
+    let mut numbers = property(entity, key)?.split_whitespace().map(|n| n.parse::<f64>().ok());
+
+    match (numbers.next(), numbers.next(), numbers.next(), numbers.next()) {
+        (Some(Some(x)), Some(Some(y)), Some(Some(z)), None) => Some(Vector3::new(x, y, z)),
+        _ => None,
+    }
+}
+
+pub fn number(entity: &Entity, key: &str) -> Option<f64> {
+    property(entity, key)?.trim().parse().ok()
+}
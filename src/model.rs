@@ -1,8 +1,8 @@
 use std::ops::Range;
 
-use ahash::AHashMap;
-
 use crate::{
+    assets::Handle,
+    shaders::Defines,
     texture::*,
 };
 
@@ -45,25 +45,186 @@ impl Vertex for ModelVertex {
     }
 }
 
+/// Axis aligned bounding box in model space.
+#[derive(Copy, Clone, Debug)]
+pub struct Bounds {
+    pub min: cgmath::Point3<f32>,
+    pub max: cgmath::Point3<f32>,
+}
+
+impl Bounds {
+    pub fn from_vertices(vertices: &[ModelVertex]) -> Self {
+        Self::from_points(vertices.iter().map(|v| v.position))
+    }
+
+    pub fn from_points(points: impl IntoIterator<Item = [f32; 3]>) -> Self {
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+
+        for position in points {
+            for i in 0..3 {
+                min[i] = min[i].min(position[i]);
+                max[i] = max[i].max(position[i]);
+            }
+        }
+
+        Self { min: min.into(), max: max.into() }
+    }
+
+    pub fn center(&self) -> cgmath::Point3<f32> {
+        cgmath::Point3::new(
+            (self.min.x + self.max.x) * 0.5,
+            (self.min.y + self.max.y) * 0.5,
+            (self.min.z + self.max.z) * 0.5,
+        )
+    }
+
+    pub fn contains(&self, point: cgmath::Point3<f32>) -> bool {
+        (0..3).all(|i| self.min[i] <= point[i] && point[i] <= self.max[i])
+    }
+
+    /// Corner `i` takes x, y and z from `max` when bits 0, 1 and 2 are set.
+    pub fn corners(&self) -> [cgmath::Point3<f32>; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| cgmath::Point3::new(
+            if i & 1 == 0 { self.min.x } else { self.max.x },
+            if i & 2 == 0 { self.min.y } else { self.max.y },
+            if i & 4 == 0 { self.min.z } else { self.max.z },
+        ))
+    }
+}
+
+/// How a material is lit, compiled into its `shader.wgsl` permutation.
+#[repr(u32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Shading {
+    /// The diffuse texture as is.
+    #[default]
+    Unlit = 0,
+    /// Cook-Torrance metallic-roughness with image based lighting.
+    Pbr = 1,
+}
+
+/// How a material's alpha is used. Each mode has its own pipeline.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlphaMode {
+    #[default]
+    Opaque,
+    /// Pixels under half alpha are discarded, the rest drawn opaque.
+    Mask,
+    /// Blended over what's behind, drawn back to front after everything
+    /// opaque.
+    Blend,
+}
+
+/// Per-material uniform, binding 2 of the material bind group.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    pub shading: u32,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Multiplies the diffuse texture's alpha.
+    pub alpha: f32,
+}
+
+impl MaterialUniform {
+    pub fn new(data: &MaterialData) -> Self {
+        Self {
+            shading: data.shading as u32,
+            metallic: data.metallic,
+            roughness: data.roughness,
+            alpha: data.alpha,
+        }
+    }
+}
+
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
 }
 
+#[allow(dead_code)]
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: Texture,
+    pub diffuse_texture: Handle<Texture>,
+    pub shading: Shading,
+    pub alpha_mode: AlphaMode,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    /// The `shader.wgsl` features the material needs.
+    pub fn defines(&self) -> Defines {
+        let mut defines = Defines::new();
+
+        if self.shading == Shading::Pbr {
+            defines = defines.with("PBR");
+        }
+        if self.alpha_mode == AlphaMode::Mask {
+            defines = defines.with("ALPHA_MASK");
+        }
+
+        defines
+    }
+}
+
+/// CPU side copy of a model, filled in by a loader thread and turned into
+/// a [`Model`] once it reaches the main thread.
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+}
+
+pub struct MaterialData {
+    pub name: String,
+    pub diffuse_path: String,
+    pub diffuse_image: image::DynamicImage,
+    pub shading: Shading,
+    /// Only used by `Shading::Pbr`, like `roughness`.
+    pub metallic: f32,
+    pub roughness: f32,
+    pub alpha_mode: AlphaMode,
+    pub alpha: f32,
+    /// How the diffuse texture is sampled outside 0 to 1.
+    pub address_mode: wgpu::AddressMode,
+}
+
+impl MaterialData {
+    /// An unlit material showing `diffuse_image`.
+    pub fn unlit(name: String, diffuse_path: String, diffuse_image: image::DynamicImage) -> Self {
+        Self {
+            name,
+            diffuse_path,
+            diffuse_image,
+            shading: Shading::Unlit,
+            metallic: 0.0,
+            roughness: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            alpha: 1.0,
+            address_mode: wgpu::AddressMode::ClampToEdge,
+        }
+    }
+}
+
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    pub material: usize,
+}
+
+#[allow(dead_code)]
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    pub bounds: Bounds,
 }
 
+#[allow(dead_code)]
 pub trait DrawModel<'a> {
     fn draw_mesh(
         &mut self,
@@ -0,0 +1,414 @@
+use std::rc::Rc;
+
+use crate::graph::{PassDesc, RenderGraph, TextureDesc};
+use crate::registry::{RenderPipelineDesc, Registry};
+use crate::shaders;
+
+/// What the scene is rendered into before post processing.
+pub const HDR: &str = "hdr";
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// The tonemapped image, in the surface format, when FXAA runs after it.
+const LDR: &str = "ldr";
+
+/// The names the bloom and FXAA passes' and the tonemap pass's bind group
+/// layouts are registered under.
+const BLOOM_LAYOUT: &str = "bloom";
+const TONEMAP_LAYOUT: &str = "tonemap";
+
+/// Bloom mips, each half the size of the one before, starting at half the
+/// surface.
+pub const BLOOM_LEVELS: usize = 5;
+const BLOOM_TEXTURES: [&str; BLOOM_LEVELS] = ["bloom0", "bloom1", "bloom2", "bloom3", "bloom4"];
+const DOWNSAMPLE_PASSES: [&str; BLOOM_LEVELS] = [
+    "bloom_prefilter",
+    "bloom_down1",
+    "bloom_down2",
+    "bloom_down3",
+    "bloom_down4",
+];
+const UPSAMPLE_PASSES: [&str; BLOOM_LEVELS - 1] = ["bloom_up0", "bloom_up1", "bloom_up2", "bloom_up3"];
+
+#[repr(u32)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Tonemapper {
+    #[default]
+    Aces = 0,
+    Reinhard = 1,
+    /// Clamps, for looking at raw values.
+    None = 2,
+}
+
+/// The passes post processing adds to the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostPass {
+    /// Writes bloom level `n` from the level above, or from the HDR target
+    /// with the threshold applied for level 0.
+    Downsample(usize),
+    /// Blurs bloom level `n + 1` onto level `n`.
+    Upsample(usize),
+    Tonemap,
+    Fxaa,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostSettings {
+    /// In stops, so each +1 doubles the brightness.
+    pub exposure: f32,
+    pub bloom: bool,
+    pub bloom_intensity: f32,
+    /// Brightness above which pixels bloom.
+    pub threshold: f32,
+    /// How far under the threshold bloom fades in.
+    pub knee: f32,
+    pub tonemapper: Tonemapper,
+    /// Changing it needs the graph rebuilt with `add_to_graph`.
+    pub fxaa: bool,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            bloom: true,
+            bloom_intensity: 0.05,
+            threshold: 1.0,
+            knee: 0.5,
+            tonemapper: Tonemapper::default(),
+            fxaa: false,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniform {
+    exposure: f32,
+    bloom_intensity: f32,
+    threshold: f32,
+    knee: f32,
+    tonemapper: u32,
+    encode_srgb: u32,
+    _padding: [u32; 2],
+}
+
+struct BindGroups {
+    downsample: Vec<wgpu::BindGroup>,
+    upsample: Vec<wgpu::BindGroup>,
+    tonemap: wgpu::BindGroup,
+    fxaa: Option<wgpu::BindGroup>,
+}
+
+/// Bloom and tonemapping from the HDR target onto the surface, and FXAA
+/// after the tonemap when it's on.
+///
+/// The bind groups sample the graph's transient textures, so [`prepare`]
+/// rebuilds them whenever the graph reallocates those.
+///
+/// [`prepare`]: PostProcess::prepare
+pub struct PostProcess {
+    pub settings: PostSettings,
+    surface_format: wgpu::TextureFormat,
+    /// The surface format isn't sRGB, so the shader encodes it.
+    encode_srgb: bool,
+    buffer: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    bloom_layout: Rc<wgpu::BindGroupLayout>,
+    tonemap_layout: Rc<wgpu::BindGroupLayout>,
+    prefilter_pipeline: Rc<wgpu::RenderPipeline>,
+    downsample_pipeline: Rc<wgpu::RenderPipeline>,
+    upsample_pipeline: Rc<wgpu::RenderPipeline>,
+    tonemap_pipeline: Rc<wgpu::RenderPipeline>,
+    fxaa_pipeline: Rc<wgpu::RenderPipeline>,
+    bind_groups: Option<BindGroups>,
+    generation: u32,
+}
+
+impl PostProcess {
+    pub fn new(device: &wgpu::Device, registry: &mut Registry, surface_format: wgpu::TextureFormat) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Post Buffer"),
+            size: std::mem::size_of::<PostUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("post_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+
+        let common_entries = [
+            texture_entry(0),
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+
+        let bloom_layout = registry.bind_group_layout(device, BLOOM_LAYOUT, &common_entries);
+        let tonemap_layout = registry.bind_group_layout(
+            device,
+            TONEMAP_LAYOUT,
+            &[common_entries[0], common_entries[1], common_entries[2], texture_entry(3)],
+        );
+
+        let mut pipeline = |label, layout, fragment_entry, format, blend| registry.render_pipeline(device, &RenderPipelineDesc {
+            label,
+            shader: "post.wgsl",
+            defines: shaders::Defines::new(),
+            bind_group_layouts: vec![layout],
+            vertex_entry: "vs_fullscreen",
+            vertex_buffers: Vec::new(),
+            fragment_entry,
+            targets: vec![Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+
+        let prefilter_pipeline = pipeline("Bloom Prefilter Pipeline", BLOOM_LAYOUT, "fs_prefilter", HDR_FORMAT, wgpu::BlendState::REPLACE);
+        let downsample_pipeline = pipeline("Bloom Downsample Pipeline", BLOOM_LAYOUT, "fs_downsample", HDR_FORMAT, wgpu::BlendState::REPLACE);
+        let upsample_pipeline = pipeline("Bloom Upsample Pipeline", BLOOM_LAYOUT, "fs_upsample", HDR_FORMAT, additive);
+        let tonemap_pipeline = pipeline("Tonemap Pipeline", TONEMAP_LAYOUT, "fs_tonemap", surface_format, wgpu::BlendState::REPLACE);
+        let fxaa_pipeline = pipeline("FXAA Pipeline", BLOOM_LAYOUT, "fs_fxaa", surface_format, wgpu::BlendState::REPLACE);
+
+        Self {
+            settings: PostSettings::default(),
+            surface_format,
+            encode_srgb: !surface_format.describe().srgb,
+            buffer,
+            sampler,
+            bloom_layout,
+            tonemap_layout,
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            tonemap_pipeline,
+            fxaa_pipeline,
+            bind_groups: None,
+            generation: 0,
+        }
+    }
+
+    /// Declares the HDR target, the bloom mips and the post passes, ending
+    /// with the tonemap, or FXAA if it's on, onto `output`. `key` wraps the
+    /// passes in the graph's own pass type.
+    pub fn add_to_graph<K>(&self, graph: &mut RenderGraph<K>, output: &'static str, key: impl Fn(PostPass) -> K) {
+        graph.add_texture(HDR, TextureDesc {
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            ..TextureDesc::new(HDR_FORMAT)
+        });
+
+        for (level, name) in BLOOM_TEXTURES.iter().enumerate() {
+            graph.add_texture(name, TextureDesc {
+                scale: 0.5f32.powi(level as i32 + 1),
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                ..TextureDesc::new(HDR_FORMAT)
+            });
+        }
+
+        for level in 0..BLOOM_LEVELS {
+            let source = if level == 0 { HDR } else { BLOOM_TEXTURES[level - 1] };
+
+            graph.add_pass(PassDesc::new(key(PostPass::Downsample(level)), DOWNSAMPLE_PASSES[level])
+                .read(source)
+                .color(BLOOM_TEXTURES[level], Some(wgpu::Color::BLACK)));
+        }
+
+        for level in (0..BLOOM_LEVELS - 1).rev() {
+            graph.add_pass(PassDesc::new(key(PostPass::Upsample(level)), UPSAMPLE_PASSES[level])
+                .read(BLOOM_TEXTURES[level + 1])
+                .color(BLOOM_TEXTURES[level], None));
+        }
+
+        let tonemapped = if self.settings.fxaa {
+            graph.add_texture(LDR, TextureDesc {
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                ..TextureDesc::new(self.surface_format)
+            });
+            LDR
+        } else {
+            output
+        };
+
+        graph.add_pass(PassDesc::new(key(PostPass::Tonemap), "tonemap")
+            .read(HDR)
+            .read(BLOOM_TEXTURES[0])
+            .color(tonemapped, None));
+
+        if self.settings.fxaa {
+            graph.add_pass(PassDesc::new(key(PostPass::Fxaa), "fxaa")
+                .read(LDR)
+                .color(output, None));
+        }
+    }
+
+    /// Rebuilds the bind groups if the graph's textures changed and uploads
+    /// the settings. With `raw` the HDR target is shown as is, for the
+    /// debug views.
+    pub fn prepare<K>(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, graph: &RenderGraph<K>, raw: bool) {
+        if self.bind_groups.is_none() || self.generation != graph.generation() {
+            self.generation = graph.generation();
+            self.bind_groups = Some(self.create_bind_groups(device, graph));
+        }
+
+        let settings = &self.settings;
+        let uniform = if raw {
+            PostUniform {
+                exposure: 1.0,
+                bloom_intensity: 0.0,
+                tonemapper: Tonemapper::None as u32,
+                ..self.uniform()
+            }
+        } else {
+            PostUniform {
+                bloom_intensity: if settings.bloom { settings.bloom_intensity } else { 0.0 },
+                ..self.uniform()
+            }
+        };
+
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    fn uniform(&self) -> PostUniform {
+        PostUniform {
+            exposure: self.settings.exposure.exp2(),
+            bloom_intensity: self.settings.bloom_intensity,
+            threshold: self.settings.threshold,
+            knee: self.settings.knee.max(0.0001),
+            tonemapper: self.settings.tonemapper as u32,
+            encode_srgb: self.encode_srgb as u32,
+            _padding: [0; 2],
+        }
+    }
+
+    fn create_bind_groups<K>(&self, device: &wgpu::Device, graph: &RenderGraph<K>) -> BindGroups {
+        let bloom_group = |source: &str| device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bloom_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(graph.view(source)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("bloom_bind_group"),
+        });
+
+        let downsample = (0..BLOOM_LEVELS)
+            .map(|level| bloom_group(if level == 0 { HDR } else { BLOOM_TEXTURES[level - 1] }))
+            .collect();
+
+        let upsample = (0..BLOOM_LEVELS - 1)
+            .map(|level| bloom_group(BLOOM_TEXTURES[level + 1]))
+            .collect();
+
+        let tonemap = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.tonemap_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(graph.view(HDR)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(graph.view(BLOOM_TEXTURES[0])),
+                },
+            ],
+            label: Some("tonemap_bind_group"),
+        });
+
+        let fxaa = graph.contains(LDR).then(|| bloom_group(LDR));
+
+        BindGroups {
+            downsample,
+            upsample,
+            tonemap,
+            fxaa,
+        }
+    }
+
+    /// Bloom passes are skipped while bloom is off, the rest never are.
+    pub fn enabled(&self, pass: PostPass) -> bool {
+        match pass {
+            PostPass::Downsample(_) | PostPass::Upsample(_) => self.settings.bloom,
+            PostPass::Tonemap | PostPass::Fxaa => true,
+        }
+    }
+
+    pub fn record<'a>(&'a self, pass: PostPass, render_pass: &mut wgpu::RenderPass<'a>) {
+        let bind_groups = self.bind_groups.as_ref().expect("PostProcess::prepare wasn't called");
+
+        let (pipeline, bind_group) = match pass {
+            PostPass::Downsample(0) => (&self.prefilter_pipeline, &bind_groups.downsample[0]),
+            PostPass::Downsample(level) => (&self.downsample_pipeline, &bind_groups.downsample[level]),
+            PostPass::Upsample(level) => (&self.upsample_pipeline, &bind_groups.upsample[level]),
+            PostPass::Tonemap => (&self.tonemap_pipeline, &bind_groups.tonemap),
+            PostPass::Fxaa => (
+                &self.fxaa_pipeline,
+                bind_groups.fxaa.as_ref().expect("FXAA pass in a graph built without it"),
+            ),
+        };
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
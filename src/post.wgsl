@@ -0,0 +1,306 @@
+// Post processing: bloom down/upsampling, tonemapping and FXAA, all drawn
+// with a fullscreen triangle
+
+struct PostUniform {
+    exposure: f32,
+    bloom_intensity: f32,
+    threshold: f32,
+    knee: f32,
+    tonemapper: u32,
+    encode_srgb: u32,
+    _padding: vec2<u32>,
+};
+
+@group(0) @binding(0)
+var t_source: texture_2d<f32>;
+@group(0) @binding(1)
+var s_source: sampler;
+@group(0) @binding(2)
+var<uniform> post: PostUniform;
+@group(0) @binding(3)
+var t_bloom: texture_2d<f32>;
+
+#include "fullscreen.wgsl"
+
+fn sample_source(uv: vec2<f32>) -> vec3<f32> {
+    return textureSample(t_source, s_source, uv).rgb;
+}
+
+// 13 tap filter from Call of Duty: Advanced Warfare, which doesn't flicker
+// as bright pixels move between texels
+fn downsample(uv: vec2<f32>) -> vec3<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_source));
+
+    let a = sample_source(uv + texel * vec2<f32>(-2.0, 2.0));
+    let b = sample_source(uv + texel * vec2<f32>(0.0, 2.0));
+    let c = sample_source(uv + texel * vec2<f32>(2.0, 2.0));
+    let d = sample_source(uv + texel * vec2<f32>(-2.0, 0.0));
+    let e = sample_source(uv);
+    let f = sample_source(uv + texel * vec2<f32>(2.0, 0.0));
+    let g = sample_source(uv + texel * vec2<f32>(-2.0, -2.0));
+    let h = sample_source(uv + texel * vec2<f32>(0.0, -2.0));
+    let i = sample_source(uv + texel * vec2<f32>(2.0, -2.0));
+    let j = sample_source(uv + texel * vec2<f32>(-1.0, 1.0));
+    let k = sample_source(uv + texel * vec2<f32>(1.0, 1.0));
+    let l = sample_source(uv + texel * vec2<f32>(-1.0, -1.0));
+    let m = sample_source(uv + texel * vec2<f32>(1.0, -1.0));
+
+    return e * 0.125
+        + (a + c + g + i) * 0.03125
+        + (b + d + f + h) * 0.0625
+        + (j + k + l + m) * 0.125;
+}
+
+// The first downsample also drops everything under the threshold, with a
+// soft knee so the cutoff isn't visible
+@fragment
+fn fs_prefilter(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let color = downsample(in.uv);
+    let brightness = max(color.r, max(color.g, color.b));
+
+    var soft = clamp(brightness - post.threshold + post.knee, 0.0, 2.0 * post.knee);
+    soft = soft * soft / (4.0 * post.knee + 0.00001);
+
+    let weight = max(soft, brightness - post.threshold) / max(brightness, 0.00001);
+    return vec4<f32>(color * weight, 1.0);
+}
+
+@fragment
+fn fs_downsample(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(downsample(in.uv), 1.0);
+}
+
+// 3x3 tent filter, added onto the next larger level by the blend state
+@fragment
+fn fs_upsample(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_source));
+
+    var sum = sample_source(in.uv) * 4.0;
+    sum += sample_source(in.uv + texel * vec2<f32>(0.0, 1.0)) * 2.0;
+    sum += sample_source(in.uv + texel * vec2<f32>(-1.0, 0.0)) * 2.0;
+    sum += sample_source(in.uv + texel * vec2<f32>(1.0, 0.0)) * 2.0;
+    sum += sample_source(in.uv + texel * vec2<f32>(0.0, -1.0)) * 2.0;
+    sum += sample_source(in.uv + texel * vec2<f32>(-1.0, 1.0));
+    sum += sample_source(in.uv + texel * vec2<f32>(1.0, 1.0));
+    sum += sample_source(in.uv + texel * vec2<f32>(-1.0, -1.0));
+    sum += sample_source(in.uv + texel * vec2<f32>(1.0, -1.0));
+
+    return vec4<f32>(sum / 16.0, 1.0);
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn reinhard(x: vec3<f32>) -> vec3<f32> {
+    return x / (1.0 + x);
+}
+
+fn linear_to_srgb(x: vec3<f32>) -> vec3<f32> {
+    let low = x * 12.92;
+    let high = 1.055 * pow(x, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, x <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_tonemap(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let hdr = sample_source(in.uv);
+    let bloom = textureSample(t_bloom, s_source, in.uv).rgb;
+
+    let exposed = (hdr + bloom * post.bloom_intensity) * post.exposure;
+
+    var color: vec3<f32>;
+    switch post.tonemapper {
+        case 1u: {
+            color = reinhard(exposed);
+        }
+        case 2u: {
+            color = clamp(exposed, vec3<f32>(0.0), vec3<f32>(1.0));
+        }
+        default: {
+            color = aces(exposed);
+        }
+    }
+
+    if post.encode_srgb == 1u {
+        color = linear_to_srgb(color);
+    }
+
+    return vec4<f32>(color, 1.0);
+}
+
+// FXAA, after Timothy Lottes' FXAA 3.11 quality preset as simplified by
+// Simon Rodriguez. Runs on the tonemapped image, where luma differences
+// match what's visible.
+
+let FXAA_EDGE_THRESHOLD_MIN: f32 = 0.0312;
+let FXAA_EDGE_THRESHOLD_MAX: f32 = 0.125;
+let FXAA_SUBPIXEL_QUALITY: f32 = 0.75;
+let FXAA_ITERATIONS: i32 = 12;
+
+fn fxaa_sample(uv: vec2<f32>) -> vec3<f32> {
+    // sampled in loops and branches, where implicit derivatives aren't allowed
+    return textureSampleLevel(t_source, s_source, uv, 0.0).rgb;
+}
+
+fn fxaa_luma(color: vec3<f32>) -> f32 {
+    let luma = dot(color, vec3<f32>(0.299, 0.587, 0.114));
+
+    // the source is in linear space when the hardware does the sRGB encode
+    if post.encode_srgb == 1u {
+        return luma;
+    }
+    return sqrt(luma);
+}
+
+fn fxaa_quality(i: i32) -> f32 {
+    if i < 5 {
+        return 1.0;
+    }
+    if i == 5 {
+        return 1.5;
+    }
+    if i < 10 {
+        return 2.0;
+    }
+    if i == 10 {
+        return 4.0;
+    }
+    return 8.0;
+}
+
+@fragment
+fn fs_fxaa(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_source));
+    let uv = in.uv;
+
+    let color_center = fxaa_sample(uv);
+    let luma_center = fxaa_luma(color_center);
+
+    let luma_down = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(0.0, -1.0)));
+    let luma_up = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(0.0, 1.0)));
+    let luma_left = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(-1.0, 0.0)));
+    let luma_right = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(1.0, 0.0)));
+
+    let luma_min = min(luma_center, min(min(luma_down, luma_up), min(luma_left, luma_right)));
+    let luma_max = max(luma_center, max(max(luma_down, luma_up), max(luma_left, luma_right)));
+    let luma_range = luma_max - luma_min;
+
+    // flat areas aren't on an edge
+    if luma_range < max(FXAA_EDGE_THRESHOLD_MIN, luma_max * FXAA_EDGE_THRESHOLD_MAX) {
+        return vec4<f32>(color_center, 1.0);
+    }
+
+    let luma_down_left = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(-1.0, -1.0)));
+    let luma_up_right = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(1.0, 1.0)));
+    let luma_up_left = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(-1.0, 1.0)));
+    let luma_down_right = fxaa_luma(fxaa_sample(uv + texel * vec2<f32>(1.0, -1.0)));
+
+    let luma_down_up = luma_down + luma_up;
+    let luma_left_right = luma_left + luma_right;
+    let luma_left_corners = luma_down_left + luma_up_left;
+    let luma_down_corners = luma_down_left + luma_down_right;
+    let luma_right_corners = luma_down_right + luma_up_right;
+    let luma_up_corners = luma_up_right + luma_up_left;
+
+    let edge_horizontal = abs(-2.0 * luma_left + luma_left_corners)
+        + abs(-2.0 * luma_center + luma_down_up) * 2.0
+        + abs(-2.0 * luma_right + luma_right_corners);
+    let edge_vertical = abs(-2.0 * luma_up + luma_up_corners)
+        + abs(-2.0 * luma_center + luma_left_right) * 2.0
+        + abs(-2.0 * luma_down + luma_down_corners);
+    let is_horizontal = edge_horizontal >= edge_vertical;
+
+    // which side of the edge the pixel is on
+    let luma1 = select(luma_left, luma_down, is_horizontal);
+    let luma2 = select(luma_right, luma_up, is_horizontal);
+    let gradient1 = luma1 - luma_center;
+    let gradient2 = luma2 - luma_center;
+    let is_1_steepest = abs(gradient1) >= abs(gradient2);
+    let gradient_scaled = 0.25 * max(abs(gradient1), abs(gradient2));
+
+    var step_length = select(texel.x, texel.y, is_horizontal);
+    var luma_local_average: f32;
+
+    if is_1_steepest {
+        step_length = -step_length;
+        luma_local_average = 0.5 * (luma1 + luma_center);
+    } else {
+        luma_local_average = 0.5 * (luma2 + luma_center);
+    }
+
+    // walk along the edge, half a texel over, until both ends are found
+    var current_uv = uv;
+    if is_horizontal {
+        current_uv.y += step_length * 0.5;
+    } else {
+        current_uv.x += step_length * 0.5;
+    }
+
+    let offset = select(vec2<f32>(0.0, texel.y), vec2<f32>(texel.x, 0.0), is_horizontal);
+    var uv1 = current_uv - offset;
+    var uv2 = current_uv + offset;
+
+    var luma_end1 = fxaa_luma(fxaa_sample(uv1)) - luma_local_average;
+    var luma_end2 = fxaa_luma(fxaa_sample(uv2)) - luma_local_average;
+    var reached1 = abs(luma_end1) >= gradient_scaled;
+    var reached2 = abs(luma_end2) >= gradient_scaled;
+
+    if !reached1 {
+        uv1 -= offset;
+    }
+    if !reached2 {
+        uv2 += offset;
+    }
+
+    for (var i = 2; i < FXAA_ITERATIONS; i += 1) {
+        if reached1 && reached2 {
+            break;
+        }
+
+        if !reached1 {
+            luma_end1 = fxaa_luma(fxaa_sample(uv1)) - luma_local_average;
+            reached1 = abs(luma_end1) >= gradient_scaled;
+        }
+        if !reached2 {
+            luma_end2 = fxaa_luma(fxaa_sample(uv2)) - luma_local_average;
+            reached2 = abs(luma_end2) >= gradient_scaled;
+        }
+
+        if !reached1 {
+            uv1 -= offset * fxaa_quality(i);
+        }
+        if !reached2 {
+            uv2 += offset * fxaa_quality(i);
+        }
+    }
+
+    let distance1 = select(uv.y - uv1.y, uv.x - uv1.x, is_horizontal);
+    let distance2 = select(uv2.y - uv.y, uv2.x - uv.x, is_horizontal);
+    let is_direction1 = distance1 < distance2;
+    let distance_final = min(distance1, distance2);
+    let edge_thickness = distance1 + distance2;
+
+    // only blend towards the end whose luma varies the same way as ours
+    let luma_end = select(luma_end2, luma_end1, is_direction1);
+    let correct_variation = (luma_end < 0.0) != (luma_center < luma_local_average);
+    let pixel_offset = select(0.0, -distance_final / edge_thickness + 0.5, correct_variation);
+
+    // subpixel aliasing, for thin features the edge walk misses
+    let luma_average = (1.0 / 12.0) * (2.0 * (luma_down_up + luma_left_right) + luma_left_corners + luma_right_corners);
+    let subpixel1 = clamp(abs(luma_average - luma_center) / luma_range, 0.0, 1.0);
+    let subpixel2 = (-2.0 * subpixel1 + 3.0) * subpixel1 * subpixel1;
+    let subpixel_offset = subpixel2 * subpixel2 * FXAA_SUBPIXEL_QUALITY;
+
+    let final_offset = max(pixel_offset, subpixel_offset);
+
+    var final_uv = uv;
+    if is_horizontal {
+        final_uv.y += final_offset * step_length;
+    } else {
+        final_uv.x += final_offset * step_length;
+    }
+
+    return vec4<f32>(fxaa_sample(final_uv), 1.0);
+}
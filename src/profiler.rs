@@ -0,0 +1,378 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most scopes a single frame can have.
+const MAX_SCOPES: usize = 32;
+
+/// Frames whose timestamps can be waiting on readback at once. A frame that
+/// finds its slot still busy isn't timed rather than stalling on the GPU.
+const FRAMES_IN_FLIGHT: usize = 3;
+
+/// How many frames of timings the averages cover.
+const HISTORY: usize = 120;
+
+/// How many frames of events a trace export holds.
+const TRACE_FRAMES: usize = 600;
+
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bytes of timestamps one frame resolves, a begin and an end per scope.
+const SLOT_SIZE: wgpu::BufferAddress = (MAX_SCOPES * 2 * std::mem::size_of::<u64>()) as wgpu::BufferAddress;
+
+/// Timings of one named scope over the last `HISTORY` frames.
+pub struct PassTimings {
+    pub name: &'static str,
+    times: VecDeque<f32>,
+}
+
+impl PassTimings {
+    /// Average in milliseconds.
+    pub fn average(&self) -> f32 {
+        self.times.iter().sum::<f32>() / self.times.len().max(1) as f32
+    }
+
+    /// Worst in milliseconds.
+    pub fn max(&self) -> f32 {
+        self.times.iter().cloned().fold(0.0, f32::max)
+    }
+
+    /// Most recent in milliseconds.
+    pub fn last(&self) -> f32 {
+        self.times.back().cloned().unwrap_or(0.0)
+    }
+}
+
+struct Scope {
+    name: &'static str,
+    cpu_start: Instant,
+    cpu_end: Option<Instant>,
+}
+
+/// A finished scope on the trace timeline, in microseconds since the
+/// profiler started.
+struct TraceEvent {
+    name: &'static str,
+    gpu: bool,
+    start: f64,
+    duration: f64,
+}
+
+struct Slot {
+    readback: wgpu::Buffer,
+    /// Set by the map callback, to whether mapping worked.
+    mapped: Arc<Mutex<Option<bool>>>,
+    /// Scopes whose timestamps are in `readback`, set while it's in use.
+    pending: Option<Vec<Scope>>,
+}
+
+struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    slots: Vec<Slot>,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+}
+
+/// Times each pass of a frame, on the GPU with timestamp queries when the
+/// device has `TIMESTAMP_QUERY` and on the CPU otherwise.
+///
+/// CPU timings only cover recording the commands, not executing them, so
+/// they're a lower bound on what the pass costs.
+///
+/// Per frame: `begin_frame`, then `begin`/`end` around each pass on the
+/// encoder, `resolve` before finishing the encoder and `end_frame` after
+/// submitting it. GPU results arrive a few frames late.
+pub struct Profiler {
+    gpu: Option<GpuTimer>,
+    frame: usize,
+    /// Whether this frame got a free slot for its timestamps.
+    timing_gpu: bool,
+    scopes: Vec<Scope>,
+    open: Vec<usize>,
+    passes: Vec<PassTimings>,
+    trace: VecDeque<Vec<TraceEvent>>,
+    epoch: Instant,
+    last_log: Instant,
+}
+
+impl Profiler {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let gpu = device.features().contains(wgpu::Features::TIMESTAMP_QUERY).then(|| {
+            let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("profiler_queries"),
+                ty: wgpu::QueryType::Timestamp,
+                count: (FRAMES_IN_FLIGHT * MAX_SCOPES * 2) as u32,
+            });
+
+            let slots = (0..FRAMES_IN_FLIGHT)
+                .map(|_| Slot {
+                    readback: device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some("profiler_readback"),
+                        size: SLOT_SIZE,
+                        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                        mapped_at_creation: false,
+                    }),
+                    mapped: Arc::new(Mutex::new(None)),
+                    pending: None,
+                })
+                .collect();
+
+            GpuTimer {
+                query_set,
+                slots,
+                period: queue.get_timestamp_period(),
+            }
+        });
+
+        if gpu.is_none() {
+            log::info!("no TIMESTAMP_QUERY, profiling passes on the CPU");
+        }
+
+        let now = Instant::now();
+
+        Self {
+            gpu,
+            frame: 0,
+            timing_gpu: false,
+            scopes: Vec::new(),
+            open: Vec::new(),
+            passes: Vec::new(),
+            trace: VecDeque::with_capacity(TRACE_FRAMES),
+            epoch: now,
+            last_log: now,
+        }
+    }
+
+    pub fn is_gpu(&self) -> bool {
+        self.gpu.is_some()
+    }
+
+    pub fn passes(&self) -> &[PassTimings] {
+        &self.passes
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.scopes.clear();
+        self.open.clear();
+
+        let slot = self.frame % FRAMES_IN_FLIGHT;
+        self.timing_gpu = self.gpu.as_ref().is_some_and(|gpu| gpu.slots[slot].pending.is_none());
+    }
+
+    /// Opens a scope called `name`. Scopes nest and are closed by `end` in
+    /// reverse order.
+    pub fn begin(&mut self, encoder: &mut wgpu::CommandEncoder, name: &'static str) {
+        if self.scopes.len() == MAX_SCOPES {
+            log::warn!("more than {} profiler scopes in a frame, {} isn't timed", MAX_SCOPES, name);
+            return;
+        }
+
+        let index = self.scopes.len();
+        self.write_timestamp(encoder, index * 2);
+
+        self.open.push(index);
+        self.scopes.push(Scope {
+            name,
+            cpu_start: Instant::now(),
+            cpu_end: None,
+        });
+    }
+
+    pub fn end(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let index = match self.open.pop() {
+            Some(index) => index,
+            None => return,
+        };
+
+        self.write_timestamp(encoder, index * 2 + 1);
+        self.scopes[index].cpu_end = Some(Instant::now());
+    }
+
+    fn write_timestamp(&self, encoder: &mut wgpu::CommandEncoder, query: usize) {
+        if let (true, Some(gpu)) = (self.timing_gpu, &self.gpu) {
+            let base = (self.frame % FRAMES_IN_FLIGHT) * MAX_SCOPES * 2;
+            encoder.write_timestamp(&gpu.query_set, (base + query) as u32);
+        }
+    }
+
+    /// Copies this frame's timestamps into its readback buffer.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let gpu = match (self.timing_gpu, &self.gpu) {
+            (true, Some(gpu)) if !self.scopes.is_empty() => gpu,
+            _ => return,
+        };
+
+        let slot = self.frame % FRAMES_IN_FLIGHT;
+        let base = (slot * MAX_SCOPES * 2) as u32;
+        let count = self.scopes.len() as u32 * 2;
+
+        encoder.resolve_query_set(&gpu.query_set, base..base + count, &gpu.slots[slot].readback, 0);
+    }
+
+    /// Starts reading back this frame's timestamps and records whatever
+    /// earlier frames have finished.
+    pub fn end_frame(&mut self, device: &wgpu::Device) {
+        let scopes = std::mem::take(&mut self.scopes);
+        let slot = self.frame % FRAMES_IN_FLIGHT;
+        self.frame += 1;
+
+        match &mut self.gpu {
+            Some(gpu) if self.timing_gpu && !scopes.is_empty() => {
+                let slot = &mut gpu.slots[slot];
+                let mapped = slot.mapped.clone();
+
+                slot.readback.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                    if let Err(e) = &result {
+                        log::warn!("couldn't read back timestamps: {}", e);
+                    }
+                    *mapped.lock().unwrap() = Some(result.is_ok());
+                });
+                slot.pending = Some(scopes);
+            }
+
+            Some(_) => {}
+
+            None => {
+                let events = scopes.iter()
+                    .filter_map(|scope| {
+                        let end = scope.cpu_end?;
+                        Some(TraceEvent {
+                            name: scope.name,
+                            gpu: false,
+                            start: self.micros(scope.cpu_start),
+                            duration: (end - scope.cpu_start).as_secs_f64() * 1e6,
+                        })
+                    })
+                    .collect();
+                self.record(events);
+            }
+        }
+
+        self.collect(device);
+
+        if self.last_log.elapsed() >= LOG_INTERVAL {
+            self.last_log = Instant::now();
+            self.log();
+        }
+    }
+
+    fn collect(&mut self, device: &wgpu::Device) {
+        let gpu = match &mut self.gpu {
+            Some(gpu) => gpu,
+            None => return,
+        };
+
+        device.poll(wgpu::Maintain::Poll);
+
+        let mut finished = Vec::new();
+
+        for slot in &mut gpu.slots {
+            let scopes = match slot.mapped.lock().unwrap().take() {
+                Some(true) => slot.pending.take().unwrap_or_default(),
+                Some(false) => {
+                    slot.pending = None;
+                    continue;
+                }
+                None => continue,
+            };
+
+            let timestamps = {
+                let view = slot.readback.slice(..).get_mapped_range();
+                bytemuck::cast_slice::<u8, u64>(&view)[..scopes.len() * 2].to_vec()
+            };
+            slot.readback.unmap();
+
+            finished.push((scopes, timestamps));
+        }
+
+        let period = gpu.period as f64;
+
+        for (scopes, timestamps) in finished {
+            // GPU clocks have their own epoch, so the frame is placed on the
+            // trace where its first scope started recording
+            let (origin, anchor) = match (timestamps.first(), scopes.first()) {
+                (Some(&origin), Some(first)) => (origin, self.micros(first.cpu_start)),
+                _ => continue,
+            };
+
+            let events = scopes.iter()
+                .zip(timestamps.chunks_exact(2))
+                .filter(|(scope, _)| scope.cpu_end.is_some())
+                .map(|(scope, pair)| TraceEvent {
+                    name: scope.name,
+                    gpu: true,
+                    start: anchor + pair[0].saturating_sub(origin) as f64 * period / 1000.0,
+                    duration: pair[1].saturating_sub(pair[0]) as f64 * period / 1000.0,
+                })
+                .collect();
+
+            self.record(events);
+        }
+    }
+
+    fn micros(&self, instant: Instant) -> f64 {
+        (instant - self.epoch).as_secs_f64() * 1e6
+    }
+
+    fn record(&mut self, events: Vec<TraceEvent>) {
+        for event in &events {
+            let index = match self.passes.iter().position(|pass| pass.name == event.name) {
+                Some(index) => index,
+                None => {
+                    self.passes.push(PassTimings {
+                        name: event.name,
+                        times: VecDeque::with_capacity(HISTORY),
+                    });
+                    self.passes.len() - 1
+                }
+            };
+
+            let times = &mut self.passes[index].times;
+            if times.len() == HISTORY {
+                times.pop_front();
+            }
+            times.push_back((event.duration / 1000.0) as f32);
+        }
+
+        if self.trace.len() == TRACE_FRAMES {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(events);
+    }
+
+    fn log(&self) {
+        for pass in &self.passes {
+            log::info!(
+                "{} pass: {:.3} ms average, {:.3} ms worst ({})",
+                pass.name,
+                pass.average(),
+                pass.max(),
+                if self.is_gpu() { "gpu" } else { "cpu" },
+            );
+        }
+    }
+
+    /// Writes the recorded frames as a Chrome trace, for `chrome://tracing`
+    /// or Perfetto.
+    pub fn export_trace(&self, path: &Path) -> std::io::Result<()> {
+        let mut json = String::from("{\"traceEvents\":[\n");
+        json.push_str("{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":0,\"args\":{\"name\":\"cpu\"}},\n");
+        json.push_str("{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":1,\"args\":{\"name\":\"gpu\"}}");
+
+        for event in self.trace.iter().flatten() {
+            let _ = write!(
+                json,
+                ",\n{{\"name\":\"{}\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}}}",
+                event.name.escape_default(),
+                event.gpu as u32,
+                event.start,
+                event.duration,
+            );
+        }
+
+        json.push_str("\n]}\n");
+        std::fs::write(path, json)
+    }
+}
@@ -0,0 +1,213 @@
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use ahash::AHashMap;
+
+use crate::shaders::{Defines, ShaderCache};
+
+/// The material bind group every model and level mesh binds as group 0:
+/// diffuse texture, its sampler and the `MaterialUniform`.
+pub const MATERIAL: &str = "material";
+
+/// The `CameraUniform` bind group.
+pub const CAMERA: &str = "camera";
+
+/// Everything of a render pipeline's descriptor, hashable so equal ones
+/// share a pipeline. Bind group layouts are referred to by the names they
+/// were registered with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderPipelineDesc {
+    pub label: &'static str,
+    pub shader: &'static str,
+    pub defines: Defines,
+    pub bind_group_layouts: Vec<&'static str>,
+    pub vertex_entry: &'static str,
+    pub vertex_buffers: Vec<wgpu::VertexBufferLayout<'static>>,
+    pub fragment_entry: &'static str,
+    pub targets: Vec<Option<wgpu::ColorTargetState>>,
+    pub primitive: wgpu::PrimitiveState,
+    pub depth_stencil: Option<wgpu::DepthStencilState>,
+    pub multisample: wgpu::MultisampleState,
+}
+
+// the depth bias has floats, so it's left out of the hash and compared as
+// is; pipelines never use NaN biases
+impl Eq for RenderPipelineDesc {}
+
+impl Hash for RenderPipelineDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.label.hash(state);
+        self.shader.hash(state);
+        self.defines.hash(state);
+        self.bind_group_layouts.hash(state);
+        self.vertex_entry.hash(state);
+        self.vertex_buffers.hash(state);
+        self.fragment_entry.hash(state);
+        self.targets.hash(state);
+        self.primitive.hash(state);
+        self.multisample.hash(state);
+
+        if let Some(depth_stencil) = &self.depth_stencil {
+            depth_stencil.format.hash(state);
+            depth_stencil.depth_write_enabled.hash(state);
+            depth_stencil.depth_compare.hash(state);
+        }
+    }
+}
+
+/// Bind group layouts, pipeline layouts and render pipelines shared by the
+/// renderers. Layouts are registered under a name, so loaders and passes
+/// can fetch them without having them passed down, and layouts with the
+/// same entries are one object whatever they're called, so bind groups
+/// made for one fit pipelines made with the other.
+#[derive(Default)]
+pub struct Registry {
+    shaders: ShaderCache,
+    bind_group_layouts: AHashMap<Vec<wgpu::BindGroupLayoutEntry>, Rc<wgpu::BindGroupLayout>>,
+    names: AHashMap<&'static str, Vec<wgpu::BindGroupLayoutEntry>>,
+    pipeline_layouts: AHashMap<Vec<&'static str>, Rc<wgpu::PipelineLayout>>,
+    render_pipelines: AHashMap<RenderPipelineDesc, Rc<wgpu::RenderPipeline>>,
+}
+
+impl Registry {
+    /// A registry holding the `MATERIAL` and `CAMERA` layouts.
+    pub fn new(device: &wgpu::Device) -> Self {
+        let mut registry = Self::default();
+
+        registry.bind_group_layout(device, MATERIAL, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                // This should match the filterable field of the
+                // corresponding Texture entry above.
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]);
+
+        registry.bind_group_layout(device, CAMERA, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]);
+
+        registry
+    }
+
+    /// Registers `entries` as the layout called `name`, creating it unless
+    /// a layout with the same entries exists already.
+    ///
+    /// # Panics
+    ///
+    /// If `name` was registered before with different entries.
+    pub fn bind_group_layout(
+        &mut self,
+        device: &wgpu::Device,
+        name: &'static str,
+        entries: &[wgpu::BindGroupLayoutEntry],
+    ) -> Rc<wgpu::BindGroupLayout> {
+        if let Some(registered) = self.names.get(name) {
+            assert!(registered == entries, "bind group layout {} registered twice with different entries", name);
+        }
+        self.names.insert(name, entries.to_vec());
+
+        self.bind_group_layouts
+            .entry(entries.to_vec())
+            .or_insert_with(|| Rc::new(device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some(name),
+                entries,
+            })))
+            .clone()
+    }
+
+    /// The layout registered as `name`.
+    ///
+    /// # Panics
+    ///
+    /// If nothing was registered as `name`, which means a renderer was made
+    /// before the one owning the layout.
+    pub fn layout(&self, name: &str) -> Rc<wgpu::BindGroupLayout> {
+        self.names.get(name)
+            .and_then(|entries| self.bind_group_layouts.get(entries))
+            .unwrap_or_else(|| panic!("no bind group layout called {}", name))
+            .clone()
+    }
+
+    /// The pipeline layout binding the layouts registered as `names`, group
+    /// 0 first.
+    pub fn pipeline_layout(&mut self, device: &wgpu::Device, names: &[&'static str]) -> Rc<wgpu::PipelineLayout> {
+        if let Some(layout) = self.pipeline_layouts.get(names) {
+            return layout.clone();
+        }
+
+        let bind_group_layouts = names.iter().map(|name| self.layout(name)).collect::<Vec<_>>();
+        let layout = Rc::new(device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &bind_group_layouts.iter().map(|layout| layout.as_ref()).collect::<Vec<_>>(),
+            push_constant_ranges: &[],
+        }));
+
+        self.pipeline_layouts.insert(names.to_vec(), layout.clone());
+        layout
+    }
+
+    /// The pipeline described by `desc`, compiled the first time it's asked
+    /// for.
+    pub fn render_pipeline(&mut self, device: &wgpu::Device, desc: &RenderPipelineDesc) -> Rc<wgpu::RenderPipeline> {
+        if let Some(pipeline) = self.render_pipelines.get(desc) {
+            return pipeline.clone();
+        }
+
+        let layout = self.pipeline_layout(device, &desc.bind_group_layouts);
+        let shader = self.shaders.get(device, desc.shader, &desc.defines);
+
+        let pipeline = Rc::new(device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(desc.label),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: desc.vertex_entry,
+                buffers: &desc.vertex_buffers,
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: desc.fragment_entry,
+                targets: &desc.targets,
+            }),
+            primitive: desc.primitive,
+            depth_stencil: desc.depth_stencil.clone(),
+            multisample: desc.multisample,
+            multiview: None,
+        }));
+
+        self.render_pipelines.insert(desc.clone(), pipeline.clone());
+        pipeline
+    }
+}
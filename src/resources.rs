@@ -1,8 +1,14 @@
-use std::io::{BufReader, Cursor};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use ahash::AHashMap;
+use anyhow::bail;
 
 use wgpu::util::DeviceExt;
 
-use crate::{model, texture};
+use crate::{assets, model, registry::{self, Registry}, texture};
+use crate::error::AssetError;
 
 #[cfg(target_arch = "wasm32")]
 fn format_url(file_name: &str) -> reqwest::Url {
@@ -16,113 +22,390 @@ fn format_url(file_name: &str) -> reqwest::Url {
     base.join(file_name).unwrap()
 }
 
-pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
+/// Somewhere assets can be read from: a directory on disk or an archive.
+///
+/// Sources are mounted into a single search path; a lookup walks the
+/// sources from the most recently mounted to the oldest, so later mounts
+/// shadow files of the same name in earlier ones.
+pub trait AssetSource: Send + Sync {
+    /// Human readable name used in logs.
+    fn name(&self) -> &str;
+
+    /// Returns the contents of `file_name`, or `None` if this source
+    /// doesn't contain it.
+    fn read(&self, file_name: &str) -> Result<Option<Vec<u8>>, AssetError>;
+}
+
+pub struct DirectorySource {
+    name: String,
+    root: PathBuf,
+}
 
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
-    let txt = std::fs::read_to_string(path)?;
+impl DirectorySource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Self {
+            name: root.display().to_string(),
+            root,
+        }
+    }
+}
 
-    Ok(txt)
+impl AssetSource for DirectorySource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, file_name: &str) -> Result<Option<Vec<u8>>, AssetError> {
+        // names come from map data too, so keep them inside the root: `..`
+        // would climb out of it and `join` replaces it with an absolute path
+        let relative = Path::new(file_name).components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !relative {
+            return Err(AssetError::Missing { path: file_name.to_string() });
+        }
+
+        match std::fs::read(self.root.join(file_name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(AssetError::io(file_name, e)),
+        }
+    }
 }
 
-pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
+pub struct ZipSource {
+    name: String,
+    // ZipArchive needs `&mut` to read entries
+    archive: Mutex<zip::ZipArchive<std::fs::File>>,
+}
 
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
-    let data = std::fs::read(path)?;
+impl ZipSource {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
 
-    Ok(data)
+        Ok(Self {
+            name: path.display().to_string(),
+            archive: Mutex::new(zip::ZipArchive::new(file)?),
+        })
+    }
 }
 
+impl AssetSource for ZipSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
 
-pub async fn load_texture(
-    file_name: &str,
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-) -> anyhow::Result<texture::Texture> {
-    let data = load_binary(file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name)
+    fn read(&self, file_name: &str) -> Result<Option<Vec<u8>>, AssetError> {
+        let mut archive = self.archive.lock().unwrap();
+
+        let mut entry = match archive.by_name(file_name) {
+            Ok(entry) => entry,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(AssetError::io(file_name, e)),
+        };
+
+        // grown as it's read rather than sized from the header, which a
+        // corrupt archive can make up
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(|e| AssetError::io(file_name, e))?;
+
+        Ok(Some(data))
+    }
 }
 
-pub async fn load_model(
-    file_name: &str,
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    layout: &wgpu::BindGroupLayout,
-) -> anyhow::Result<model::Model> {
-    let obj_text = load_string(file_name).await?;
+/// A Quake `.pak` archive: a "PACK" header pointing at a flat directory
+/// of 64 byte entries (56 byte name, offset, length) with no compression.
+pub struct PakSource {
+    name: String,
+    file: Mutex<std::fs::File>,
+    entries: AHashMap<String, (u64, usize)>,
+}
+
+impl PakSource {
+    const ENTRY_SIZE: usize = 64;
+    const NAME_SIZE: usize = 56;
+
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let file_length = file.metadata()?.len();
+        // so a corrupt header can't make us allocate more than is there
+        let fits = |offset: i32, length: i32| {
+            offset >= 0 && length >= 0 && offset as u64 + length as u64 <= file_length
+        };
+
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)?;
+
+        if &header[0..4] != b"PACK" {
+            bail!("{} is not a pak file", path.display());
+        }
+
+        let dir_offset = i32::from_le_bytes(header[4..8].try_into()?);
+        let dir_length = i32::from_le_bytes(header[8..12].try_into()?);
+
+        if !fits(dir_offset, dir_length) || !(dir_length as usize).is_multiple_of(Self::ENTRY_SIZE) {
+            bail!("{} has a corrupt directory", path.display());
+        }
+
+        let mut directory = vec![0u8; dir_length as usize];
+        file.seek(SeekFrom::Start(dir_offset as u64))?;
+        file.read_exact(&mut directory)?;
+
+        let mut entries = AHashMap::new();
+
+        for entry in directory.chunks_exact(Self::ENTRY_SIZE) {
+            let name = &entry[..Self::NAME_SIZE];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(Self::NAME_SIZE)];
+            let name = String::from_utf8_lossy(name).to_string();
+
+            let offset = i32::from_le_bytes(entry[56..60].try_into()?);
+            let length = i32::from_le_bytes(entry[60..64].try_into()?);
+
+            if !fits(offset, length) {
+                bail!("{} has a corrupt entry for {}", path.display(), name);
+            }
+
+            // the engine keeps the first entry when a name is duplicated
+            entries.entry(name).or_insert((offset as u64, length as usize));
+        }
+
+        Ok(Self {
+            name: path.display().to_string(),
+            file: Mutex::new(file),
+            entries,
+        })
+    }
+}
+
+impl AssetSource for PakSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, file_name: &str) -> Result<Option<Vec<u8>>, AssetError> {
+        let (offset, length) = match self.entries.get(file_name) {
+            Some(&entry) => entry,
+            None => return Ok(None),
+        };
+
+        let mut file = self.file.lock().unwrap();
+        let mut data = vec![0u8; length];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(|e| AssetError::io(file_name, e))?;
+
+        Ok(Some(data))
+    }
+}
+
+static SEARCH_PATH: RwLock<Vec<Box<dyn AssetSource>>> = RwLock::new(Vec::new());
+
+/// Adds a source to the top of the search path.
+pub fn mount(source: Box<dyn AssetSource>) {
+    log::info!("mounted {}", source.name());
+    SEARCH_PATH.write().unwrap().push(source);
+}
+
+/// Mounts `path` as a directory or, for `.pak`, `.zip` and `.pk3` files,
+/// as an archive.
+///
+/// Mounting a directory also mounts the archives inside it the way Quake
+/// sets up a game directory: `pak0.pak`, `pak1.pak`, ... until one is
+/// missing, then any `.pk3` files in alphabetical order. Each of these
+/// overrides the loose files and the archives mounted before it.
+pub fn mount_path(path: &Path) -> anyhow::Result<()> {
+    if path.is_dir() {
+        mount(Box::new(DirectorySource::new(path)));
+        mount_archives_in(path)?;
+        return Ok(());
+    }
+
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("pak") => mount(Box::new(PakSource::open(path)?)),
+        Some("zip") | Some("pk3") => mount(Box::new(ZipSource::open(path)?)),
+        _ => bail!("{} is not a directory or a supported archive", path.display()),
+    }
+
+    Ok(())
+}
+
+fn mount_archives_in(dir: &Path) -> anyhow::Result<()> {
+    for i in 0.. {
+        let pak = dir.join(format!("pak{}.pak", i));
+        if !pak.is_file() {
+            break;
+        }
+        mount(Box::new(PakSource::open(&pak)?));
+    }
+
+    let mut pk3s = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file() && p.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.eq_ignore_ascii_case("pk3"))
+        })
+        .collect::<Vec<_>>();
+    pk3s.sort();
+
+    for pk3 in pk3s {
+        mount(Box::new(ZipSource::open(&pk3)?));
+    }
+
+    Ok(())
+}
+
+/// Builds the search path from the configured asset roots.
+///
+/// When nothing was configured we fall back to a `res` directory next to
+/// the executable and finally to the copy `build.rs` made in `OUT_DIR`,
+/// which is what you get with a plain `cargo run`.
+pub fn init(roots: &[PathBuf]) -> anyhow::Result<()> {
+    if !roots.is_empty() {
+        for root in roots {
+            mount_path(root)?;
+        }
+        return Ok(());
+    }
+
+    let beside_exe = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("res")))
+        .filter(|dir| dir.is_dir());
+
+    match beside_exe {
+        Some(dir) => mount_path(&dir),
+        None => mount_path(&Path::new(env!("OUT_DIR")).join("res")),
+    }
+}
+
+/// Reads `file_name` from the first source in the search path that has it.
+pub fn load_binary(file_name: &str) -> Result<Vec<u8>, AssetError> {
+    let search_path = SEARCH_PATH.read().unwrap();
+
+    for source in search_path.iter().rev() {
+        if let Some(data) = source.read(file_name)? {
+            return Ok(data);
+        }
+    }
+
+    Err(AssetError::Missing { path: file_name.to_string() })
+}
+
+pub fn load_string(file_name: &str) -> Result<String, AssetError> {
+    let data = load_binary(file_name)?;
+
+    String::from_utf8(data).map_err(|e| {
+        let offset = e.utf8_error().valid_up_to();
+        AssetError::parse_at(file_name, e.as_bytes(), offset, "invalid UTF-8")
+    })
+}
+
+pub fn decode_image(file_name: &str) -> Result<image::DynamicImage, AssetError> {
+    let data = load_binary(file_name)?;
+
+    image::load_from_memory(&data).map_err(|e| AssetError::image(file_name, e))
+}
+
+/// Like [`decode_image`], but logs the error and returns the placeholder
+/// checkerboard instead of failing.
+pub fn decode_image_or_placeholder(file_name: &str) -> image::DynamicImage {
+    decode_image(file_name).unwrap_or_else(|e| {
+        log::warn!("{}, using a placeholder", e);
+        texture::Texture::placeholder_image()
+    })
+}
+
+/// Parses an OBJ file and decodes its textures without touching the GPU,
+/// so it can run on a worker thread. See [`upload_model`].
+pub fn parse_model(file_name: &str) -> Result<model::ModelData, AssetError> {
+    let obj_text = load_string(file_name)?;
     let obj_cursor = Cursor::new(obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
 
-    let (models, obj_materials) = tobj::load_obj_buf_async(
+    // tobj only lets the material callback return its own error codes, so
+    // keep ours around to report instead
+    let mtl_error = std::cell::RefCell::new(None);
+
+    let (models, obj_materials) = tobj::load_obj_buf(
         &mut obj_reader,
         &tobj::LoadOptions {
             triangulate: true,
             single_index: true,
             ..Default::default()
         },
-        |p| async move {
-            let mat_text = load_string(&p).await.unwrap();
+        |p| {
+            let mtl_path = p.to_string_lossy();
+            let mat_text = load_string(&mtl_path).map_err(|e| {
+                *mtl_error.borrow_mut() = Some(e);
+                tobj::LoadError::OpenFileFailed
+            })?;
             tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
         },
-    )
-    .await?;
+    ).map_err(|e| AssetError::parse(file_name, e))?;
+
+    let obj_materials = obj_materials.map_err(|e| match mtl_error.take() {
+        Some(mtl_error) => mtl_error,
+        None => AssetError::parse(file_name, e),
+    })?;
 
     let mut materials = Vec::new();
 
-    if obj_materials?.is_empty() {
-        let diffuse_texture = load_texture("no_texture.png", device, queue).await?;
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-            ],
-            label: None,
-        });
-
-        materials.push(model::Material {
-            name: "no_material".to_string(),
-
-            diffuse_texture,
-
-            bind_group,
-        })
+    if obj_materials.is_empty() {
+        materials.push(model::MaterialData::unlit(
+            "no_material".to_string(),
+            "no_texture.png".to_string(),
+            decode_image_or_placeholder("no_texture.png"),
+        ))
     };
 
 
-    // for m in obj_materials? {
-    //     let diffuse_texture = load_texture(&m.diffuse_texture, device, queue).await?;
-    //     let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-    //         layout,
-    //         entries: &[
-    //             wgpu::BindGroupEntry {
-    //                 binding: 0,
-    //                 resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-    //             },
-    //             wgpu::BindGroupEntry {
-    //                 binding: 1,
-    //                 resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-    //             },
-    //         ],
-    //         label: None,
-    //     });
-
-    //     materials.push(model::Material {
-    //         name: m.name,
-    //         diffuse_texture,
-    //         bind_group,
-    //     })
-    // }
+    for m in obj_materials {
+        // materials without a texture get the default one, missing or
+        // broken textures the checkerboard
+        let diffuse_path = if m.diffuse_texture.is_empty() {
+            "no_texture.png".to_string()
+        } else {
+            m.diffuse_texture
+        };
+
+        // the PBR extension to MTL: `Pr` roughness and `Pm` metallic
+        let param = |key: &str| m.unknown_param.get(key).and_then(|value| value.trim().parse::<f32>().ok());
+        let (roughness, metallic) = (param("Pr"), param("Pm"));
+
+        let mut material = model::MaterialData::unlit(m.name, diffuse_path.clone(), decode_image_or_placeholder(&diffuse_path));
+
+        if roughness.is_some() || metallic.is_some() {
+            material.shading = model::Shading::Pbr;
+            material.roughness = roughness.unwrap_or(1.0).clamp(0.0, 1.0);
+            material.metallic = metallic.unwrap_or(0.0).clamp(0.0, 1.0);
+        }
+
+        // some exporters write transparency `Tr` instead of dissolve `d`
+        material.alpha = match param("Tr") {
+            Some(transparency) if m.dissolve == 1.0 => 1.0 - transparency,
+            _ => m.dissolve,
+        }.clamp(0.0, 1.0);
+
+        // Quake marks alpha masked textures with a leading `{`
+        let masked = Path::new(&material.diffuse_path)
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('{'));
+
+        material.alpha_mode = if material.alpha < 1.0 {
+            model::AlphaMode::Blend
+        } else if masked {
+            model::AlphaMode::Mask
+        } else {
+            model::AlphaMode::Opaque
+        };
+
+        materials.push(material);
+    }
 
     let meshes = models
         .into_iter()
@@ -174,30 +457,247 @@ pub async fn load_model(
                         m.mesh.positions[i * 3 + 2],
                     ],
                     tex_coords: texcoord,
-                    normal: normal,
+                    normal,
                 } })
                 .collect::<Vec<_>>();
 
+            model::MeshData {
+                name: file_name.to_string(),
+                vertices,
+                indices: m.mesh.indices,
+                material: m.mesh.material_id.unwrap_or(0),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(model::ModelData { meshes, materials })
+}
+
+/// Creates the GPU buffers and bind groups for parsed model data. Textures
+/// go through `textures` so materials sharing an image share the upload.
+pub fn upload_model(
+    file_name: &str,
+    data: &model::ModelData,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    registry: &Registry,
+    textures: &mut assets::Cache<texture::Texture>,
+) -> Result<model::Model, AssetError> {
+    let materials = data.materials
+        .iter()
+        .map(|m| upload_material(m, device, queue, registry, textures))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let meshes = data.meshes
+        .iter()
+        .map(|m| {
             let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
+                contents: bytemuck::cast_slice(&m.vertices),
                 usage: wgpu::BufferUsages::VERTEX,
             });
             let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
+                contents: bytemuck::cast_slice(&m.indices),
                 usage: wgpu::BufferUsages::INDEX,
             });
 
             model::Mesh {
-                name: file_name.to_string(),
+                name: m.name.clone(),
                 vertex_buffer,
                 index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+                num_elements: m.indices.len() as u32,
+                material: m.material,
+                bounds: model::Bounds::from_vertices(&m.vertices),
             }
         })
         .collect::<Vec<_>>();
 
     Ok(model::Model { meshes, materials })
 }
+
+/// Uploads a material's texture through `textures`, falling back to the
+/// placeholder, and creates its uniform buffer and bind group.
+pub fn upload_material(
+    m: &model::MaterialData,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    registry: &Registry,
+    textures: &mut assets::Cache<texture::Texture>,
+) -> Result<model::Material, AssetError> {
+    let diffuse_texture = match textures.get_or_upload(&m.diffuse_path, &m.diffuse_image, device, queue) {
+        Ok(handle) => handle,
+        Err(e) => {
+            log::warn!("{}, using a placeholder", e);
+            let placeholder = texture::Texture::placeholder_image();
+            textures.get_or_upload("<placeholder>", &placeholder, device, queue)?
+        }
+    };
+    let texture = diffuse_texture.get().unwrap();
+    // the texture is shared by every material using its path, the
+    // addressing is the material's own
+    let sampler = texture::Texture::image_sampler(device, m.address_mode);
+
+    let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{:?} Material Buffer", m.name)),
+        contents: bytemuck::cast_slice(&[model::MaterialUniform::new(m)]),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &registry.layout(registry::MATERIAL),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+        ],
+        label: None,
+    });
+
+    Ok(model::Material {
+        name: m.name.clone(),
+
+        diffuse_texture,
+        shading: m.shading,
+        alpha_mode: m.alpha_mode,
+        uniform_buffer,
+
+        bind_group,
+    })
+}
+
+/// A textured unit cube drawn in place of models that failed to load.
+pub fn placeholder_model() -> model::ModelData {
+    // one face per axis direction, as (normal, tangent, bitangent)
+    let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+        ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+        ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ];
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+
+    for (n, t, b) in faces {
+        let base = vertices.len() as u32;
+
+        for (u, v) in [(0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)] {
+            let (su, sv) = (u * 2.0 - 1.0, 1.0 - v * 2.0);
+            let position = [0, 1, 2].map(|i| (n[i] + t[i] * su + b[i] * sv) * 0.5);
+
+            vertices.push(model::ModelVertex {
+                position,
+                tex_coords: [u, v],
+                normal: n,
+            });
+        }
+
+        indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+
+    model::ModelData {
+        meshes: vec![model::MeshData {
+            name: "placeholder".to_string(),
+            vertices,
+            indices,
+            material: 0,
+        }],
+        materials: vec![model::MaterialData::unlit(
+            "placeholder".to_string(),
+            "<placeholder>".to_string(),
+            texture::Texture::placeholder_image(),
+        )],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a pak holding `files` in order to the temp directory.
+    fn write_pak(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+
+        for (name, contents) in files {
+            let mut entry = [0u8; PakSource::ENTRY_SIZE];
+            entry[..name.len()].copy_from_slice(name.as_bytes());
+            entry[56..60].copy_from_slice(&(12 + data.len() as i32).to_le_bytes());
+            entry[60..64].copy_from_slice(&(contents.len() as i32).to_le_bytes());
+            directory.extend_from_slice(&entry);
+            data.extend_from_slice(contents);
+        }
+
+        let mut pak = b"PACK".to_vec();
+        pak.extend_from_slice(&(12 + data.len() as i32).to_le_bytes());
+        pak.extend_from_slice(&(directory.len() as i32).to_le_bytes());
+        pak.extend_from_slice(&data);
+        pak.extend_from_slice(&directory);
+
+        let path = std::env::temp_dir().join(format!("{}-{}.pak", test, std::process::id()));
+        std::fs::write(&path, pak).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_the_directory() {
+        let path = write_pak("reads_the_directory", &[
+            ("maps/start.bsp", b"level"),
+            ("gfx/palette.lmp", b"colors"),
+        ]);
+        let pak = PakSource::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pak.read("maps/start.bsp").unwrap().as_deref(), Some(&b"level"[..]));
+        assert_eq!(pak.read("gfx/palette.lmp").unwrap().as_deref(), Some(&b"colors"[..]));
+        assert_eq!(pak.read("maps/e1m1.bsp").unwrap(), None);
+    }
+
+    #[test]
+    fn directories_stay_inside_their_root() {
+        let root = std::env::temp_dir().join(format!("directory-root-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("maps")).unwrap();
+        std::fs::write(root.join("maps/start.map"), b"level").unwrap();
+        let outside = root.with_extension("secret");
+        std::fs::write(&outside, b"secret").unwrap();
+
+        let source = DirectorySource::new(&root);
+        let file_name = outside.file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(source.read("maps/start.map").unwrap().as_deref(), Some(&b"level"[..]));
+        for name in [
+            format!("../{}", file_name),
+            format!("maps/../../{}", file_name),
+            outside.display().to_string(),
+        ] {
+            assert!(matches!(source.read(&name), Err(AssetError::Missing { .. })), "{}", name);
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(&outside).unwrap();
+    }
+
+    #[test]
+    fn first_duplicate_wins() {
+        let path = write_pak("first_duplicate_wins", &[
+            ("progs.dat", b"first"),
+            ("progs.dat", b"second"),
+        ]);
+        let pak = PakSource::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pak.read("progs.dat").unwrap().as_deref(), Some(&b"first"[..]));
+    }
+}
@@ -1,26 +1,22 @@
 // Vertex shader
 
-struct InstanceInput {
-    @location(5) model_matrix_0: vec4<f32>,
-    @location(6) model_matrix_1: vec4<f32>,
-    @location(7) model_matrix_2: vec4<f32>,
-    @location(8) model_matrix_3: vec4<f32>,
-};
+#include "instance.wgsl"
+#include "camera.wgsl"
 
-struct CameraUniform {
-    view_proj: mat4x4<f32>,
-};
 @group(1) @binding(0)
 var<uniform> camera: CameraUniform;
 
 struct VertexInput {
 	@location(0) position: vec3<f32>,
 	@location(1) tex_coords: vec2<f32>,
+	@location(2) normal: vec3<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) world_position: vec3<f32>,
 };
 
 @vertex
@@ -35,7 +31,11 @@ fn vs_main( model: VertexInput, instance: InstanceInput, ) -> VertexOutput {
 
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    // instances are only rotated and moved, so no inverse transpose
+    out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.world_position = world_position.xyz;
+    out.clip_position = camera.view_proj * world_position;
 
     return out;
 
@@ -46,9 +46,176 @@ var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
 
+// Material parameters, see model.rs. `shading` is compiled in as the PBR
+// define rather than read here
+
+struct MaterialUniform {
+    shading: u32,
+    metallic: f32,
+    roughness: f32,
+    alpha: f32,
+};
+@group(0) @binding(2)
+var<uniform> material: MaterialUniform;
+
+#ifdef PBR
+
+// Image based lighting, see ibl.rs
+
+@group(3) @binding(0)
+var t_irradiance: texture_cube<f32>;
+@group(3) @binding(1)
+var t_prefiltered: texture_cube<f32>;
+@group(3) @binding(2)
+var t_brdf_lut: texture_2d<f32>;
+@group(3) @binding(3)
+var s_ibl: sampler;
+
+// highest mip of the prefiltered cubemap, PREFILTERED_MIPS - 1 in ibl.rs
+let MAX_REFLECTION_LOD: f32 = 4.0;
+
+let PI: f32 = 3.14159265;
+
+// A fixed key light so PBR materials have highlights before maps bring
+// their own lights
+let SUN_DIRECTION: vec3<f32> = vec3<f32>(0.3, 1.0, 0.5);
+let SUN_COLOR: vec3<f32> = vec3<f32>(2.0, 1.9, 1.7);
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Cook-Torrance metallic-roughness shading lit by the sun and the
+// environment
+fn shade_pbr(albedo: vec3<f32>, n: vec3<f32>, v: vec3<f32>) -> vec3<f32> {
+    // fully smooth surfaces turn the GGX lobe into a singularity
+    let roughness = clamp(material.roughness, 0.04, 1.0);
+    let metallic = material.metallic;
+
+    let f0 = mix(vec3<f32>(0.04), albedo, metallic);
+    let n_dot_v = max(dot(n, v), 0.0001);
+
+    let l = normalize(SUN_DIRECTION);
+    let h = normalize(v + l);
+    let n_dot_l = max(dot(n, l), 0.0);
+    let n_dot_h = max(dot(n, h), 0.0);
+
+    let f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+    let specular = distribution_ggx(n_dot_h, roughness) * geometry_smith(n_dot_v, n_dot_l, roughness) * f
+        / (4.0 * n_dot_v * n_dot_l + 0.0001);
+    let k_d = (vec3<f32>(1.0) - f) * (1.0 - metallic);
+    let direct = (k_d * albedo / PI + specular) * SUN_COLOR * n_dot_l;
+
+    let f_ambient = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let k_d_ambient = (vec3<f32>(1.0) - f_ambient) * (1.0 - metallic);
+    let irradiance = textureSample(t_irradiance, s_ibl, n).rgb;
+    let prefiltered = textureSampleLevel(t_prefiltered, s_ibl, reflect(-v, n), roughness * MAX_REFLECTION_LOD).rgb;
+    let brdf = textureSample(t_brdf_lut, s_ibl, vec2<f32>(n_dot_v, roughness)).rg;
+    let ambient = k_d_ambient * irradiance * albedo + prefiltered * (f_ambient * brdf.x + brdf.y);
+
+    return direct + ambient;
+}
+
+#endif
+
+// Debug views, see debug.rs
+
+struct DebugUniform {
+    mode: u32,
+    znear: f32,
+    zfar: f32,
+    mesh_color: vec4<f32>,
+};
+@group(2) @binding(0)
+var<uniform> debug: DebugUniform;
+
+fn uv_checker(uv: vec2<f32>) -> vec3<f32> {
+    let cell = floor(fract(uv) * 8.0);
+    let dark = (cell.x + cell.y) % 2.0;
+    return mix(vec3<f32>(fract(uv), 1.0), vec3<f32>(0.1, 0.1, 0.1), dark);
+}
+
+fn linear_depth(depth: f32) -> f32 {
+    let z = debug.znear * debug.zfar / (debug.zfar - depth * (debug.zfar - debug.znear));
+    return z / debug.zfar;
+}
+
+// Alpha under which masked materials are discarded
+let ALPHA_CUTOFF: f32 = 0.5;
+
+fn shade(in: VertexOutput) -> vec4<f32> {
+
+    let normal = normalize(in.world_normal);
+
+    switch debug.mode {
+        case 1u: {
+            return vec4<f32>(normal * 0.5 + 0.5, 1.0);
+        }
+        case 2u: {
+            return vec4<f32>(uv_checker(in.tex_coords), 1.0);
+        }
+        case 3u: {
+            return vec4<f32>(vec3<f32>(1.0 - linear_depth(in.clip_position.z)), 1.0);
+        }
+        case 4u: {
+            // a little fake lighting so the shape still reads
+            let shade = 0.6 + 0.4 * abs(dot(normal, normalize(vec3<f32>(0.3, 1.0, 0.5))));
+            return vec4<f32>(debug.mesh_color.rgb * shade, 1.0);
+        }
+        default: {
+            let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+            let alpha = color.a * material.alpha;
+
+#ifdef PBR
+            let v = normalize(camera.view_position.xyz - in.world_position);
+            return vec4<f32>(shade_pbr(color.rgb, normal, v), alpha);
+#else
+            return vec4<f32>(color.rgb, alpha);
+#endif
+        }
+    }
+
+}
+
 @fragment
 fn fs_main( in: VertexOutput ) -> @location(0) vec4<f32> {
 
-	return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+#ifdef ALPHA_MASK
+    // tested on the texture so the debug views keep the cutouts
+    let alpha = textureSample(t_diffuse, s_diffuse, in.tex_coords).a * material.alpha;
+    if alpha < ALPHA_CUTOFF {
+        discard;
+    }
+
+    return vec4<f32>(shade(in).rgb, 1.0);
+#else
+    return shade(in);
+#endif
 
-}
\ No newline at end of file
+}
+
+@fragment
+fn fs_wireframe( in: VertexOutput ) -> @location(0) vec4<f32> {
+
+    return vec4<f32>(1.0, 1.0, 1.0, 1.0);
+
+}
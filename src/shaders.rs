@@ -0,0 +1,242 @@
+use std::collections::BTreeSet;
+
+use ahash::AHashMap;
+
+/// The WGSL sources built into the binary, by the name shaders and
+/// `#include` refer to them with.
+const SOURCES: &[(&str, &str)] = &[
+    ("camera.wgsl", include_str!("camera.wgsl")),
+    ("cube_face.wgsl", include_str!("cube_face.wgsl")),
+    ("debug_lines.wgsl", include_str!("debug_lines.wgsl")),
+    ("equirect.wgsl", include_str!("equirect.wgsl")),
+    ("fullscreen.wgsl", include_str!("fullscreen.wgsl")),
+    ("ibl.wgsl", include_str!("ibl.wgsl")),
+    ("instance.wgsl", include_str!("instance.wgsl")),
+    ("level.wgsl", include_str!("level.wgsl")),
+    ("post.wgsl", include_str!("post.wgsl")),
+    ("shader.wgsl", include_str!("shader.wgsl")),
+    ("sky.wgsl", include_str!("sky.wgsl")),
+];
+
+/// Names a shader is preprocessed with, checked by `#ifdef`. Ordered, so
+/// equal sets compare and hash the same whatever order they were built in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Defines(BTreeSet<&'static str>);
+
+impl Defines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, name: &'static str) -> Self {
+        self.0.insert(name);
+        self
+    }
+}
+
+/// Expands the directives of the built in shader `name`, each on a line of
+/// its own:
+///
+/// - `#include "file.wgsl"` pastes in another built in source, once per
+///   shader however often it's included
+/// - `#define NAME` defines `NAME` for the rest of the shader
+/// - `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif` keep or drop the
+///   lines between them, and nest
+pub fn preprocess(name: &str, defines: &Defines) -> anyhow::Result<String> {
+    preprocess_sources(SOURCES, name, defines)
+}
+
+/// [`preprocess`] with `sources` in place of the built in ones.
+fn preprocess_sources(sources: &[(&str, &str)], name: &str, defines: &Defines) -> anyhow::Result<String> {
+    let mut preprocessor = Preprocessor {
+        sources,
+        defines: defines.0.iter().map(|name| name.to_string()).collect(),
+        included: Vec::new(),
+        output: String::new(),
+    };
+
+    preprocessor.expand(name)?;
+    Ok(preprocessor.output)
+}
+
+struct Preprocessor<'a> {
+    sources: &'a [(&'a str, &'a str)],
+    defines: BTreeSet<String>,
+    included: Vec<String>,
+    output: String,
+}
+
+/// An `#ifdef` or `#ifndef` being expanded.
+struct Conditional {
+    /// Whether the lines before `#else` are kept.
+    condition: bool,
+    in_else: bool,
+    /// Whether every conditional around this one keeps its lines.
+    outer_active: bool,
+}
+
+impl Conditional {
+    fn active(&self) -> bool {
+        self.outer_active && self.condition != self.in_else
+    }
+}
+
+impl Preprocessor<'_> {
+    fn expand(&mut self, name: &str) -> anyhow::Result<()> {
+        if self.included.iter().any(|included| included == name) {
+            return Ok(());
+        }
+        self.included.push(name.to_string());
+
+        let source = self.sources.iter()
+            .find(|(source_name, _)| *source_name == name)
+            .map(|(_, source)| *source)
+            .ok_or_else(|| anyhow::anyhow!("there's no shader called {}", name))?;
+
+        let mut conditionals: Vec<Conditional> = Vec::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let at = || format!("{}:{}", name, i + 1);
+            let active = conditionals.last().is_none_or(Conditional::active);
+
+            let Some(directive) = line.trim_start().strip_prefix('#') else {
+                if active {
+                    self.output.push_str(line);
+                    self.output.push('\n');
+                }
+                continue;
+            };
+
+            let mut words = directive.split_whitespace();
+            let (keyword, argument) = (words.next().unwrap_or_default(), words.next());
+
+            match (keyword, argument) {
+                ("ifdef" | "ifndef", Some(define)) => conditionals.push(Conditional {
+                    condition: self.defines.contains(define) == (keyword == "ifdef"),
+                    in_else: false,
+                    outer_active: active,
+                }),
+
+                ("else", None) => match conditionals.last_mut() {
+                    Some(conditional) if !conditional.in_else => conditional.in_else = true,
+                    _ => anyhow::bail!("{}: #else without #ifdef", at()),
+                },
+
+                ("endif", None) => {
+                    conditionals.pop().ok_or_else(|| anyhow::anyhow!("{}: #endif without #ifdef", at()))?;
+                }
+
+                ("define", Some(define)) => {
+                    if active {
+                        self.defines.insert(define.to_string());
+                    }
+                }
+
+                ("include", Some(file)) => {
+                    let file = file.strip_prefix('"')
+                        .and_then(|file| file.strip_suffix('"'))
+                        .ok_or_else(|| anyhow::anyhow!("{}: #include needs a quoted file name", at()))?;
+
+                    if active {
+                        self.expand(file).map_err(|e| anyhow::anyhow!("{}: {}", at(), e))?;
+                    }
+                }
+
+                _ => anyhow::bail!("{}: can't read directive #{}", at(), directive.trim()),
+            }
+        }
+
+        if !conditionals.is_empty() {
+            anyhow::bail!("{}: #ifdef without #endif", name);
+        }
+
+        Ok(())
+    }
+}
+
+/// Compiles the built in shader `name` with `defines`. The sources are
+/// part of the binary, so a directive error is a bug and panics, like wgpu
+/// does for invalid WGSL.
+pub fn create_module(device: &wgpu::Device, name: &str, defines: &Defines) -> wgpu::ShaderModule {
+    let source = preprocess(name, defines).unwrap_or_else(|e| panic!("{}", e));
+
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(name),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    })
+}
+
+/// Shader modules by source and defines, so each permutation is compiled
+/// once.
+#[derive(Default)]
+pub struct ShaderCache {
+    modules: AHashMap<(&'static str, Defines), wgpu::ShaderModule>,
+}
+
+impl ShaderCache {
+    pub fn get(&mut self, device: &wgpu::Device, name: &'static str, defines: &Defines) -> &wgpu::ShaderModule {
+        self.modules
+            .entry((name, defines.clone()))
+            .or_insert_with(|| create_module(device, name, defines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NESTED: &str = "\
+a
+#ifdef A
+b
+#ifdef B
+c
+#else
+d
+#endif
+#else
+e
+#endif
+f
+";
+
+    #[test]
+    fn nested_conditionals() {
+        let sources = [("nested.wgsl", NESTED)];
+        let expand = |defines| preprocess_sources(&sources, "nested.wgsl", &defines).unwrap();
+
+        assert_eq!(expand(Defines::new()), "a\ne\nf\n");
+        assert_eq!(expand(Defines::new().with("A")), "a\nb\nd\nf\n");
+        assert_eq!(expand(Defines::new().with("A").with("B")), "a\nb\nc\nf\n");
+        // the inner #else belongs to B, which is skipped with the rest of A
+        assert_eq!(expand(Defines::new().with("B")), "a\ne\nf\n");
+    }
+
+    #[test]
+    fn includes_once() {
+        let sources = [
+            ("main.wgsl", "#include \"common.wgsl\"\n#include \"common.wgsl\"\nmain\n"),
+            ("common.wgsl", "common\n"),
+        ];
+
+        assert_eq!(preprocess_sources(&sources, "main.wgsl", &Defines::new()).unwrap(), "common\nmain\n");
+    }
+
+    #[test]
+    fn unbalanced_conditionals() {
+        let error = |source| preprocess_sources(&[("bad.wgsl", source)], "bad.wgsl", &Defines::new())
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(error("a\n#endif\n"), "bad.wgsl:2: #endif without #ifdef");
+        assert_eq!(error("#ifdef A\n#else\n#else\n#endif\n"), "bad.wgsl:3: #else without #ifdef");
+        assert_eq!(error("#ifdef A\na\n"), "bad.wgsl: #ifdef without #endif");
+    }
+
+    #[test]
+    fn built_in_shaders_expand() {
+        for (name, _) in SOURCES {
+            preprocess(name, &Defines::new()).unwrap();
+        }
+    }
+}
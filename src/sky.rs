@@ -0,0 +1,207 @@
+use std::rc::Rc;
+
+use wgpu::util::DeviceExt;
+
+use crate::{
+    camera::Camera,
+    error::AssetError,
+    post::HDR_FORMAT,
+    registry::{RenderPipelineDesc, Registry},
+    resources,
+    shaders,
+    texture::Texture,
+};
+
+/// The name `Skybox` registers its bind group layout under.
+const LAYOUT: &str = "sky";
+
+/// Suffixes of a six image skybox in cube layer order, +X, -X, +Y, -Y, +Z
+/// and -Z, following the Quake 2 `env/` naming.
+const FACE_SUFFIXES: [&str; 6] = ["rt", "lf", "up", "dn", "bk", "ft"];
+
+const FACE_EXTENSIONS: [&str; 3] = ["png", "tga", "jpg"];
+
+/// The images of a skybox, decoded and waiting to be uploaded.
+pub enum CubemapImages {
+    /// An equirectangular panorama.
+    Panorama { path: String, image: image::DynamicImage },
+    /// Six faces in cube layer order.
+    Faces { name: String, faces: [image::DynamicImage; 6] },
+}
+
+impl CubemapImages {
+    /// Decodes the skybox called `name`, either the panorama
+    /// `env/<name>.hdr` or six faces `env/<name><suffix>` as PNG, TGA or
+    /// JPEG. Doesn't touch the GPU, so it can run on an asset worker.
+    pub fn decode(name: &str) -> Result<Self, AssetError> {
+        let panorama = format!("env/{}.hdr", name);
+
+        match resources::decode_image(&panorama) {
+            Ok(image) => return Ok(Self::Panorama { path: panorama, image }),
+            Err(AssetError::Missing { .. }) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut faces = Vec::with_capacity(6);
+
+        for suffix in FACE_SUFFIXES {
+            let face = FACE_EXTENSIONS.iter()
+                .map(|extension| resources::decode_image(&format!("env/{}{}.{}", name, suffix, extension)))
+                .find(|result| !matches!(result, Err(AssetError::Missing { .. })))
+                .unwrap_or_else(|| Err(AssetError::Missing { path: format!("env/{}{}", name, suffix) }))?;
+
+            faces.push(face);
+        }
+
+        Ok(Self::Faces {
+            name: format!("env/{}", name),
+            faces: faces.try_into().unwrap(),
+        })
+    }
+
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        registry: &mut Registry,
+    ) -> Result<Texture, AssetError> {
+        match self {
+            Self::Panorama { path, image } => Texture::cubemap_from_equirect(device, queue, registry, image, path),
+            Self::Faces { name, faces } => Texture::cubemap_from_faces(device, queue, faces, name),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyUniform {
+    inv_view_proj: [[f32; 4]; 4],
+}
+
+/// A cubemap drawn on the far plane of the scene pass, behind everything
+/// drawn before it.
+pub struct Skybox {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    pipeline: Rc<wgpu::RenderPipeline>,
+}
+
+impl Skybox {
+    pub fn new(device: &wgpu::Device, registry: &mut Registry, cubemap: &Texture, sample_count: u32) -> Self {
+        use cgmath::SquareMatrix;
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sky Buffer"),
+            contents: bytemuck::cast_slice(&[SkyUniform {
+                inv_view_proj: cgmath::Matrix4::identity().into(),
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group_layout = registry.bind_group_layout(device, LAYOUT, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::Cube,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]);
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&cubemap.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&cubemap.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("sky_bind_group"),
+        });
+
+        let pipeline = registry.render_pipeline(device, &Self::pipeline_desc(sample_count));
+
+        Self {
+            buffer,
+            bind_group,
+            pipeline,
+        }
+    }
+
+    /// Switches to the pipeline for targets with a different MSAA sample
+    /// count.
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, registry: &mut Registry, sample_count: u32) {
+        self.pipeline = registry.render_pipeline(device, &Self::pipeline_desc(sample_count));
+    }
+
+    fn pipeline_desc(sample_count: u32) -> RenderPipelineDesc {
+        RenderPipelineDesc {
+            label: "Sky Pipeline",
+            shader: "sky.wgsl",
+            defines: shaders::Defines::new(),
+            bind_group_layouts: vec![LAYOUT],
+            vertex_entry: "vs_main",
+            vertex_buffers: Vec::new(),
+            fragment_entry: "fs_main",
+            targets: vec![Some(wgpu::ColorTargetState {
+                format: HDR_FORMAT,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            primitive: wgpu::PrimitiveState::default(),
+            // the triangle sits on the far plane, so only pixels nothing
+            // else was drawn to pass
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera) {
+        let uniform = SkyUniform {
+            inv_view_proj: camera.build_sky_matrix().into(),
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
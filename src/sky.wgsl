@@ -0,0 +1,38 @@
+// Skybox drawn behind the scene from a cubemap
+
+struct Sky {
+    inv_view_proj: mat4x4<f32>,
+};
+
+@group(0) @binding(0)
+var t_sky: texture_cube<f32>;
+@group(0) @binding(1)
+var s_sky: sampler;
+@group(0) @binding(2)
+var<uniform> sky: Sky;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+// One triangle covering the screen on the far plane, so anything drawn
+// before it hides it
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    let ndc = uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(ndc, 1.0, 1.0);
+    out.ndc = ndc;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let far = sky.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let dir = far.xyz / far.w;
+
+    return vec4<f32>(textureSample(t_sky, s_sky, dir).rgb, 1.0);
+}
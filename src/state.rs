@@ -1,22 +1,39 @@
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::Instant;
+
+use ahash::AHashMap;
 use winit::window::Window;
 use winit::event::*;
+use winit::event_loop::EventLoopWindowTarget;
 
 use wgpu::util::DeviceExt;
 
-use cgmath::prelude::*;
-
 use crate::{
 	texture::*,
 	camera::*,
 	instance::*,
 	model::*,
+	assets::*,
 	resources::*,
+	error::InitError,
+	adapter::*,
+	config::Config,
+	debug::{self, *},
+	events::Event,
+	game::{Collider, Game, Light, Renderable, Script},
+	input::{self, Bindings, Input},
+	ui::*,
+	profiler::Profiler,
+	graph::*,
+	post::*,
+	sky::*,
+	ibl::{self, Environment},
+	level::*,
+	registry::{self, RenderPipelineDesc, Registry},
+	shaders::Defines,
 };
 
-const NUM_INSTANCES_PER_ROW: u32 = 10;
-const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(NUM_INSTANCES_PER_ROW as f32 * 0.5, 0.0, NUM_INSTANCES_PER_ROW as f32 * 0.5);
-const SPACE_BETWEEN: f32 = 3.0;
-
 // #[repr(C)]
 // #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 // struct Vertex {
@@ -53,12 +70,22 @@ const SPACE_BETWEEN: f32 = 3.0;
 //     Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.2652641], }, // E
 // ];
 
+/// Behind the scene where there's no skybox.
+const CLEAR_COLOR: wgpu::Color = wgpu::Color { r: 0.1, g: 0.2, b: 0.3, a: 1.0 };
 
-const INDICES: &[u16] = &[
-    0, 1, 4,
-    1, 2, 4,
-    2, 3, 4,
-];
+/// Instances of one model, a range of the frame's instance buffer.
+struct Batch {
+	model: Handle<Model>,
+	instances: Range<u32>,
+}
+
+/// The passes of a frame, see `State::record`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pass {
+	Scene,
+	Post(PostPass),
+	Ui,
+}
 
 pub struct State {
 	surface: wgpu::Surface,
@@ -66,50 +93,57 @@ pub struct State {
 	queue: wgpu::Queue,
 	config: wgpu::SurfaceConfiguration,
 	pub size: winit::dpi::PhysicalSize<u32>,
-	render_pipeline: wgpu::RenderPipeline,
+	pipelines: ModelPipelines,
+	sample_count: u32,
+	/// MSAA sample counts the adapter supports for the scene targets.
+	sample_counts: Vec<u32>,
 	// vertex_buffer: wgpu::Buffer,
 	// num_vertices: u32,
     // index_buffer: wgpu::Buffer, 
     // num_indices: u32,
     diffuse_bind_group: wgpu::BindGroup,
-    diffuse_texture: Texture,
-    camera: Camera,
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
-    camera_controller: CameraController,
-	instances: Vec<Instance>,
+	/// The renderables of this frame, grouped by model.
+	batches: Vec<Batch>,
 	instance_buffer: wgpu::Buffer,
-	depth_texture: Texture,
-	obj_model: Model,
+	/// Blended batches, meshes and instances to draw this frame, farthest
+	/// first.
+	blend_order: Vec<(usize, usize, u32)>,
+	graph: RenderGraph<Pass>,
+	post: PostProcess,
+	registry: Registry,
+	assets: AssetManager,
+	placeholder_model: Model,
+	debug: DebugShading,
+	debug_lines: DebugLines,
+	environment: Environment,
+	/// Drawn when the map names a sky, otherwise the clear color shows.
+	sky: Option<Skybox>,
+	/// The map's brushes, when one was given and it compiled.
+	level: Option<Level>,
+	game: Game,
+	input: Input,
+	overlay: Overlay,
+	frame_stats: FrameStats,
+	last_frame: Instant,
+	profiler: Profiler,
 }
 
 impl  State {
 	
-	pub async fn new(window: &Window) -> Self {
+	pub async fn new<T>(
+		window: &Window,
+		event_loop: &EventLoopWindowTarget<T>,
+		options: &Config,
+	) -> Result<Self, InitError> {
 		let size = window.inner_size();
 
-		let instance = wgpu::Instance::new(wgpu::Backends::all());
+		let instance = wgpu::Instance::new(options.backends);
 		let surface = unsafe { instance.create_surface(window) };
-		let adapter = instance.request_adapter(
-			&wgpu::RequestAdapterOptions {
-				power_preference: wgpu::PowerPreference::default(),
-				compatible_surface: Some(&surface),
-				force_fallback_adapter: false,
-			}
-		).await.unwrap();
-
-		let (device, queue) = adapter.request_device(
-			
-			&wgpu::DeviceDescriptor {
-				features: wgpu::Features::empty(),
-				limits: wgpu::Limits::default(),
-				label: None,
-			},
-
-			None,
-
-		).await.unwrap();
+		let adapter = select_adapter(&instance, &surface, options.backends, options.adapter).await?;
+		let (device, queue) = request_device(&adapter).await?;
 
 		let config = wgpu::SurfaceConfiguration {
 			usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -123,37 +157,27 @@ impl  State {
 		surface.configure(&device, &config);
 		
 		let diffuse_bytes = include_bytes!("../ferris.png");
-		let diffuse_texture = Texture::from_bytes(&device, &queue, diffuse_bytes, "../ferris.png").unwrap();
-
-		let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        // This should match the filterable field of the
-                        // corresponding Texture entry above.
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("texture_bind_group_layout"),
-            });
+		let diffuse_texture = Texture::from_bytes(&device, &queue, diffuse_bytes, "../ferris.png")?;
+
+		let mut registry = Registry::new(&device);
 
+		let diffuse_material = MaterialUniform {
+			shading: Shading::Unlit as u32,
+			metallic: 0.0,
+			roughness: 1.0,
+			alpha: 1.0,
+		};
+		let diffuse_material_buffer = device.create_buffer_init(
+		    &wgpu::util::BufferInitDescriptor {
+		        label: Some("Diffuse Material Buffer"),
+		        contents: bytemuck::cast_slice(&[diffuse_material]),
+		        usage: wgpu::BufferUsages::UNIFORM,
+		    }
+		);
 
 		let diffuse_bind_group = device.create_bind_group(
 		    &wgpu::BindGroupDescriptor {
-		        layout: &texture_bind_group_layout,
+		        layout: &registry.layout(registry::MATERIAL),
 		        entries: &[
 		            wgpu::BindGroupEntry {
 		                binding: 0,
@@ -162,28 +186,17 @@ impl  State {
 		            wgpu::BindGroupEntry {
 		                binding: 1,
 		                resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+		            },
+		            wgpu::BindGroupEntry {
+		                binding: 2,
+		                resource: diffuse_material_buffer.as_entire_binding(),
 		            }
 		        ],
 		        label: Some("diffuse_bind_group"),
 		    }
 		);
 
-	    let camera = Camera {
-	        // position the camera one unit up and 2 units back
-	        // +z is out of the screen
-	        eye: (0.0, 1.0, 2.0).into(),
-	        // have it look at the origin
-	        target: (0.0, 0.0, 0.0).into(),
-	        // which way is "up"
-	        up: cgmath::Vector3::unit_y(),
-	        aspect: config.width as f32 / config.height as f32,
-	        fovy: 45.0,
-	        znear: 0.1,
-	        zfar: 100.0,
-	    };
-
-	    let mut camera_uniform = CameraUniform::new();
-		camera_uniform.update_view_proj(&camera);
+	    let camera_uniform = CameraUniform::new();
 
 		let camera_buffer = device.create_buffer_init(
 		    &wgpu::util::BufferInitDescriptor {
@@ -193,24 +206,8 @@ impl  State {
 		    }
 		);
 
-		let camera_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-		    entries: &[
-		        wgpu::BindGroupLayoutEntry {
-		            binding: 0,
-		            visibility: wgpu::ShaderStages::VERTEX,
-		            ty: wgpu::BindingType::Buffer {
-		                ty: wgpu::BufferBindingType::Uniform,
-		                has_dynamic_offset: false,
-		                min_binding_size: None,
-		            },
-		            count: None,
-		        }
-		    ],
-		    label: Some("camera_bind_group_layout"),
-		});
-
 		let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-		    layout: &camera_bind_group_layout,
+		    layout: &registry.layout(registry::CAMERA),
 		    entries: &[
 		        wgpu::BindGroupEntry {
 		            binding: 0,
@@ -220,115 +217,71 @@ impl  State {
 		    label: Some("camera_bind_group"),
 		});
 
-		let camera_controller = CameraController::new(0.2);
+		// grown to fit once the renderables are known
+		let instance_buffer = instance_buffer(&device, 1);
 
-		let instances = (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
-		    (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-		        let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-		        let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+		let sample_counts = sample_counts(&adapter, &[HDR_FORMAT, Texture::DEPTH_FORMAT]);
+		let sample_count = sample_counts.iter().rev().cloned().find(|&count| count <= options.msaa).unwrap_or(1);
+		if sample_count != options.msaa {
+			log::warn!("{}x MSAA isn't supported, using {}x", options.msaa, sample_count);
+		}
 
-		        let position = cgmath::Vector3 { x, y: 0.0, z };
+		let mut post = PostProcess::new(&device, &mut registry, config.format);
+		post.settings.fxaa = options.fxaa;
 
-		        let rotation = if position.is_zero() {
-		            cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-		        } else {
-		            cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-		        };
+		let mut graph = build_graph(&post, sample_count);
+		graph.resize(&device, config.width, config.height);
 
-		        Instance {
-		            position, rotation,
-		        }
-		    })
-		}).collect::<Vec<_>>();
+		let debug = DebugShading::new(&device, &mut registry);
 
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-		let instance_buffer = device.create_buffer_init(
-		    &wgpu::util::BufferInitDescriptor {
-		        label: Some("Instance Buffer"),
-		        contents: bytemuck::cast_slice(&instance_data),
-		        usage: wgpu::BufferUsages::VERTEX,
-		    }
+		// lit by the color behind the scene until a level brings a sky
+		let environment = Environment::from_color(
+			&device,
+			&queue,
+			[CLEAR_COLOR.r as f32, CLEAR_COLOR.g as f32, CLEAR_COLOR.b as f32],
+			&mut registry,
 		);
 
-		let depth_texture = Texture::create_depth_texture(&device, &config, "depth_texture");
+		let pipelines = ModelPipelines::new(&device, sample_count);
 
-		let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-		    label: Some("Shader"),
-		    source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
-		});
+		let debug_lines = DebugLines::new(&device, HDR_FORMAT, sample_count, &mut registry);
 
-		let render_pipeline_layout =
-		    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-		        label: Some("Render Pipeline Layout"),
-		        bind_group_layouts: &[
-		        	&texture_bind_group_layout,
-		        	&camera_bind_group_layout,
-		        ],
-		        push_constant_ranges: &[],
-		    });
-
-		let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-		    label: Some("Render Pipeline"),
-		    layout: Some(&render_pipeline_layout),
-		    vertex: wgpu::VertexState {
-		        module: &shader,
-		        entry_point: "vs_main",
-		        buffers: &[
-		        	ModelVertex::desc(), InstanceRaw::desc()
-		        ],
-		    },
-		    fragment: Some(wgpu::FragmentState {
-		        module: &shader,
-		        entry_point: "fs_main",
-		        targets: &[Some(wgpu::ColorTargetState {
-		            format: config.format,
-		            blend: Some(wgpu::BlendState::REPLACE),
-		            write_mask: wgpu::ColorWrites::ALL,
-		        })],
-		    }),
-		    primitive: wgpu::PrimitiveState {
-		        topology: wgpu::PrimitiveTopology::TriangleList,
-		        strip_index_format: None,
-		        front_face: wgpu::FrontFace::Ccw,
-		        cull_mode: Some(wgpu::Face::Back),
-		        // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-		        polygon_mode: wgpu::PolygonMode::Fill,
-		        // Requires Features::DEPTH_CLIP_CONTROL
-		        unclipped_depth: false,
-		        // Requires Features::CONSERVATIVE_RASTERIZATION
-		        conservative: false,
-		    },
-		    depth_stencil: Some(wgpu::DepthStencilState {
-		        format: Texture::DEPTH_FORMAT,
-		        depth_write_enabled: true,
-		        depth_compare: wgpu::CompareFunction::Less, // 1.
-		        stencil: wgpu::StencilState::default(), // 2.
-		        bias: wgpu::DepthBiasState::default(),
-		    }),
-		    multisample: wgpu::MultisampleState {
-		        count: 1,
-		        mask: !0,
-		        alpha_to_coverage_enabled: false,
-		    },
-		    multiview: None,
-		});
+		let overlay = Overlay::new(event_loop, &device, config.format);
+		let profiler = Profiler::new(&device, &queue);
 
-		let obj_model = load_model(
-		    "teapot.obj",
-		    &device,
-		    &queue,
-		    &texture_bind_group_layout,
-		).await.unwrap();
+		let mut assets = AssetManager::new();
+		if let Some(file_name) = &options.map {
+			assets.load_level(file_name);
+		}
 
-		Self {
-			surface, device, queue,
-			config, size, render_pipeline,
-			diffuse_bind_group, diffuse_texture,
-			camera, camera_uniform, camera_buffer, camera_bind_group, camera_controller,
-			instances, instance_buffer,
-			depth_texture, obj_model
+		let game = Game::new(&mut assets, config.width as f32 / config.height as f32);
+		if let Some(camera) = camera(&game) {
+			debug.write(&queue, camera.znear, camera.zfar);
 		}
 
+		let placeholder_model = upload_model(
+			"placeholder",
+			&placeholder_model(),
+			&device,
+			&queue,
+			&registry,
+			&mut assets.textures,
+		)?;
+
+		Ok(Self {
+			surface, device, queue,
+			config, size, pipelines,
+			sample_count, sample_counts,
+			diffuse_bind_group,
+			camera_uniform, camera_buffer, camera_bind_group,
+			batches: Vec::new(), instance_buffer, blend_order: Vec::new(),
+			graph, post, registry,
+			assets, placeholder_model,
+			debug, debug_lines, environment, sky: None, level: None,
+			game, input: Input::new(Bindings::load()), overlay, frame_stats: FrameStats::new(), last_frame: Instant::now(),
+			profiler,
+		})
+
 	}
 
 	pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -337,21 +290,411 @@ impl  State {
 			self.config.width = new_size.width;
 			self.config.height = new_size.height;
 			self.surface.configure(&self.device, &self.config);
-			self.depth_texture = Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+			self.graph.resize(&self.device, new_size.width, new_size.height);
 		}
 	}
 
 	pub fn input(&mut self, event: &WindowEvent) -> bool {
-		self.camera_controller.process_events(event)
+		// the overlay gets first pick so typing into it doesn't move the
+		// camera, but only presses, so nothing stays held once let go over it
+		if self.overlay.on_event(event) {
+			if input::is_release(event) {
+				self.input.window_event(event);
+			}
+			return true;
+		}
+
+		self.input.window_event(event)
+	}
+
+	pub fn device_input(&mut self, event: &DeviceEvent) {
+		self.input.device_event(event);
+	}
+
+	/// Whether the quit action went down this frame.
+	pub fn quit_requested(&self) -> bool {
+		self.input.pressed("quit")
+	}
+
+	/// Runs the debug actions that went down this frame.
+	fn debug_actions(&mut self) {
+		if self.input.pressed("wireframe") {
+			if self.pipelines.wireframe {
+				self.debug.wireframe = !self.debug.wireframe;
+			} else {
+				log::warn!("wireframe needs POLYGON_MODE_LINE, which this adapter doesn't have");
+			}
+		}
+
+		if self.input.pressed("debug_view") {
+			self.debug.mode = self.debug.mode.next();
+			if let Some(camera) = camera(&self.game) {
+				self.debug.write(&self.queue, camera.znear, camera.zfar);
+			}
+			log::info!("debug view {:?}", self.debug.mode);
+		}
+
+		if self.input.pressed("bounds") {
+			self.debug.bounds = !self.debug.bounds;
+		}
+
+		if self.input.pressed("planes") {
+			self.debug.planes = !self.debug.planes;
+		}
+
+		if self.input.pressed("trace") {
+			self.export_trace();
+		}
+
+		if self.input.pressed("overlay") {
+			self.overlay.visible = !self.overlay.visible;
+		}
 	}
 
 	pub fn update(&mut self) {
-		self.camera_controller.update_camera(&mut self.camera);
-    	self.camera_uniform.update_view_proj(&self.camera);
+		let now = Instant::now();
+		let dt = (now - self.last_frame).as_secs_f32();
+		self.frame_stats.push(dt);
+		self.last_frame = now;
+
+		self.assets.poll(&self.device, &self.queue, &mut self.registry, self.sample_count);
+		if let Some(loaded) = self.assets.take_level() {
+			self.set_level(loaded);
+		}
+		self.assets.collect_garbage();
+
+		self.input.poll_gamepads();
+		self.debug_actions();
+		self.game.update(&self.input, dt);
+		self.input.end_frame();
+		let world = &self.game.world;
+
+		if self.debug.bounds {
+			for (entity, collider) in world.query::<Collider>() {
+				if let Some(transform) = world.get::<Transform>(entity) {
+					self.debug_lines.bounds(&collider.bounds, transform.matrix(), [1.0, 1.0, 0.0]);
+				}
+			}
+			for (entity, light) in world.query::<Light>() {
+				if let Some(transform) = world.get::<Transform>(entity) {
+					let center = cgmath::EuclideanSpace::from_vec(transform.position);
+					self.debug_lines.sphere(center, light.radius, light.color);
+				}
+			}
+			if let Some(level) = &self.level {
+				for field in level.fields() {
+					self.debug_lines.bounds(field, cgmath::SquareMatrix::identity(), [0.0, 1.0, 1.0]);
+				}
+			}
+		}
+		if let Some(level) = self.level.as_ref().filter(|_| self.debug.planes) {
+			for plane in level.planes() {
+				self.debug_lines.plane(plane.center, plane.normal, plane.size, [1.0, 0.0, 1.0]);
+			}
+		}
+		self.debug_lines.flush(&self.device, &self.queue);
+
+		// without a camera the last frame's view stays
+		let Some(camera) = camera(&self.game) else {
+			return;
+		};
+		let eye = camera.eye;
+
+    	self.camera_uniform.update_view_proj(camera);
     	self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+
+		if let Some(sky) = &self.sky {
+			sky.update(&self.queue, camera);
+		}
+
+		self.prepare_instances(eye);
+
+		if let Some(level) = &mut self.level {
+			let world = &self.game.world;
+			let lights = world.query::<Light>()
+				.filter_map(|(entity, light)| Some((world.get::<Transform>(entity)?.position, light)));
+			level.set_lights(&self.queue, lights, eye);
+
+			for event in level.update(&self.queue, dt, eye) {
+				match event {
+					Event::Fired { classname, target: Some(target) } => log::info!("{} fired {}", classname, target),
+					Event::Fired { classname, target: None } => log::info!("{} fired", classname),
+					Event::Message(message) => log::info!("{}", message),
+					Event::LightStyle { style, on } => self.game.switch_lights(style, on),
+				}
+			}
+		}
+	}
+
+	/// Starts playing a level the asset manager finished loading: its
+	/// lights join the game and its sky replaces the clear color, in the
+	/// background and in the scene's lighting.
+	fn set_level(&mut self, loaded: LoadedLevel) {
+		self.game.spawn_lights(&loaded.lights);
+
+		if let Some(cubemap) = &loaded.sky {
+			self.environment = Environment::new(&self.device, &self.queue, cubemap, &mut self.registry);
+			self.sky = Some(Skybox::new(&self.device, &mut self.registry, cubemap, self.sample_count));
+		}
+
+		self.level = Some(loaded.level);
+	}
+
+	/// Switches MSAA, rebuilding the scene's targets and pipelines. Falls
+	/// back to the current count if the driver rejects the new one.
+	fn set_sample_count(&mut self, sample_count: u32) {
+		let previous = self.sample_count;
+
+		self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+		self.create_multisampled(sample_count);
+
+		if let Some(e) = pollster::block_on(self.device.pop_error_scope()) {
+			log::warn!("couldn't switch to {}x MSAA, staying at {}x: {}", sample_count, previous, e);
+			self.create_multisampled(previous);
+		} else {
+			log::info!("using {}x MSAA", sample_count);
+		}
+	}
+
+	fn create_multisampled(&mut self, sample_count: u32) {
+		self.sample_count = sample_count;
+
+		self.pipelines.set_sample_count(sample_count);
+		self.debug_lines.set_sample_count(&self.device, &mut self.registry, sample_count);
+		if let Some(sky) = &mut self.sky {
+			sky.set_sample_count(&self.device, &mut self.registry, sample_count);
+		}
+		if let Some(level) = &mut self.level {
+			level.set_sample_count(&self.device, &mut self.registry, sample_count);
+		}
+
+		self.rebuild_graph();
+	}
+
+	fn rebuild_graph(&mut self) {
+		self.graph = build_graph(&self.post, self.sample_count);
+		self.graph.resize(&self.device, self.config.width, self.config.height);
+	}
+
+	/// Groups the world's renderables into batches by model, uploads their
+	/// transforms and sorts the blended draws back to front from `eye`.
+	fn prepare_instances(&mut self, eye: cgmath::Point3<f32>) {
+		let world = &self.game.world;
+
+		let mut batches: Vec<(Handle<Model>, Vec<Transform>)> = Vec::new();
+		for (entity, renderable) in world.query::<Renderable>() {
+			let Some(transform) = world.get::<Transform>(entity) else {
+				continue;
+			};
+
+			match batches.iter_mut().find(|(model, _)| *model == renderable.model) {
+				Some((_, transforms)) => transforms.push(transform.clone()),
+				None => batches.push((renderable.model.clone(), vec![transform.clone()])),
+			}
+		}
+
+		let models = batches.iter()
+			.map(|(model, transforms)| (scene_model(model, &self.placeholder_model), transforms.as_slice()))
+			.collect::<Vec<_>>();
+		self.blend_order = blend_order(&models, eye);
+
+		let mut instance_data = Vec::new();
+		self.batches = batches.into_iter().map(|(model, transforms)| {
+			let start = instance_data.len() as u32;
+			instance_data.extend(transforms.iter().map(Transform::to_raw));
+			Batch { model, instances: start..instance_data.len() as u32 }
+		}).collect();
+
+		if self.instance_buffer.size() < std::mem::size_of_val(instance_data.as_slice()) as u64 {
+			self.instance_buffer = instance_buffer(&self.device, instance_data.len().next_power_of_two());
+		}
+		self.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+	}
+
+	/// Compiles the pipelines this frame draws with that aren't cached yet.
+	fn prepare_pipelines(&mut self) {
+		let wireframe = self.debug.wireframe && self.pipelines.wireframe;
+
+		for batch in &self.batches {
+			if let Some(model) = scene_model(&batch.model, &self.placeholder_model) {
+				self.pipelines.prepare(&self.device, &mut self.registry, model, wireframe);
+			}
+		}
+	}
+
+	/// Draws the batch's instances of the meshes whose material uses
+	/// `alpha_mode`, or of all meshes in wireframe for `None`.
+	fn draw_batch<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, batch: &'a Batch, alpha_mode: Option<AlphaMode>) {
+		let Some(model) = scene_model(&batch.model, &self.placeholder_model) else {
+			return;
+		};
+
+		for (i, mesh) in model.meshes.iter().enumerate() {
+			let material = &model.materials[mesh.material];
+			if alpha_mode.is_some_and(|mode| mode != material.alpha_mode) {
+				continue;
+			}
+
+			let key = match alpha_mode {
+				Some(_) => PipelineKey::new(material),
+				None => PipelineKey::wireframe(),
+			};
+			let Some(pipeline) = self.pipelines.get(&key) else {
+				continue;
+			};
+
+			render_pass.set_pipeline(pipeline);
+			render_pass.set_bind_group(2, &self.debug.bind_group, &[self.debug.mesh_offset(i)]);
+			render_pass.draw_mesh_instanced(mesh, material, batch.instances.clone(), &self.camera_bind_group);
+		}
+	}
+
+	/// Draws the blended meshes one instance at a time in `blend_order`.
+	fn draw_blended<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+		for &(batch, i, instance) in &self.blend_order {
+			let batch = &self.batches[batch];
+			let Some(model) = scene_model(&batch.model, &self.placeholder_model) else {
+				continue;
+			};
+
+			let instance = batch.instances.start + instance;
+			let mesh = &model.meshes[i];
+			let material = &model.materials[mesh.material];
+			let Some(pipeline) = self.pipelines.get(&PipelineKey::new(material)) else {
+				continue;
+			};
+
+			render_pass.set_pipeline(pipeline);
+			render_pass.set_bind_group(2, &self.debug.bind_group, &[self.debug.mesh_offset(i)]);
+			render_pass.draw_mesh_instanced(mesh, material, instance..instance + 1, &self.camera_bind_group);
+		}
+	}
+
+	/// How far along the queued asset loads are, from 0 to 1.
+	pub fn loading_progress(&self) -> f32 {
+		self.assets.progress()
+	}
+
+	fn export_trace(&self) {
+		let path = std::path::Path::new("trace.json");
+
+		match self.profiler.export_trace(path) {
+			Ok(()) => log::info!("wrote {}", path.display()),
+			Err(e) => log::error!("couldn't write {}: {}", path.display(), e),
+		}
+	}
+
+	fn draw_ui(&mut self, ctx: &egui::Context) {
+		let planes = camera(&self.game).map(|camera| (camera.znear, camera.zfar));
+		let mut debug_changed = false;
+		let mut grid_changed = false;
+		let mut export = false;
+		let mut sample_count = None;
+		let fxaa = self.post.settings.fxaa;
+
+		egui::Window::new("debug").show(ctx, |ui| {
+			self.frame_stats.ui(ui);
+
+			ui.collapsing("camera", |ui| {
+				let world = &mut self.game.world;
+				let camera = world.query_mut::<Camera>().next().map(|(entity, camera)| {
+					camera_ui(ui, camera);
+					entity
+				});
+
+				if let Some(script) = camera.and_then(|camera| world.get_mut::<Box<dyn Script>>(camera)) {
+					script.ui(ui);
+				}
+			});
+
+			ui.collapsing("assets", |ui| {
+				assets_ui(ui, &self.assets);
+			});
+
+			ui.collapsing("instances", |ui| {
+				grid_changed = instances_ui(ui, &mut self.game.grid);
+			});
+
+			ui.collapsing("renderer", |ui| {
+				debug_changed = renderer_ui(ui, &mut self.debug, self.pipelines.wireframe);
+				sample_count = msaa_ui(ui, self.sample_count, &self.sample_counts);
+			});
+
+			ui.collapsing("post", |ui| {
+				post_ui(ui, &mut self.post.settings);
+			});
+
+			ui.collapsing("profiler", |ui| {
+				export = profiler_ui(ui, &self.profiler);
+			});
+		});
+
+		if export {
+			self.export_trace();
+		}
+
+		if let Some(sample_count) = sample_count {
+			self.set_sample_count(sample_count);
+		}
+
+		if fxaa != self.post.settings.fxaa {
+			self.rebuild_graph();
+		}
+
+		if let Some(camera) = camera(&self.game) {
+			if debug_changed || planes != Some((camera.znear, camera.zfar)) {
+				self.debug.write(&self.queue, camera.znear, camera.zfar);
+			}
+		}
+
+		// drawn from next frame, when the renderer picks the new entities up
+		if grid_changed {
+			self.game.spawn_grid();
+		}
 	}
 
-	pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+	/// Records the draws of one pass of the graph.
+	fn record<'a>(&'a self, pass: Pass, render_pass: &mut wgpu::RenderPass<'a>) {
+		match pass {
+			Pass::Scene => {
+				// first, as the bind groups below are the models'
+				if let Some(level) = &self.level {
+					level.draw(render_pass, &self.camera_bind_group);
+				}
+
+				render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+				render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+				render_pass.set_bind_group(3, &self.environment.bind_group, &[]);
+
+				render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+
+				for batch in &self.batches {
+					self.draw_batch(render_pass, batch, Some(AlphaMode::Opaque));
+					self.draw_batch(render_pass, batch, Some(AlphaMode::Mask));
+
+					if self.debug.wireframe {
+						self.draw_batch(render_pass, batch, None);
+					}
+				}
+
+				// after the opaque models so the depth test skips covered
+				// pixels, before the blended ones so they show it through
+				if let Some(sky) = &self.sky {
+					sky.draw(render_pass);
+				}
+
+				self.draw_blended(render_pass);
+
+				self.debug_lines.draw(render_pass, &self.camera_bind_group);
+			}
+
+			Pass::Post(pass) => self.post.record(pass, render_pass),
+
+			Pass::Ui => self.overlay.paint(render_pass),
+		}
+	}
+
+	pub fn render(&mut self, window: &Window) -> Result<(), wgpu::SurfaceError> {
 		let output = self.surface.get_current_texture()?;
 
 		let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
@@ -360,47 +703,272 @@ impl  State {
 			label: Some("Render Encoder"),
 		});
 
+		let loading = !self.assets.is_idle();
+		let show_ui = self.overlay.visible || loading;
 
-		let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-			label: Some("Render Pass"),
-			color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-				view: &view,
-				resolve_target: None,
-				ops: wgpu::Operations {
-					load: wgpu::LoadOp::Clear(wgpu::Color {
-						r: 0.1,
-						g: 0.2,
-						b: 0.3,
-						a: 1.0,
-					}),
-					store: true,
-				},
-			})],
-		    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-		        view: &self.depth_texture.view,
-		        depth_ops: Some(wgpu::Operations {
-		            load: wgpu::LoadOp::Clear(1.0),
-		            store: true,
-		        }),
-		        stencil_ops: None,
-		    }),
-		});
+		if show_ui {
+			let input = self.overlay.begin(window);
+			let ctx = self.overlay.context.clone();
+			let ui_output = ctx.run(input, |ctx| {
+				if loading {
+					loading_ui(ctx, &self.assets);
+				}
 
-		render_pass.set_pipeline(&self.render_pipeline);
+				if self.overlay.visible {
+					self.draw_ui(ctx);
+				}
+			});
 
-		render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-		render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
-			
-		render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-		
-		render_pass.draw_model_instanced(&self.obj_model, 0..self.instances.len() as u32, &self.camera_bind_group);
+			self.overlay.prepare(window, ui_output, &self.device, &self.queue, &mut encoder);
+		}
 
-		drop(render_pass);
+		// after the overlay, which can change what's drawn
+		self.prepare_pipelines();
+		self.post.prepare(&self.device, &self.queue, &self.graph, self.debug.mode != DebugMode::Shaded);
+		self.profiler.begin_frame();
 
+		for pass in self.graph.passes() {
+			let enabled = match pass.key {
+				Pass::Post(post_pass) => self.post.enabled(post_pass),
+				Pass::Ui => show_ui,
+				Pass::Scene => true,
+			};
+
+			if !enabled {
+				continue;
+			}
+
+			self.profiler.begin(&mut encoder, pass.name);
+			{
+				let mut render_pass = self.graph.begin_pass(pass, &mut encoder, &view);
+				self.record(pass.key, &mut render_pass);
+			}
+			self.profiler.end(&mut encoder);
+		}
+
+		self.profiler.resolve(&mut encoder);
 		self.queue.submit(std::iter::once(encoder.finish()));
+		self.profiler.end_frame(&self.device);
 		output.present();
 
 		Ok(())
 
 	}
-}
\ No newline at end of file
+}
+
+/// Back to front order of the blended meshes' instances across the
+/// batches, by the distance from `eye` to each mesh's center. Batches
+/// whose model is still loading are skipped.
+fn blend_order(batches: &[(Option<&Model>, &[Transform])], eye: cgmath::Point3<f32>) -> Vec<(usize, usize, u32)> {
+	use cgmath::{MetricSpace, Transform};
+
+	let mut draws = Vec::new();
+
+	for (batch, &(model, transforms)) in batches.iter().enumerate() {
+		let Some(model) = model else {
+			continue;
+		};
+
+		for (i, mesh) in model.meshes.iter().enumerate() {
+			if model.materials[mesh.material].alpha_mode != AlphaMode::Blend {
+				continue;
+			}
+
+			let center = mesh.bounds.center();
+			for (j, transform) in transforms.iter().enumerate() {
+				let distance = transform.matrix().transform_point(center).distance2(eye);
+				draws.push((distance, batch, i, j as u32));
+			}
+		}
+	}
+
+	draws.sort_by(|a, b| b.0.total_cmp(&a.0));
+	draws.into_iter().map(|(_, batch, mesh, instance)| (batch, mesh, instance)).collect()
+}
+
+/// Room for `count` instances.
+fn instance_buffer(device: &wgpu::Device, count: usize) -> wgpu::Buffer {
+	device.create_buffer(&wgpu::BufferDescriptor {
+		label: Some("Instance Buffer"),
+		size: (count * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+		usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+		mapped_at_creation: false,
+	})
+}
+
+/// The camera the scene is seen through, the first one in the world.
+fn camera(game: &Game) -> Option<&Camera> {
+	game.world.query::<Camera>().map(|(_, camera)| camera).next()
+}
+
+/// The frame: the scene into the HDR target, resolved first when
+/// multisampled, then post processing onto the surface and the overlay on
+/// top.
+fn build_graph(post: &PostProcess, sample_count: u32) -> RenderGraph<Pass> {
+	let clear = Some(CLEAR_COLOR);
+	let mut graph = RenderGraph::new();
+
+	graph.add_texture("depth", TextureDesc {
+		sample_count,
+		..TextureDesc::new(Texture::DEPTH_FORMAT)
+	});
+
+	let scene = PassDesc::new(Pass::Scene, "scene").depth("depth", Some(1.0));
+
+	if sample_count > 1 {
+		graph.add_texture("hdr_msaa", TextureDesc {
+			sample_count,
+			..TextureDesc::new(HDR_FORMAT)
+		});
+		graph.add_pass(scene.color_resolved("hdr_msaa", HDR, clear));
+	} else {
+		graph.add_pass(scene.color(HDR, clear));
+	}
+
+	post.add_to_graph(&mut graph, SURFACE, Pass::Post);
+	graph.add_pass(PassDesc::new(Pass::Ui, "ui").color(SURFACE, None));
+	graph.compile();
+
+	graph
+}
+
+/// The model drawn for `model`, `placeholder` once it failed to load.
+fn scene_model<'a>(model: &'a Handle<Model>, placeholder: &'a Model) -> Option<&'a Model> {
+	match model.state() {
+		LoadState::Ready(model) => Some(model),
+		LoadState::Failed(_) => Some(placeholder),
+		LoadState::Loading => None,
+	}
+}
+
+/// A model pipeline: the material's shader permutation and the fixed
+/// function state that goes with it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PipelineKey {
+	defines: Defines,
+	alpha_mode: AlphaMode,
+	wireframe: bool,
+}
+
+impl PipelineKey {
+	fn new(material: &Material) -> Self {
+		Self {
+			defines: material.defines(),
+			alpha_mode: material.alpha_mode,
+			wireframe: false,
+		}
+	}
+
+	/// The wireframe ignores materials, so every mesh shares one.
+	fn wireframe() -> Self {
+		Self {
+			defines: Defines::new(),
+			alpha_mode: AlphaMode::Opaque,
+			wireframe: true,
+		}
+	}
+}
+
+/// The model pipelines by permutation, each fetched from the registry the
+/// first time a material needs it.
+struct ModelPipelines {
+	pipelines: AHashMap<PipelineKey, Rc<wgpu::RenderPipeline>>,
+	sample_count: u32,
+	/// Whether the adapter can draw lines, which the wireframe needs.
+	wireframe: bool,
+}
+
+impl ModelPipelines {
+	fn new(device: &wgpu::Device, sample_count: u32) -> Self {
+		Self {
+			pipelines: AHashMap::new(),
+			sample_count,
+			wireframe: device.features().contains(wgpu::Features::POLYGON_MODE_LINE),
+		}
+	}
+
+	/// Forgets the pipelines, they're fetched again for the new count.
+	fn set_sample_count(&mut self, sample_count: u32) {
+		self.sample_count = sample_count;
+		self.pipelines.clear();
+	}
+
+	/// Fetches the pipelines `model` is drawn with. Recording only borrows
+	/// them, so this runs before.
+	fn prepare(&mut self, device: &wgpu::Device, registry: &mut Registry, model: &Model, wireframe: bool) {
+		let keys = model.materials.iter()
+			.map(PipelineKey::new)
+			.chain(wireframe.then(PipelineKey::wireframe));
+
+		for key in keys {
+			if self.pipelines.contains_key(&key) {
+				continue;
+			}
+
+			let desc = model_pipeline_desc(HDR_FORMAT, self.sample_count, &key);
+			self.pipelines.insert(key, registry.render_pipeline(device, &desc));
+		}
+	}
+
+	fn get(&self, key: &PipelineKey) -> Option<&wgpu::RenderPipeline> {
+		self.pipelines.get(key).map(Rc::as_ref)
+	}
+}
+
+fn model_pipeline_desc(format: wgpu::TextureFormat, sample_count: u32, key: &PipelineKey) -> RenderPipelineDesc {
+	// the wireframe sits on top of the filled surfaces it outlines
+	let overlay = key.wireframe;
+	let blended = key.alpha_mode == AlphaMode::Blend;
+
+	let (label, fs_entry_point, polygon_mode) = match (overlay, key.alpha_mode) {
+		(true, _) => ("Wireframe Pipeline", "fs_wireframe", wgpu::PolygonMode::Line),
+		(false, AlphaMode::Opaque) => ("Render Pipeline", "fs_main", wgpu::PolygonMode::Fill),
+		(false, AlphaMode::Mask) => ("Masked Pipeline", "fs_main", wgpu::PolygonMode::Fill),
+		(false, AlphaMode::Blend) => ("Blended Pipeline", "fs_main", wgpu::PolygonMode::Fill),
+	};
+
+	RenderPipelineDesc {
+	    label,
+	    shader: "shader.wgsl",
+	    defines: key.defines.clone(),
+	    bind_group_layouts: vec![registry::MATERIAL, registry::CAMERA, debug::LAYOUT, ibl::LAYOUT],
+	    vertex_entry: "vs_main",
+	    vertex_buffers: vec![ModelVertex::desc(), InstanceRaw::desc()],
+	    fragment_entry: fs_entry_point,
+	    targets: vec![Some(wgpu::ColorTargetState {
+	        format,
+	        blend: Some(if blended { wgpu::BlendState::ALPHA_BLENDING } else { wgpu::BlendState::REPLACE }),
+	        write_mask: wgpu::ColorWrites::ALL,
+	    })],
+	    primitive: wgpu::PrimitiveState {
+	        topology: wgpu::PrimitiveTopology::TriangleList,
+	        strip_index_format: None,
+	        front_face: wgpu::FrontFace::Ccw,
+	        cull_mode: Some(wgpu::Face::Back),
+	        // Setting this to anything other than Fill requires Features::POLYGON_MODE_LINE
+	        polygon_mode,
+	        // Requires Features::DEPTH_CLIP_CONTROL
+	        unclipped_depth: false,
+	        // Requires Features::CONSERVATIVE_RASTERIZATION
+	        conservative: false,
+	    },
+	    depth_stencil: Some(wgpu::DepthStencilState {
+	        format: Texture::DEPTH_FORMAT,
+	        // blended surfaces are sorted instead, and mustn't hide what's
+	        // behind them from later ones
+	        depth_write_enabled: !overlay && !blended,
+	        depth_compare: if overlay { wgpu::CompareFunction::LessEqual } else { wgpu::CompareFunction::Less },
+	        stencil: wgpu::StencilState::default(),
+	        bias: if overlay {
+	        	wgpu::DepthBiasState { constant: -2, slope_scale: -1.0, clamp: 0.0 }
+	        } else {
+	        	wgpu::DepthBiasState::default()
+	        },
+	    }),
+	    multisample: wgpu::MultisampleState {
+	        count: sample_count,
+	        mask: !0,
+	        alpha_to_coverage_enabled: false,
+	    },
+	}
+}
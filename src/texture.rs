@@ -1,22 +1,16 @@
 use image::GenericImageView;
-use anyhow::*;
-
-#[repr(C)]
-#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-pub enum CompareFunction {
-    Undefined = 0,
-    Never = 1,
-    Less = 2,
-    Equal = 3,
-    LessEqual = 4,
-    Greater = 5,
-    NotEqual = 6,
-    GreaterEqual = 7,
-    Always = 8,
-}
+use wgpu::util::DeviceExt;
+
+use crate::error::AssetError;
+use crate::registry::{RenderPipelineDesc, Registry};
+use crate::shaders;
+
+/// The name the equirect to cubemap pass registers its bind group layout
+/// under.
+const EQUIRECT_LAYOUT: &str = "equirect";
 
 pub struct Texture {
+    #[allow(dead_code)]
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
@@ -30,19 +24,88 @@ impl Texture {
         queue: &wgpu::Queue,
         bytes: &[u8], 
         label: &str
-    ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
+    ) -> Result<Self, AssetError> {
+        let img = image::load_from_memory(bytes).map_err(|e| AssetError::image(label, e))?;
         Self::from_image(device, queue, &img, Some(label))
     }
 
+    /// The texture is sampled clamped to its edges, materials that need
+    /// another addressing bind their own [`Texture::image_sampler`].
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>
-    ) -> Result<Self> {
+    ) -> Result<Self, AssetError> {
         let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        let texture = Self::create_2d(device, queue, &rgba, wgpu::TextureFormat::Rgba8UnormSrgb, label)?;
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = Self::image_sampler(device, wgpu::AddressMode::ClampToEdge);
+        
+        Ok(Self { texture, view, sampler })
+    }
+
+    /// The sampler of [`Texture::from_image`], with `address_mode` for
+    /// coordinates outside 0 to 1.
+    pub fn image_sampler(device: &wgpu::Device, address_mode: wgpu::AddressMode) -> wgpu::Sampler {
+        device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: address_mode,
+                address_mode_v: address_mode,
+                address_mode_w: address_mode,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        )
+    }
+    
+    /// A baked lightmap atlas. Its texels are light levels rather than
+    /// colors, so it's linear, and filtered both ways for smooth shadows.
+    pub fn lightmap(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: &image::RgbaImage,
+    ) -> Result<Self, AssetError> {
+        let texture = Self::create_2d(device, queue, rgba, wgpu::TextureFormat::Rgba8Unorm, Some("lightmap"))?;
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Ok(Self { texture, view, sampler })
+    }
+
+    /// Uploads `rgba` into a new single mip texture, checking it fits the
+    /// device.
+    fn create_2d(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: &image::RgbaImage,
+        format: wgpu::TextureFormat,
+        label: Option<&str>,
+    ) -> Result<wgpu::Texture, AssetError> {
+        let dimensions = rgba.dimensions();
+
+        let max = device.limits().max_texture_dimension_2d;
+        let requested = dimensions.0.max(dimensions.1);
+        if requested > max {
+            return Err(AssetError::GpuLimit {
+                path: label.unwrap_or_default().to_string(),
+                limit: "texture size",
+                requested,
+                max,
+            });
+        }
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -56,7 +119,7 @@ impl Texture {
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                format,
                 usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             }
         );
@@ -68,7 +131,7 @@ impl Texture {
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            rgba,
             wgpu::ImageDataLayout {
                 offset: 0,
                 bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
@@ -77,56 +140,274 @@ impl Texture {
             size,
         );
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            }
-        );
-        
-        Ok(Self { texture, view, sampler })
+        Ok(texture)
     }
-    
-    pub fn create_depth_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, label: &str) -> Self {
+
+    /// Cubemap from six square faces of the same size, in the +X, -X, +Y,
+    /// -Y, +Z, -Z order of the cube's layers.
+    pub fn cubemap_from_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage; 6],
+        label: &str,
+    ) -> Result<Self, AssetError> {
+        let (width, height) = faces[0].dimensions();
+
+        if width != height || faces.iter().any(|face| face.dimensions() != (width, height)) {
+            return Err(AssetError::parse(label, "cubemap faces have to be square and all the same size"));
+        }
+
+        Self::check_cube_size(device, width, label)?;
+
         let size = wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
-            depth_or_array_layers: 1,
+            width,
+            height,
+            depth_or_array_layers: 6,
         };
-        let desc = wgpu::TextureDescriptor {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
-                | wgpu::TextureUsages::TEXTURE_BINDING,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+
+        for (layer, face) in faces.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                },
+                &face.to_rgba8(),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * width),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+                wgpu::Extent3d { depth_or_array_layers: 1, ..size },
+            );
+        }
+
+        Ok(Self::from_cube(device, texture))
+    }
+
+    /// Cubemap from an equirectangular panorama, usually an HDR image,
+    /// reprojected onto the faces on the GPU.
+    pub fn cubemap_from_equirect(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        registry: &mut Registry,
+        img: &image::DynamicImage,
+        label: &str,
+    ) -> Result<Self, AssetError> {
+        let (width, height) = img.dimensions();
+
+        let max = device.limits().max_texture_dimension_2d;
+        if width.max(height) > max {
+            return Err(AssetError::GpuLimit {
+                path: label.to_string(),
+                limit: "texture size",
+                requested: width.max(height),
+                max,
+            });
+        }
+
+        // a quarter of the panorama's width covers the same angle per texel
+        let face_size = (width / 4).max(1);
+        Self::check_cube_size(device, face_size, label)?;
+
+        let source_size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
         };
-        let texture = device.create_texture(&desc);
+        let source = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: source_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                compare: Some(wgpu::CompareFunction::LessEqual),
-                lod_min_clamp: -100.0,
-                lod_max_clamp: 100.0,
-                ..Default::default()
-            }
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &source,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&img.to_rgba32f()),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(16 * width),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+            source_size,
         );
 
+        let format = wgpu::TextureFormat::Rgba16Float;
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: face_size,
+                height: face_size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+        });
+
+        let bind_group_layout = registry.bind_group_layout(device, EQUIRECT_LAYOUT, &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]);
+
+        let pipeline = registry.render_pipeline(device, &RenderPipelineDesc {
+            label: "Equirect Pipeline",
+            shader: "equirect.wgsl",
+            defines: shaders::Defines::new(),
+            bind_group_layouts: vec![EQUIRECT_LAYOUT],
+            vertex_entry: "vs_fullscreen",
+            vertex_buffers: Vec::new(),
+            fragment_entry: "fs_main",
+            targets: vec![Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        let source_view = source.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Equirect Encoder"),
+        });
+
+        for face in 0..6u32 {
+            let face_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Equirect Face Buffer"),
+                contents: bytemuck::cast_slice(&[face, 0, 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: face_buffer.as_entire_binding(),
+                    },
+                ],
+                label: Some("equirect_bind_group"),
+            });
+
+            let face_view = texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: face,
+                array_layer_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            });
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Equirect Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &face_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(&pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(Self::from_cube(device, texture))
+    }
+
+    fn check_cube_size(device: &wgpu::Device, size: u32, label: &str) -> Result<(), AssetError> {
+        let max = device.limits().max_texture_dimension_2d;
+
+        if size > max {
+            return Err(AssetError::GpuLimit {
+                path: label.to_string(),
+                limit: "cubemap face size",
+                requested: size,
+                max,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn from_cube(device: &wgpu::Device, texture: wgpu::Texture) -> Self {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
         Self { texture, view, sampler }
     }
-}
\ No newline at end of file
+
+    /// Magenta and black checkerboard that stands in for missing images.
+    pub fn placeholder_image() -> image::DynamicImage {
+        let img = image::RgbaImage::from_fn(64, 64, |x, y| {
+            if (x / 8 + y / 8) % 2 == 0 {
+                image::Rgba([255, 0, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 255])
+            }
+        });
+
+        image::DynamicImage::ImageRgba8(img)
+    }
+}
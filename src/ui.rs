@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+
+use winit::event::WindowEvent;
+use winit::event_loop::EventLoopWindowTarget;
+use winit::window::Window;
+
+use crate::{
+    assets::{AssetManager, Handle, LoadState},
+    camera::Camera,
+    debug::{DebugMode, DebugShading},
+    instance::InstanceGrid,
+    post::{PostSettings, Tonemapper},
+    profiler::Profiler,
+};
+
+/// egui drawn on top of the finished frame, for poking at the scene
+/// without recompiling. `prepare` runs before the frame's passes are
+/// recorded and `paint` inside the UI pass.
+pub struct Overlay {
+    pub context: egui::Context,
+    state: egui_winit::State,
+    renderer: egui_wgpu::Renderer,
+    paint_jobs: Vec<egui::ClippedPrimitive>,
+    screen_descriptor: egui_wgpu::renderer::ScreenDescriptor,
+    free: Vec<egui::TextureId>,
+    pub visible: bool,
+}
+
+impl Overlay {
+    pub fn new<T>(
+        event_loop: &EventLoopWindowTarget<T>,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let mut state = egui_winit::State::new(event_loop);
+        state.set_max_texture_side(device.limits().max_texture_dimension_2d as usize);
+
+        Self {
+            context: egui::Context::default(),
+            state,
+            renderer: egui_wgpu::Renderer::new(device, format, None, 1),
+            paint_jobs: Vec::new(),
+            screen_descriptor: egui_wgpu::renderer::ScreenDescriptor {
+                size_in_pixels: [0, 0],
+                pixels_per_point: 1.0,
+            },
+            free: Vec::new(),
+            visible: true,
+        }
+    }
+
+    /// Returns true if egui wants the event to itself, e.g. a click on a
+    /// panel or typing into a text field.
+    pub fn on_event(&mut self, event: &WindowEvent) -> bool {
+        if !self.visible {
+            return false;
+        }
+
+        self.state.on_event(&self.context, event).consumed
+    }
+
+    pub fn begin(&mut self, window: &Window) -> egui::RawInput {
+        self.state.take_egui_input(window)
+    }
+
+    /// Uploads what egui needs to draw `output`, ahead of `paint`.
+    pub fn prepare(
+        &mut self,
+        window: &Window,
+        output: egui::FullOutput,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        // freed a frame late, once the pass that drew with them is done
+        for id in self.free.drain(..) {
+            self.renderer.free_texture(&id);
+        }
+
+        self.state.handle_platform_output(window, &self.context, output.platform_output);
+
+        self.paint_jobs = self.context.tessellate(output.shapes);
+        let size = window.inner_size();
+        self.screen_descriptor = egui_wgpu::renderer::ScreenDescriptor {
+            size_in_pixels: [size.width, size.height],
+            pixels_per_point: window.scale_factor() as f32,
+        };
+
+        for (id, delta) in &output.textures_delta.set {
+            self.renderer.update_texture(device, queue, *id, delta);
+        }
+
+        self.renderer.update_buffers(device, queue, encoder, &self.paint_jobs, &self.screen_descriptor);
+        self.free = output.textures_delta.free;
+    }
+
+    /// Draws what the last `prepare` got.
+    pub fn paint<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        self.renderer.render(render_pass, &self.paint_jobs, &self.screen_descriptor);
+    }
+}
+
+/// Rolling window of recent frame times.
+pub struct FrameStats {
+    times: VecDeque<f32>,
+}
+
+impl FrameStats {
+    const FRAMES: usize = 120;
+
+    pub fn new() -> Self {
+        Self {
+            times: VecDeque::with_capacity(Self::FRAMES),
+        }
+    }
+
+    pub fn push(&mut self, seconds: f32) {
+        if self.times.len() == Self::FRAMES {
+            self.times.pop_front();
+        }
+        self.times.push_back(seconds);
+    }
+
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        if self.times.is_empty() {
+            return;
+        }
+
+        let average = self.times.iter().sum::<f32>() / self.times.len() as f32;
+        let worst = self.times.iter().cloned().fold(0.0, f32::max);
+
+        ui.label(format!("{:.2} ms ({:.0} fps)", average * 1000.0, 1.0 / average));
+        ui.label(format!("worst {:.2} ms over {} frames", worst * 1000.0, self.times.len()));
+    }
+}
+
+pub fn camera_ui(ui: &mut egui::Ui, camera: &mut Camera) {
+    egui::Grid::new("camera").num_columns(2).show(ui, |ui| {
+        ui.label("eye");
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut camera.eye.x).speed(0.1));
+            ui.add(egui::DragValue::new(&mut camera.eye.y).speed(0.1));
+            ui.add(egui::DragValue::new(&mut camera.eye.z).speed(0.1));
+        });
+        ui.end_row();
+
+        ui.label("target");
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut camera.target.x).speed(0.1));
+            ui.add(egui::DragValue::new(&mut camera.target.y).speed(0.1));
+            ui.add(egui::DragValue::new(&mut camera.target.z).speed(0.1));
+        });
+        ui.end_row();
+
+        ui.label("fov");
+        ui.add(egui::Slider::new(&mut camera.fovy, 10.0..=120.0).suffix("°"));
+        ui.end_row();
+
+        ui.label("near");
+        ui.add(egui::Slider::new(&mut camera.znear, 0.01..=10.0).logarithmic(true));
+        ui.end_row();
+
+        ui.label("far");
+        ui.add(egui::Slider::new(&mut camera.zfar, 10.0..=10000.0).logarithmic(true));
+        ui.end_row();
+    });
+}
+
+fn state_label<T>(handle: &Handle<T>) -> String {
+    match handle.state() {
+        LoadState::Loading => "loading".to_string(),
+        LoadState::Ready(_) => format!("ready, {} refs", handle.ref_count()),
+        LoadState::Failed(e) => format!("failed: {}", e),
+    }
+}
+
+/// A progress bar in the middle of the screen and the models and level
+/// still on their way, shown until the asset manager is idle whether or
+/// not the overlay is.
+pub fn loading_ui(ctx: &egui::Context, assets: &AssetManager) {
+    egui::Window::new("loading")
+        .title_bar(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.add(egui::ProgressBar::new(assets.progress()).show_percentage());
+
+            let mut loading = assets.loading().collect::<Vec<_>>();
+            loading.sort_unstable();
+            for path in loading {
+                ui.label(path);
+            }
+        });
+}
+
+pub fn assets_ui(ui: &mut egui::Ui, assets: &AssetManager) {
+    ui.label(format!("{:.0}% loaded", assets.progress() * 100.0));
+
+    egui::CollapsingHeader::new("models").default_open(true).show(ui, |ui| {
+        for handle in assets.models.iter() {
+            ui.collapsing(format!("{} ({})", handle.path(), state_label(handle)), |ui| {
+                if let Some(model) = handle.get() {
+                    for mesh in &model.meshes {
+                        ui.label(format!("mesh {}: {} indices", mesh.name, mesh.num_elements));
+                    }
+                    for material in &model.materials {
+                        ui.label(format!("material {}: {}", material.name, material.diffuse_texture.path()));
+                    }
+                }
+            });
+        }
+    });
+
+    egui::CollapsingHeader::new("textures").show(ui, |ui| {
+        for handle in assets.textures.iter() {
+            ui.label(format!("{} ({})", handle.path(), state_label(handle)));
+        }
+    });
+}
+
+/// Returns true when the grid changed and the instances need rebuilding.
+pub fn instances_ui(ui: &mut egui::Ui, grid: &mut InstanceGrid) -> bool {
+    let before = *grid;
+
+    ui.add(egui::Slider::new(&mut grid.per_row, 1..=50).text("per row"));
+    ui.add(egui::Slider::new(&mut grid.spacing, 0.5..=20.0).text("spacing"));
+    ui.add(egui::Slider::new(&mut grid.tilt, 0.0..=180.0).text("tilt").suffix("°"));
+
+    *grid != before
+}
+
+/// Returns true when the debug uniform needs rewriting.
+pub fn renderer_ui(ui: &mut egui::Ui, debug: &mut DebugShading, has_wireframe: bool) -> bool {
+    let before = debug.mode;
+
+    egui::ComboBox::from_label("view")
+        .selected_text(format!("{:?}", debug.mode))
+        .show_ui(ui, |ui| {
+            for mode in [
+                DebugMode::Shaded,
+                DebugMode::Normals,
+                DebugMode::UvChecker,
+                DebugMode::Depth,
+                DebugMode::MeshColors,
+            ] {
+                ui.selectable_value(&mut debug.mode, mode, format!("{:?}", mode));
+            }
+        });
+
+    ui.add_enabled(has_wireframe, egui::Checkbox::new(&mut debug.wireframe, "wireframe"));
+    ui.checkbox(&mut debug.bounds, "bounding boxes");
+    ui.checkbox(&mut debug.planes, "brush planes");
+
+    debug.mode != before
+}
+
+/// Returns the newly picked sample count, if any.
+pub fn msaa_ui(ui: &mut egui::Ui, current: u32, supported: &[u32]) -> Option<u32> {
+    let mut selected = current;
+
+    egui::ComboBox::from_label("msaa")
+        .selected_text(format!("{}x", current))
+        .show_ui(ui, |ui| {
+            for &count in supported {
+                ui.selectable_value(&mut selected, count, format!("{}x", count));
+            }
+        });
+
+    (selected != current).then_some(selected)
+}
+
+pub fn post_ui(ui: &mut egui::Ui, settings: &mut PostSettings) {
+    ui.add(egui::Slider::new(&mut settings.exposure, -8.0..=8.0).text("exposure").suffix(" EV"));
+
+    egui::ComboBox::from_label("tonemapper")
+        .selected_text(format!("{:?}", settings.tonemapper))
+        .show_ui(ui, |ui| {
+            for tonemapper in [Tonemapper::Aces, Tonemapper::Reinhard, Tonemapper::None] {
+                ui.selectable_value(&mut settings.tonemapper, tonemapper, format!("{:?}", tonemapper));
+            }
+        });
+
+    ui.checkbox(&mut settings.fxaa, "fxaa");
+    ui.checkbox(&mut settings.bloom, "bloom");
+    ui.add_enabled_ui(settings.bloom, |ui| {
+        ui.add(egui::Slider::new(&mut settings.bloom_intensity, 0.0..=1.0).text("intensity"));
+        ui.add(egui::Slider::new(&mut settings.threshold, 0.0..=10.0).text("threshold"));
+        ui.add(egui::Slider::new(&mut settings.knee, 0.0..=5.0).text("knee"));
+    });
+}
+
+/// Returns true when the trace export was asked for.
+pub fn profiler_ui(ui: &mut egui::Ui, profiler: &Profiler) -> bool {
+    ui.label(if profiler.is_gpu() {
+        "gpu timestamps"
+    } else {
+        "cpu timings, command recording only"
+    });
+
+    egui::Grid::new("passes").num_columns(4).striped(true).show(ui, |ui| {
+        ui.label("pass");
+        ui.label("last");
+        ui.label("avg");
+        ui.label("worst");
+        ui.end_row();
+
+        for pass in profiler.passes() {
+            ui.label(pass.name);
+            ui.label(format!("{:.3} ms", pass.last()));
+            ui.label(format!("{:.3} ms", pass.average()));
+            ui.label(format!("{:.3} ms", pass.max()));
+            ui.end_row();
+        }
+    });
+
+    ui.button("export trace.json").clicked()
+}